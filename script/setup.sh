@@ -68,10 +68,12 @@ cd ./script/config/
 for d in *; do
   scp -i ~/organ.pem ../../log/$d/bits_64_relay.txt ubuntu@${IPS[0]}:~/organ/log/$d/ &
   scp -i ~/organ.pem ../../log/$d/bits_226_relay.txt ubuntu@${IPS[0]}:~/organ/log/$d/ &
+  scp -i ~/organ.pem ../../log/$d/identity_relay.txt ubuntu@${IPS[0]}:~/organ/log/$d/ &
   for ((i = 1; i <= $d; i++))
   do
     scp -i ~/organ.pem ../../log/$d/bits_64_nid_$(expr $i - 1).txt ubuntu@${IPS[$i]}:~/organ/log/$d/ &
     scp -i ~/organ.pem ../../log/$d/bits_226_nid_$(expr $i - 1).txt ubuntu@${IPS[$i]}:~/organ/log/$d/ &
+    scp -i ~/organ.pem ../../log/$d/identity_nid_$(expr $i - 1).txt ubuntu@${IPS[$i]}:~/organ/log/$d/ &
   done
 done
 cd ../../
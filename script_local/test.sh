@@ -24,12 +24,12 @@ for d in *; do
 	mkdir -p ../../log/local/$c
 	# Launch the server
 	RUST_LOG=INFO ../../target/release/organ server 0 $c ../../log/local/$d/bits_64_relay.txt \
-	../../log/local/$d/bits_226_relay.txt 2> >(tee -a ../../log/local/$c/relay.log >&2) &
+	../../log/local/$d/bits_226_relay.txt ../../log/local/$d/identity_relay.txt 2> >(tee -a ../../log/local/$c/relay.log >&2) &
 	sleep 1
 	# Launch the clients
     for ((i = 0; i < $d; i++)); do
 	  RUST_LOG=INFO ../../target/release/organ client $i $c ../../log/local/$d/bits_64_nid_$i.txt \
-	  ../../log/local/$d/bits_226_nid_$i.txt 2> ../../log/local/$c/client_$i.log &
+	  ../../log/local/$d/bits_226_nid_$i.txt ../../log/local/$d/identity_nid_$i.txt 2> ../../log/local/$c/client_$i.log &
     done
     wait
   done
@@ -0,0 +1,115 @@
+use crate::packed::PackedVec;
+use rayon::prelude::*;
+use rug::Integer;
+
+// Streaming aggregation on the relay. Each slot vector is added into a running
+// sum as it arrives and then dropped, so that a round takes the memory of one
+// vector however many clients take part.
+
+/// Number of contributors per word of the bitmap.
+const WORD_BITS: usize = u64::BITS as usize;
+
+/// Running sum modulo `q` of the slot vectors of one round, together with a
+/// bitmap of the clients that contributed to it.
+#[derive(Clone, Debug)]
+pub struct Aggregate {
+    /// Modulus of the sum.
+    q: Integer,
+    /// Sum of the vectors so far.
+    sum: Vec<Integer>,
+    /// Bit `nid` is set if client `nid` contributed.
+    contributors: Vec<u64>,
+    /// Number of contributors.
+    count: usize,
+}
+
+impl Aggregate {
+    /// Returns an empty sum of `len` elements modulo `q` for `client_size`
+    /// clients.
+    pub fn new(q: &Integer, len: usize, client_size: usize) -> Self {
+        Aggregate {
+            q: q.clone(),
+            sum: vec![Integer::new(); len],
+            contributors: vec![0; client_size.div_ceil(WORD_BITS)],
+            count: 0,
+        }
+    }
+
+    /// Returns whether client `nid` contributed.
+    pub fn contains(&self, nid: usize) -> bool {
+        self.contributors
+            .get(nid / WORD_BITS)
+            .is_some_and(|word| word & (1 << (nid % WORD_BITS)) != 0)
+    }
+
+    /// Adds the first elements of `slots` of client `nid` to the sum. Returns
+    /// `false` and leaves the sum as it is if `nid` already contributed.
+    pub fn add(&mut self, nid: usize, slots: &PackedVec) -> bool {
+        if self.contains(nid) {
+            return false;
+        }
+        if nid / WORD_BITS >= self.contributors.len() {
+            self.contributors.resize(nid / WORD_BITS + 1, 0);
+        }
+        self.contributors[nid / WORD_BITS] |= 1 << (nid % WORD_BITS);
+        self.count += 1;
+        let q = &self.q;
+        self.sum.par_iter_mut().enumerate().for_each(|(i, s)| {
+            *s += slots.get(i);
+            *s %= q;
+        });
+        true
+    }
+
+    /// Returns the number of contributors.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    /// Returns whether no client contributed yet.
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// Returns the contributors in ascending order.
+    pub fn contributors(&self) -> Vec<usize> {
+        (0..self.contributors.len() * WORD_BITS)
+            .filter(|nid| self.contains(*nid))
+            .collect()
+    }
+
+    /// Returns the sum of the vectors so far.
+    pub fn sum(&self) -> &[Integer] {
+        &self.sum
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregate::Aggregate;
+    use crate::packed::{width, PackedVec};
+    use crate::rng::Rng;
+    use rug::Integer;
+
+    #[test]
+    fn aggregate_test() {
+        let q = Integer::from(1009);
+        let mut rng = Rng::from_seed(0, 0);
+        let vectors: Vec<Vec<Integer>> = (0..70)
+            .map(|_| (0..8).map(|_| rng.below(&q)).collect())
+            .collect();
+        let mut aggregate = Aggregate::new(&q, 6, 5);
+        assert!(aggregate.is_empty());
+        for nid in [3, 0, 69] {
+            assert!(aggregate.add(nid, &PackedVec::pack(&vectors[nid], width(&q))));
+        }
+        assert!(!aggregate.add(3, &PackedVec::pack(&vectors[0], width(&q))));
+        assert_eq!(aggregate.len(), 3);
+        assert_eq!(aggregate.contributors(), vec![0, 3, 69]);
+        assert!(aggregate.contains(69) && !aggregate.contains(1) && !aggregate.contains(1000));
+        for (i, s) in aggregate.sum().iter().enumerate() {
+            let expected = Integer::from(&vectors[0][i] + &vectors[3][i]) + &vectors[69][i];
+            assert_eq!(*s, expected % &q);
+        }
+    }
+}
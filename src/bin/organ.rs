@@ -1,50 +1,249 @@
 #[macro_use]
 extern crate log;
 
-use organ::{client, config, guard, server};
-use rug::Integer;
+use organ::{client, config, egress, guard, message, net, params, rng, secure, server, setup};
 use std::env;
 use std::fs;
 
-fn load_prf(input: &[u8]) -> guard::Setup {
-    bincode::deserialize::<guard::Setup>(input).unwrap()
+/// Logs `message` and exits with code 1.
+fn fail(message: String) -> ! {
+    error!("{}", message);
+    std::process::exit(1);
 }
 
-/// Genereate pre-computed PRF based on `params`.
-fn generate_prf(path: &str, client_size: usize, params: &config::ProtocolParams, do_blame: bool) {
-    let shares: Vec<Vec<Integer>> = (0..params.vector_len)
-        .map(|_| guard::generate_sum_shares(client_size, &params.ring_v.order, &Integer::from(1)))
-        .collect();
-    let shares: Vec<Vec<Integer>> = (0..client_size)
-        .map(|i| shares.iter().map(|v| v[i].clone()).collect())
-        .collect();
-    let setup_values: Vec<guard::SetupValues> = (0..client_size)
-        .map(|i| guard::gen_setup_values(params, &shares[i], do_blame))
+/// Returns argument `i`, or prints the usage and exits if it is missing.
+fn arg(args: &[String], i: usize) -> &str {
+    match args.get(i) {
+        Some(arg) => arg,
+        None => {
+            usage();
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Parses argument `i`, or exits if it is missing or does not parse.
+fn parse<T: std::str::FromStr>(args: &[String], i: usize) -> T {
+    let value = arg(args, i);
+    value
+        .parse()
+        .unwrap_or_else(|_| fail(format!("Invalid argument {}.", value)))
+}
+
+/// Reads the file at `path`, or exits if it cannot be read.
+fn read_file(path: &str) -> Vec<u8> {
+    fs::read(path).unwrap_or_else(|e| fail(format!("Cannot read {}: {}.", path, e)))
+}
+
+/// Writes `contents` to the file at `path`, or exits if it cannot be written.
+fn write_file(path: &str, contents: impl AsRef<[u8]>) {
+    fs::write(path, contents).unwrap_or_else(|e| fail(format!("Cannot write {}: {}.", path, e)))
+}
+
+/// Loads the setup file at `path`, or exits if it is not one.
+fn load_prf(path: &str) -> guard::Setup {
+    bincode::deserialize(&read_file(path))
+        .unwrap_or_else(|_| fail(format!("{} is not a setup file.", path)))
+}
+
+/// Loads the identity file at `path`, or exits if it is not one.
+fn load_identity(path: &str) -> secure::Identity {
+    bincode::deserialize(&read_file(path))
+        .unwrap_or_else(|_| fail(format!("{} is not an identity file.", path)))
+}
+
+/// Prints the usage.
+fn usage() {
+    println!(r"Usage:");
+    println!(
+        r"organ client <id> <config_file> <base_prf_file> <bulk_prf_file> <identity_file> [payload_file]"
+    );
+    println!(r"organ server <id> <config_file> <base_prf_file> <bulk_prf_file> <identity_file>");
+    println!(r"organ config <config_file> <output_dir>");
+    println!(r"organ dump <config_file> <output_file>");
+    println!(r"organ echo <address>:<port>");
+    println!(r"organ validate <config_file>");
+    println!(
+        r"organ params <base|bulk> <bits> <client_size> <vector_len> <margin_bits> [error_bound]"
+    );
+    println!(r"organ keygen <relay|id> <output_dir>");
+    println!(r"organ setup client <id> <config_file> <output_dir>");
+    println!(r"organ setup relay <config_file> <output_dir>");
+    println!(r"organ member <add|remove> <id> <config_file> <output_dir> <from_round>");
+    println!(r"organ prifi <client|server> <id> <config_file>");
+}
+
+/// Loads the config from `path`. Logs every problem and exits if it cannot be
+/// read or does not validate.
+fn read_config(path: &str) -> config::Config {
+    match config::load_config(path) {
+        Ok(conf) => conf,
+        Err(config::ConfigError::Invalid(diagnostics)) => {
+            for diagnostic in diagnostics {
+                error!("{}", diagnostic);
+            }
+            error!("Invalid config {}.", path);
+            std::process::exit(1);
+        }
+        Err(e) => {
+            error!("Cannot read config {}: {}.", path, e);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Writes `identity` of `node` to `path`.
+fn write_identity(path: &str, node: &str, identity: &secure::Identity) {
+    info!("Writing identity of {}...", node);
+    write_file(
+        &format!("./{}/identity_{}.txt", path, node),
+        bincode::serialize(identity).unwrap(),
+    );
+}
+
+/// Generate the keys based on `params` for `client_size` clients and the
+/// relay. The masks of each round are derived from the keys when needed.
+fn generate_prf(
+    path: &str,
+    client_size: usize,
+    params: &config::ProtocolParams,
+    do_blame: bool,
+    rng: &mut rng::Rng,
+) {
+    // Derive the keys from pairwise seeds so that the masks of missing clients
+    // can be recovered.
+    let (offsets, seeds) = guard::generate_keys(client_size, params, rng);
+    let clients: Vec<guard::ClientSetup> = (0..client_size)
+        .map(|i| guard::ClientSetup {
+            seeds: seeds[i].clone(),
+            share: guard::derive_key(params, i, &offsets[i], &seeds[i]),
+            blinding: guard::gen_blinding(params, rng),
+        })
         .collect();
-    for (i, value) in setup_values.iter().enumerate() {
-        info!("Generating config for node {}...", i);
-        std::fs::write(
-            format!(
-                "./{}/bits_{}_nid_{}.txt",
-                path,
-                params.bits.to_string(),
-                i.to_string()
-            ),
-            bincode::serialize(&guard::Setup::SetupValues(value.clone())).unwrap(),
+    let commitments = if do_blame {
+        Some(
+            clients
+                .iter()
+                .enumerate()
+                .map(|(i, client)| {
+                    info!("Committing to the key of node {}...", i);
+                    guard::commit(params, &client.share, &client.blinding)
+                })
+                .collect(),
         )
-        .unwrap();
+    } else {
+        None
+    };
+    for (i, client) in clients.into_iter().enumerate() {
+        info!("Generating config for node {}...", i);
+        write_file(
+            &format!("./{}/bits_{}_nid_{}.txt", path, params.bits, i),
+            bincode::serialize(&guard::Setup::SetupValues(client)).unwrap(),
+        );
     }
     info!("Generating config for relay...");
-    std::fs::write(
-        format!("./{}/bits_{}_relay.txt", path, params.bits.to_string()),
-        bincode::serialize(&guard::Setup::SetupRelay(guard::gen_setup_relay(
-            params,
-            &setup_values,
-            do_blame,
-        )))
+    write_file(
+        &format!("./{}/bits_{}_relay.txt", path, params.bits),
+        bincode::serialize(&guard::Setup::SetupRelay(guard::RelaySetup {
+            members: (0..client_size).collect(),
+            offsets,
+            commitments,
+        }))
         .unwrap(),
-    )
-    .unwrap();
+    );
+}
+
+/// Reads the public keys `organ keygen` wrote for the relay and `client_size`
+/// clients from `path`. Returns the key of the relay and of each client.
+fn read_roster(path: &str, client_size: usize) -> std::io::Result<(Vec<u8>, Vec<Vec<u8>>)> {
+    let read = |node: &str| fs::read(format!("./{}/public_{}.txt", path, node));
+    let clients = (0..client_size)
+        .map(|i| read(&format!("nid_{}", i)))
+        .collect::<std::io::Result<_>>()?;
+    Ok((read("relay")?, clients))
+}
+
+/// Applies `change` to the setup based on `params` under `path`, rewriting the
+/// files of the affected nodes. Returns the new setup of the relay, or why the
+/// change does not apply.
+fn update_members(
+    path: &str,
+    client_size: usize,
+    params: &config::ProtocolParams,
+    change: guard::MemberChange,
+    rng: &mut rng::Rng,
+) -> Result<guard::RelaySetup, guard::MemberError> {
+    let relay_file = format!("./{}/bits_{}_relay.txt", path, params.bits);
+    let client_file = |i: usize| format!("./{}/bits_{}_nid_{}.txt", path, params.bits, i);
+    let mut relay = match load_prf(&relay_file) {
+        guard::Setup::SetupRelay(relay) => relay,
+        guard::Setup::SetupValues(_) => fail(format!("{} is not a relay setup.", relay_file)),
+    };
+    let mut clients: Vec<Option<guard::ClientSetup>> = (0..client_size.max(relay.offsets.len()))
+        .map(|i| {
+            if !relay.members.contains(&i) {
+                return None;
+            }
+            match load_prf(&client_file(i)) {
+                guard::Setup::SetupValues(client) => Some(client),
+                guard::Setup::SetupRelay(_) => {
+                    fail(format!("{} is not a client setup.", client_file(i)))
+                }
+            }
+        })
+        .collect();
+    // The parameters only allow for `client_size` members at a time.
+    if matches!(change, guard::MemberChange::Add(_)) && relay.members.len() >= client_size {
+        return Err(guard::MemberError::Full(client_size));
+    }
+    let affected = guard::change_members(params, &mut relay, &mut clients, change, rng)?;
+    for i in affected {
+        info!("Updating config for node {}...", i);
+        write_file(
+            &client_file(i),
+            bincode::serialize(&guard::Setup::SetupValues(clients[i].take().unwrap())).unwrap(),
+        );
+    }
+    if let guard::MemberChange::Remove(i) = change {
+        fs::remove_file(client_file(i)).unwrap_or_default();
+    }
+    info!("Updating config for relay...");
+    write_file(
+        &relay_file,
+        bincode::serialize(&guard::Setup::SetupRelay(relay.clone())).unwrap(),
+    );
+    Ok(relay)
+}
+
+/// Applies `change` to the client keys the relay identity under `path` holds:
+/// issues the identity of an added client and revokes the key of a removed
+/// one. Returns the new client keys.
+fn update_identities(path: &str, change: guard::MemberChange) -> Vec<Vec<u8>> {
+    let relay_file = format!("./{}/identity_relay.txt", path);
+    let mut relay = load_identity(&relay_file);
+    match change {
+        guard::MemberChange::Add(nid) => {
+            let (key, public) = secure::generate_key();
+            if relay.clients.len() <= nid {
+                relay.clients.resize(nid + 1, Vec::new());
+            }
+            relay.clients[nid] = public;
+            let identity = secure::Identity {
+                key,
+                relay: relay.relay.clone(),
+                clients: Vec::new(),
+                guard: None,
+            };
+            write_identity(path, &format!("nid_{}", nid), &identity);
+        }
+        guard::MemberChange::Remove(nid) => {
+            if let Some(key) = relay.clients.get_mut(nid) {
+                key.clear();
+            }
+        }
+    }
+    write_identity(path, "relay", &relay);
+    relay.clients
 }
 
 /// Command line parser and function caller.
@@ -56,51 +255,289 @@ async fn main() {
         .init();
     info!("Starting up...");
     debug!("args: {:?}", args);
-    if args[1] == "config" {
-        info!("Reading from {}...", args[2]);
-        let conf = config::load_config(&args[2]).unwrap();
+    if arg(&args, 1) == "config" {
+        info!("Reading from {}...", arg(&args, 2));
+        let conf = read_config(arg(&args, 2));
+        let mut rng = rng::Rng::from_config(&conf, conf.client_size);
         info!("Generating base round config...");
-        generate_prf(&args[3], conf.client_size, &conf.base_params, conf.do_blame);
+        generate_prf(
+            arg(&args, 3),
+            conf.client_size,
+            &conf.base_params,
+            conf.do_blame,
+            &mut rng,
+        );
         info!("Generating bulk round config...");
-        generate_prf(&args[3], conf.client_size, &conf.bulk_params, conf.do_blame);
-    } else if args[1] == "dump" {
-        info!("Reading from {}...", args[2]);
-        let conf = config::load_config(&args[2]).unwrap();
-        info!("Dumping to {}...", args[3]);
-        config::dump_config(&args[3], &conf).unwrap();
-    } else if args[1] == "prifi" {
-        info!("Reading from {}...", args[4]);
-        let conf = config::load_config(&args[4]).unwrap();
-        if args[2] == "client" {
-            let nid: usize = args[3].parse().unwrap();
-            client::main_prifi(conf, nid);
-        } else if args[2] == "server" {
-            server::main_prifi(conf).await;
+        generate_prf(
+            arg(&args, 3),
+            conf.client_size,
+            &conf.bulk_params,
+            conf.do_blame,
+            &mut rng,
+        );
+        info!("Issuing identities...");
+        let (clients, relay, guard) = secure::issue_identities(conf.client_size);
+        for (i, identity) in clients.iter().enumerate() {
+            write_identity(arg(&args, 3), &format!("nid_{}", i), identity);
         }
-    } else if args[1] == "client" || args[1] == "server" {
-        info!("Reading from {}...", args[3]);
-        let conf = config::load_config(&args[3]).unwrap();
-        info!("Reading from {}...", args[4]);
-        let base_prf = load_prf(&fs::read(&args[4]).unwrap());
-        info!("Reading from {}...", args[5]);
-        let bulk_prf = load_prf(&fs::read(&args[5]).unwrap());
-        if args[1] == "client" {
-            let nid: usize = args[2].parse().unwrap();
-            if let guard::Setup::SetupValues(base) = base_prf {
-                if let guard::Setup::SetupValues(bulk) = bulk_prf {
-                    client::main(conf, nid, base, bulk);
+        write_identity(arg(&args, 3), "relay", &relay);
+        write_identity(arg(&args, 3), "guard", &guard);
+    } else if arg(&args, 1) == "member" {
+        info!("Reading from {}...", arg(&args, 4));
+        let conf = read_config(arg(&args, 4));
+        let nid: usize = parse(&args, 3);
+        let change = match arg(&args, 2) {
+            "add" => guard::MemberChange::Add(nid),
+            "remove" => guard::MemberChange::Remove(nid),
+            _ => fail(format!("Unknown member change {}.", arg(&args, 2))),
+        };
+        let from_round: usize = parse(&args, 6);
+        // Never the test seed: it would hand out the same keys on every change.
+        let mut rng = rng::Rng::new();
+        info!("Updating base round config...");
+        let updated = update_members(
+            arg(&args, 5),
+            conf.client_size,
+            &conf.base_params,
+            change,
+            &mut rng,
+        )
+        .and_then(|base| {
+            info!("Updating bulk round config...");
+            update_members(
+                arg(&args, 5),
+                conf.client_size,
+                &conf.bulk_params,
+                change,
+                &mut rng,
+            )
+            .map(|bulk| (base, bulk))
+        });
+        let (base, bulk) = match updated {
+            Ok(updated) => updated,
+            Err(e) => {
+                error!("Cannot apply {:?}: {}.", change, e);
+                std::process::exit(1);
+            }
+        };
+        info!("Updating identities...");
+        let clients = update_identities(arg(&args, 5), change);
+        // Hand the new setup over to the relay if it is running.
+        let identity = load_identity(&format!("./{}/identity_guard.txt", arg(&args, 5)));
+        match secure::SecureStream::connect(
+            conf.server_addr,
+            secure::Peer::Guard,
+            &identity,
+            net::Limits::from_config(&conf),
+        ) {
+            Ok(mut socket) => {
+                if let Err(e) = client::greet(&conf, &mut socket, None) {
+                    fail(format!("Cannot reconfigure the relay: {}.", e));
+                }
+                info!("Reconfiguring relay at {:?}...", conf.server_addr);
+                let message = bincode::serialize(&message::Message::GuardReconfigureMessage(
+                    message::GuardReconfigureMessage {
+                        from_round,
+                        base,
+                        bulk,
+                        clients,
+                    },
+                ))
+                .unwrap();
+                if let Err(e) = socket.write(&message) {
+                    fail(format!("Cannot reconfigure the relay: {}.", e));
                 }
             }
-        } else if args[1] == "server" {
-            if let guard::Setup::SetupRelay(base) = base_prf {
-                if let guard::Setup::SetupRelay(bulk) = bulk_prf {
-                    server::main(conf, base, bulk).await;
+            Err(_) => warn!("Relay is not running. Start it with the new config."),
+        }
+    } else if arg(&args, 1) == "keygen" {
+        // Identity for the dealerless setup. The public key goes to every
+        // other node out of band.
+        let node = match arg(&args, 2) {
+            "relay" => "relay".to_string(),
+            nid => format!("nid_{}", nid),
+        };
+        let (key, public) = secure::generate_key();
+        let relay = if node == "relay" {
+            public.clone()
+        } else {
+            Vec::new()
+        };
+        let identity = secure::Identity {
+            key,
+            relay,
+            clients: Vec::new(),
+            guard: None,
+        };
+        write_identity(arg(&args, 3), &node, &identity);
+        info!("Writing public key of {}...", node);
+        write_file(&format!("./{}/public_{}.txt", arg(&args, 3), node), public);
+    } else if arg(&args, 1) == "setup" {
+        // Dealerless setup: the clients agree on their keys through the relay.
+        let (conf_file, path, node) = match arg(&args, 2) {
+            "client" => (
+                arg(&args, 4),
+                arg(&args, 5),
+                format!("nid_{}", arg(&args, 3)),
+            ),
+            _ => (arg(&args, 3), arg(&args, 4), "relay".to_string()),
+        };
+        info!("Reading from {}...", conf_file);
+        let conf = read_config(conf_file);
+        let files = [&conf.base_params, &conf.bulk_params]
+            .map(|params| format!("./{}/bits_{}_{}.txt", path, params.bits, node));
+        let identity_file = format!("./{}/identity_{}.txt", path, node);
+        let mut identity = load_identity(&identity_file);
+        let roster = read_roster(path, conf.client_size);
+        let (relay, clients) = match roster {
+            Ok(roster) => roster,
+            Err(e) => {
+                error!("Cannot read the public keys under {}: {}.", path, e);
+                std::process::exit(1);
+            }
+        };
+        identity.relay = relay;
+        identity.clients = clients;
+        let setup = if arg(&args, 2) == "client" {
+            setup::main_client(&conf, parse(&args, 3), &identity)
+                .map(|(base, bulk)| [base, bulk].map(guard::Setup::SetupValues))
+        } else {
+            setup::main_relay(&conf, &identity)
+                .await
+                .map(|(base, bulk)| [base, bulk].map(guard::Setup::SetupRelay))
+        };
+        let setup = match setup {
+            Ok(setup) => setup,
+            Err(e) => {
+                error!("Setup failed: {}.", e);
+                std::process::exit(1);
+            }
+        };
+        for (file, setup) in files.iter().zip(setup) {
+            info!("Writing to {}...", file);
+            write_file(file, bincode::serialize(&setup).unwrap());
+        }
+        // Only the relay authenticates the clients later on.
+        if arg(&args, 2) == "client" {
+            identity.clients = Vec::new();
+        }
+        write_identity(path, &node, &identity);
+    } else if arg(&args, 1) == "dump" {
+        info!("Reading from {}...", arg(&args, 2));
+        let conf = read_config(arg(&args, 2));
+        info!("Dumping to {}...", arg(&args, 3));
+        if let Err(e) = config::dump_config(arg(&args, 3), &conf) {
+            fail(format!("Cannot dump to {}: {}.", arg(&args, 3), e));
+        }
+    } else if arg(&args, 1) == "validate" {
+        match config::load_config(arg(&args, 2)) {
+            Ok(_) => println!("{} is valid.", arg(&args, 2)),
+            Err(config::ConfigError::Invalid(diagnostics)) => {
+                for diagnostic in diagnostics {
+                    println!("{}", diagnostic);
                 }
+                std::process::exit(1);
+            }
+            Err(e) => {
+                println!("Cannot read {}: {}", arg(&args, 2), e);
+                std::process::exit(1);
+            }
+        }
+    } else if arg(&args, 1) == "params" {
+        let phase = match arg(&args, 2) {
+            "base" => message::Phase::Base,
+            "bulk" => message::Phase::Bulk,
+            _ => fail(format!("Unknown round {}.", arg(&args, 2))),
+        };
+        let client_size: usize = parse(&args, 4);
+        let request = params::ParamsRequest {
+            phase,
+            bits: parse(&args, 3),
+            client_size,
+            vector_len: parse(&args, 5),
+            margin_bits: parse(&args, 6),
+            // Each client and the relay add an error of at most one.
+            error_bound: args
+                .get(7)
+                .map_or(client_size as u64 + 1, |_| parse(&args, 7)),
+        };
+        info!("Generating parameters for {:?}...", request);
+        match params::generate(&request) {
+            Ok(params) => println!("{}", serde_json::to_string_pretty(&params).unwrap()),
+            Err(e) => {
+                error!("Cannot generate parameters: {}.", e);
+                std::process::exit(1);
+            }
+        }
+    } else if arg(&args, 1) == "echo" {
+        info!("Echoing on {}...", arg(&args, 2));
+        let listener = async_std::net::TcpListener::bind(arg(&args, 2))
+            .await
+            .unwrap_or_else(|e| fail(format!("Cannot bind {}: {}.", arg(&args, 2), e)));
+        egress::serve_echo(listener).await;
+    } else if arg(&args, 1) == "prifi" {
+        info!("Reading from {}...", arg(&args, 4));
+        let conf = read_config(arg(&args, 4));
+        if arg(&args, 2) == "client" {
+            let nid: usize = parse(&args, 3);
+            if let Err(e) = client::main_prifi(conf, nid) {
+                error!("Client {} failed: {}.", nid, e);
+                std::process::exit(1);
+            }
+        } else if arg(&args, 2) == "server" {
+            if let Err(e) = server::main_prifi(conf).await {
+                error!("Relay failed: {}.", e);
+                std::process::exit(1);
+            }
+        }
+    } else if arg(&args, 1) == "client" || arg(&args, 1) == "server" {
+        info!("Reading from {}...", arg(&args, 3));
+        let conf = read_config(arg(&args, 3));
+        info!("Reading from {}...", arg(&args, 4));
+        let base_prf = load_prf(arg(&args, 4));
+        info!("Reading from {}...", arg(&args, 5));
+        let bulk_prf = load_prf(arg(&args, 5));
+        info!("Reading from {}...", arg(&args, 6));
+        let identity = load_identity(arg(&args, 6));
+        if arg(&args, 1) == "client" {
+            let nid: usize = parse(&args, 2);
+            // Send the optional payload file piece by piece, up to
+            // `max_reservation` pieces per round. Without a file, send one
+            // empty piece per round.
+            let data = args.get(7).map(|path| {
+                info!("Reading payload from {}...", path);
+                read_file(path)
+            });
+            let capacity = client::payload_capacity(&conf);
+            let max_reservation = conf.max_reservation;
+            let payload = |round: usize| match &data {
+                Some(data) => data
+                    .chunks(std::cmp::max(capacity, 1))
+                    .skip((round - 1) * max_reservation)
+                    .take(max_reservation)
+                    .map(<[u8]>::to_vec)
+                    .collect(),
+                None => vec![Vec::new()],
+            };
+            let (base, bulk) = match (base_prf, bulk_prf) {
+                (guard::Setup::SetupValues(base), guard::Setup::SetupValues(bulk)) => (base, bulk),
+                _ => fail("The setup files are not client setups.".to_string()),
+            };
+            if let Err(e) = client::main(conf, nid, base, bulk, identity, payload) {
+                error!("Client {} failed: {}.", nid, e);
+                std::process::exit(1);
+            }
+        } else {
+            let (base, bulk) = match (base_prf, bulk_prf) {
+                (guard::Setup::SetupRelay(base), guard::Setup::SetupRelay(bulk)) => (base, bulk),
+                _ => fail("The setup files are not relay setups.".to_string()),
+            };
+            if let Err(e) = server::main(conf, base, bulk, identity).await {
+                error!("Relay failed: {}.", e);
+                std::process::exit(1);
             }
         }
     } else {
-        println!(r"Usage:");
-        println!(r"organ (client|server) <id> <config_file> <base_prf_file> <bulk_prf_file>");
-        println!(r"organ config <config_file>");
+        usage();
     }
 }
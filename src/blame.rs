@@ -0,0 +1,980 @@
+use crate::client::reservation_capacity;
+use crate::client::{encode_payload, generate_client_base_message, payload_digest};
+use crate::config::{Config, ProtocolParams};
+use crate::ecc::{
+    add, get_g, get_h, get_order, mul, new_big_num_context, to_bytes, try_from_bytes,
+};
+use crate::guard::{compute_hash, ClientSetup, RelaySetup};
+use crate::message::{
+    ClientAccusationMessage, ClientBaseBlameMessage, ClientBaseMessage, ClientBlameMessage,
+    ClientBulkMessage, Message, Opening, ServerBlameRequestMessage, ServerBlameResultMessage,
+};
+use crate::prf::{ntt, scale};
+use crate::server::{decode_payload, insert_once};
+use openssl::ec::EcPoint;
+use rayon::prelude::*;
+use rug::{integer::Order, Complete, Integer};
+use rug_fft::bit_rev_radix_2_intt;
+use sha2::{Digest, Sha512};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::ops::Range;
+use std::time::{Duration, Instant};
+
+/// How many past rounds the relay keeps for accusations.
+pub const BLAME_WINDOW: usize = 2;
+
+/// How long the relay waits for the openings of a blame request without
+/// `round_timeout`.
+pub const BLAME_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Length of the secret behind the ID of a slot group in bytes.
+pub const SECRET_LEN: usize = 32;
+
+/// Domain separation string for the derivation of slot group IDs.
+const SLOT_DOMAIN: &[u8] = b"OrgAn slot group ID";
+
+/// Derives the ID of a slot group in the base round from `secret`. Only the
+/// owner of the slot group knows the secret, so revealing it proves that the
+/// accuser owns the slot group.
+pub fn slot_id(params: &ProtocolParams, secret: &[u8]) -> Integer {
+    let mut hasher = Sha512::new();
+    hasher.update(SLOT_DOMAIN);
+    hasher.update(secret);
+    Integer::from_digits(&hasher.finalize(), Order::Lsf) % &params.p
+}
+
+/// Returns the deadline for the openings of a blame request sent now.
+pub fn blame_deadline(c: &Config) -> Instant {
+    Instant::now() + c.round_timeout.map_or(BLAME_TIMEOUT, Duration::from_millis)
+}
+
+/// Powers of the inverse root of unity of the NTT and of its `vector_len`-th
+/// power, from 0 to `vector_len`.
+struct InversePowers {
+    /// Powers of the inverse root.
+    omega: Vec<Integer>,
+    /// Powers of the `vector_len`-th power of the inverse root.
+    omega_len: Vec<Integer>,
+}
+
+impl InversePowers {
+    /// Computes the powers for `params`.
+    fn new(params: &ProtocolParams) -> InversePowers {
+        let order = &params.ring_v.order;
+        let powers = |base: &Integer| -> Vec<Integer> {
+            std::iter::once(Integer::from(1))
+                .chain((0..params.vector_len).scan(Integer::from(1), |acc, _| {
+                    *acc *= base;
+                    *acc %= order;
+                    Some(acc.clone())
+                }))
+                .collect()
+        };
+        let omega = params
+            .ring_v
+            .root_of_unity(params.vector_len)
+            .invert(order)
+            .unwrap();
+        let omega_len = Integer::from(
+            omega
+                .pow_mod_ref(&Integer::from(params.vector_len), order)
+                .unwrap(),
+        );
+        InversePowers {
+            omega: powers(&omega),
+            omega_len: powers(&omega_len),
+        }
+    }
+
+    /// Returns the coefficient of the element `j` after NTT in the element `k`
+    /// before NTT.
+    fn coefficient(&self, n_inverse: &Integer, j: usize, k: usize) -> Integer {
+        let n = self.omega.len() - 1;
+        (n_inverse * &self.omega_len[j * k / n]).complete() * &self.omega[j * k % n]
+    }
+}
+
+/// Returns the hash for `attempt` of `round` processed by NTT.
+fn hash_ntt(params: &ProtocolParams, round: usize, attempt: usize) -> Vec<Integer> {
+    ntt(
+        params,
+        &compute_hash(round, attempt, params.vector_len, &params.ring_v.order),
+    )
+}
+
+/// Opens the elements in `range` of the PRF of `value`. Returns the
+/// scaled values `z`, the errors `e` with `q w = z order + e` for the product
+/// `w`, and the carries `d`, i.e. how many times the order of the NTT field
+/// the unreduced inverse NTT exceeds `w`, modulo the order of the group.
+fn open_vector(
+    params: &ProtocolParams,
+    powers: &InversePowers,
+    value: &[Integer],
+    hash_ntt: &[Integer],
+    range: Range<usize>,
+) -> (Vec<Integer>, Vec<Integer>, Vec<Integer>) {
+    let order = &params.ring_v.order;
+    let tau = get_order(params);
+    let n_inverse = Integer::from(params.vector_len).invert(order).unwrap();
+    let product_ntt: Vec<Integer> = ntt(params, value)
+        .into_iter()
+        .zip(hash_ntt.iter())
+        .map(|(a, b)| a * b)
+        .collect();
+    let mut product = product_ntt.clone();
+    bit_rev_radix_2_intt(
+        &mut product,
+        order,
+        &params.ring_v.root_of_unity(params.vector_len),
+    );
+    let product = &product[range.clone()];
+    let scaled = scale(params, product);
+    let e = product
+        .iter()
+        .zip(scaled.iter())
+        .map(|(w, z)| Integer::from(w * &params.q) - z * order)
+        .collect();
+    let carries = range
+        .into_par_iter()
+        .zip(product.par_iter())
+        .map(|(k, w)| {
+            let sum = Integer::sum(
+                product_ntt
+                    .iter()
+                    .enumerate()
+                    .map(|(j, x)| x * powers.coefficient(&n_inverse, j, k))
+                    .collect::<Vec<_>>()
+                    .iter(),
+            )
+            .complete();
+            let val = sum - w;
+            assert_eq!(Integer::from(&val % order), Integer::from(0));
+            val * Integer::from(order.invert_ref(&tau).unwrap()) % &tau
+        })
+        .collect();
+    (scaled, e, carries)
+}
+
+/// Returns `q` times the commitments to the products of the elements in
+/// `range`, from the `commitments` to the key and the `carries` of the product
+/// and of the blinding product, see `open_vector`. Returns `None` if the
+/// commitments hold no points of the group.
+fn expected_qw(
+    params: &ProtocolParams,
+    powers: &InversePowers,
+    hash_ntt: &[Integer],
+    commitments: &[Vec<u8>],
+    carry: &[Integer],
+    carry_blinding: &[Integer],
+    range: Range<usize>,
+) -> Option<Vec<EcPoint>> {
+    let order = &params.ring_v.order;
+    let n_inverse = Integer::from(params.vector_len).invert(order).unwrap();
+    let ab: Vec<EcPoint> = commitments
+        .par_iter()
+        .map(|ab| try_from_bytes(params, ab))
+        .collect::<Option<_>>()?;
+    let qw = range
+        .into_par_iter()
+        .zip(carry.par_iter().zip(carry_blinding.par_iter()))
+        .map(|(k, (d, d_blinding))| {
+            let sum = ab
+                .par_iter()
+                .enumerate()
+                .map(|(j, ab)| {
+                    mul(
+                        params,
+                        ab,
+                        &(powers.coefficient(&n_inverse, j, k) * &hash_ntt[j]),
+                    )
+                })
+                .reduce_with(|a, b| add(params, &a, &b))
+                .unwrap();
+            mul(
+                params,
+                &add(
+                    params,
+                    &add(
+                        params,
+                        &mul(params, &get_g(params), &(-Integer::from(order) * d)),
+                        &mul(
+                            params,
+                            &get_h(params),
+                            &(-Integer::from(order) * d_blinding),
+                        ),
+                    ),
+                    &sum,
+                ),
+                &params.q,
+            )
+        })
+        .collect();
+    Some(qw)
+}
+
+/// Verifies the openings of the PRF against the commitments `qw`.
+pub fn verify(
+    params: &ProtocolParams,
+    msg: &[Integer],
+    msg_b: &[Integer],
+    e: &[Vec<u8>],
+    qw: &[EcPoint],
+) -> bool {
+    msg.par_iter()
+        .zip(msg_b.par_iter())
+        .zip(e.par_iter())
+        .zip(qw.par_iter())
+        .all(|(((a, b), c), d)| {
+            let e = match try_from_bytes(params, c) {
+                Some(e) => e,
+                None => return false,
+            };
+            add(
+                params,
+                &add(
+                    params,
+                    &mul(
+                        params,
+                        &get_g(params),
+                        &Integer::from(a * &params.ring_v.order),
+                    ),
+                    &mul(
+                        params,
+                        &get_h(params),
+                        &Integer::from(b * &params.ring_v.order),
+                    ),
+                ),
+                &e,
+            )
+            .eq(
+                params.group.as_ref().unwrap(),
+                d,
+                &mut new_big_num_context(),
+            )
+            .unwrap()
+        })
+}
+
+/// Returns the indices of the slots in the slot group `slot`.
+pub fn slot_range(c: &Config, slot: usize) -> Range<usize> {
+    slot * c.slot_per_round..(slot + 1) * c.slot_per_round
+}
+
+/// Opens the elements in `range` of the PRF of `setup` for `attempt` of
+/// `round`, computing them from the key share.
+pub fn open_range(
+    params: &ProtocolParams,
+    setup: &ClientSetup,
+    round: usize,
+    attempt: usize,
+    range: Range<usize>,
+) -> Opening {
+    let powers = InversePowers::new(params);
+    let hash_ntt = hash_ntt(params, round, attempt);
+    let (share, e_share, carry) =
+        open_vector(params, &powers, &setup.share, &hash_ntt, range.clone());
+    let (blinding, e_blinding, carry_blinding) =
+        open_vector(params, &powers, &setup.blinding, &hash_ntt, range);
+    let e = e_share
+        .par_iter()
+        .zip(e_blinding.par_iter())
+        .map(|(a, b)| {
+            to_bytes(
+                params,
+                &add(
+                    params,
+                    &mul(params, &get_g(params), a),
+                    &mul(params, &get_h(params), b),
+                ),
+            )
+        })
+        .collect();
+    Opening {
+        share,
+        blinding,
+        e,
+        carry,
+        carry_blinding,
+    }
+}
+
+/// Checks `opening` of the elements in `range` against the `commitments` to
+/// the key share of a client. `hash_ntt` holds the hash of the opened round.
+fn check_opening(
+    params: &ProtocolParams,
+    powers: &InversePowers,
+    hash_ntt: &[Integer],
+    commitments: Option<&Vec<Vec<u8>>>,
+    range: Range<usize>,
+    opening: &Opening,
+) -> bool {
+    let commitments = match commitments {
+        Some(commitments) if commitments.len() == params.vector_len => commitments,
+        _ => return false,
+    };
+    [
+        opening.share.len(),
+        opening.blinding.len(),
+        opening.e.len(),
+        opening.carry.len(),
+        opening.carry_blinding.len(),
+    ]
+    .iter()
+    .all(|len| *len == range.len())
+        && expected_qw(
+            params,
+            powers,
+            hash_ntt,
+            commitments,
+            &opening.carry,
+            &opening.carry_blinding,
+            range,
+        )
+        .is_some_and(|qw| verify(params, &opening.share, &opening.blinding, &opening.e, &qw))
+}
+
+/// Opens the PRF values of the slot group `slot` in `round`.
+pub fn open_slot(
+    c: &Config,
+    nid: usize,
+    bulk_prf: &ClientSetup,
+    round: usize,
+    slot: usize,
+) -> ClientBlameMessage {
+    ClientBlameMessage {
+        round,
+        nid,
+        slot,
+        opening: open_range(&c.bulk_params, bulk_prf, round, 0, slot_range(c, slot)),
+    }
+}
+
+/// Opens the power sums and the number of IDs of `attempt` of the base round
+/// `round`, in which the client drew `ids`.
+pub fn open_base(
+    c: &Config,
+    nid: usize,
+    base_prf: &ClientSetup,
+    round: usize,
+    attempt: usize,
+    ids: &[Integer],
+) -> ClientBaseBlameMessage {
+    ClientBaseBlameMessage {
+        round,
+        attempt,
+        nid,
+        ids: ids.to_vec(),
+        opening: open_range(
+            &c.base_params,
+            base_prf,
+            round,
+            attempt,
+            0..reservation_capacity(c) + 1,
+        ),
+    }
+}
+
+/// Checks whether `message` differs from `share` in the slot group `slot` by
+/// nothing but the encoding of a payload with `digest`.
+fn is_payload(
+    c: &Config,
+    message: &ClientBulkMessage,
+    share: &[Integer],
+    slot: usize,
+    digest: &[u8],
+) -> bool {
+    let q = &c.bulk_params.q;
+    let chunks: Option<Vec<Integer>> = message
+        .slot_messages
+        .iter_range(slot_range(c, slot))
+        .zip(share.iter())
+        .map(|(m, s)| {
+            let mut diff = (m - s) % q;
+            if diff < 0 {
+                diff += q;
+            }
+            let (chunk, rem) = diff.div_rem(Integer::from(c.bulk_params.error_scale));
+            (rem == 0).then_some(chunk)
+        })
+        .collect();
+    chunks.is_some_and(|chunks| {
+        decode_payload(c, &chunks).is_some_and(|payload| {
+            payload_digest(&payload) == digest
+                && encode_payload(c, &payload).as_ref() == Some(&chunks)
+        })
+    })
+}
+
+/// Finds the clients who disrupted the slot group `slot` of `round`, whose
+/// owner wrote a payload with `digest` into it.
+///
+/// Each client in `expected` must open the slot group. A client is guilty if
+/// it did not, or if its opening does not match its commitments in
+/// `bulk_prf`. A client is also guilty if its bulk message differs from its
+/// opening in the slot group by anything but a payload with `digest`, i.e. if
+/// it wrote into a slot group it does not own. The owner is not told apart
+/// from the others, so the accusation does not bind it to its slot group.
+#[allow(clippy::too_many_arguments)]
+pub fn find_guilty(
+    c: &Config,
+    bulk_prf: &RelaySetup,
+    round: usize,
+    slot: usize,
+    digest: &[u8],
+    expected: &[usize],
+    messages: &HashMap<usize, ClientBulkMessage>,
+    openings: &HashMap<usize, ClientBlameMessage>,
+) -> Vec<usize> {
+    let params = &c.bulk_params;
+    let range = slot_range(c, slot);
+    let commitments = bulk_prf.commitments.as_ref();
+    let powers = InversePowers::new(params);
+    let hash_ntt = hash_ntt(params, round, 0);
+    let mut guilty: Vec<usize> = expected
+        .iter()
+        .copied()
+        .filter(|nid| {
+            let opening = match openings.get(nid) {
+                Some(msg) => &msg.opening,
+                None => {
+                    warn!("Client {} did not open slot {}.", nid, slot);
+                    return true;
+                }
+            };
+            if !check_opening(
+                params,
+                &powers,
+                &hash_ntt,
+                commitments.and_then(|commitments| commitments.get(*nid)),
+                range.clone(),
+                opening,
+            ) {
+                warn!("Client {} failed to open slot {}.", nid, slot);
+                return true;
+            }
+            match messages.get(nid) {
+                Some(msg) => {
+                    msg.slot_messages
+                        .iter_range(range.clone())
+                        .zip(opening.share.iter())
+                        .any(|(m, s)| (m - s) % &params.q != 0)
+                        && !is_payload(c, msg, &opening.share, slot, digest)
+                }
+                None => false,
+            }
+        })
+        .collect();
+    guilty.sort_unstable();
+    guilty
+}
+
+/// Finds the clients who sent a malformed message in `attempt` of the base
+/// round `round`.
+///
+/// Each client in `expected` must open the attempt. A client is guilty if it
+/// did not, if its opening does not match its commitments in `base_prf`, or if
+/// its base message differs from its opening by anything but the power sums
+/// and the number of the IDs it revealed, at most `max_reservation` of them.
+pub fn find_base_guilty(
+    c: &Config,
+    base_prf: &RelaySetup,
+    round: usize,
+    attempt: usize,
+    expected: &[usize],
+    messages: &HashMap<usize, ClientBaseMessage>,
+    openings: &HashMap<usize, ClientBaseBlameMessage>,
+) -> Vec<usize> {
+    let params = &c.base_params;
+    let range = 0..reservation_capacity(c) + 1;
+    let commitments = base_prf.commitments.as_ref();
+    let powers = InversePowers::new(params);
+    let hash_ntt = hash_ntt(params, round, attempt);
+    let mut guilty: Vec<usize> = expected
+        .iter()
+        .copied()
+        .filter(|nid| {
+            let (opening, message) = match (openings.get(nid), messages.get(nid)) {
+                (Some(opening), Some(message)) => (opening, message),
+                _ => {
+                    warn!("Client {} did not open attempt {}.", nid, attempt);
+                    return true;
+                }
+            };
+            let well_formed = opening.ids.len() <= c.max_reservation
+                && opening.ids.iter().all(|id| *id >= 0 && id < &params.p)
+                && check_opening(
+                    params,
+                    &powers,
+                    &hash_ntt,
+                    commitments.and_then(|commitments| commitments.get(*nid)),
+                    range.clone(),
+                    &opening.opening,
+                )
+                && message.slot_messages.iter_range(range.clone()).eq(
+                    generate_client_base_message(c, &opening.opening.share, &opening.ids),
+                );
+            if !well_formed {
+                warn!("Client {} sent a malformed base message.", nid);
+            }
+            !well_formed
+        })
+        .collect();
+    guilty.sort_unstable();
+    guilty
+}
+
+/// Bulk round of a recent round, kept for accusations.
+struct Record {
+    /// IDs of the slot groups, in the order of the slot groups.
+    perm: Vec<Integer>,
+    /// Bulk messages of the clients.
+    messages: HashMap<usize, ClientBulkMessage>,
+}
+
+/// Accusation being processed.
+struct Current {
+    /// The accusation.
+    accusation: ClientAccusationMessage,
+    /// Openings received for it.
+    openings: HashMap<usize, ClientBlameMessage>,
+    /// Time after which the clients that did not open are guilty.
+    deadline: Instant,
+}
+
+/// State of the blame protocol on the relay.
+#[derive(Default)]
+pub struct BlameState {
+    /// Bulk rounds of recent rounds.
+    records: HashMap<usize, Record>,
+    /// Accusations waiting to be processed.
+    pending: VecDeque<ClientAccusationMessage>,
+    /// Slot groups of recent rounds already accused, each of which is
+    /// opened at most once.
+    accused: HashSet<(usize, usize)>,
+    /// Accusation being processed.
+    current: Option<Current>,
+    /// Clients proven guilty, whose messages are ignored from now on.
+    pub excluded: HashSet<usize>,
+}
+
+impl BlameState {
+    /// Keeps the IDs `perm` and the bulk messages of `round` so that its slot
+    /// groups can be accused.
+    pub fn record(
+        &mut self,
+        round: usize,
+        perm: Vec<Integer>,
+        messages: HashMap<usize, ClientBulkMessage>,
+    ) {
+        self.records.insert(round, Record { perm, messages });
+        self.records.retain(|r, _| r + BLAME_WINDOW > round);
+        self.accused.retain(|(r, _)| r + BLAME_WINDOW > round);
+    }
+
+    /// Queues an accusation. Returns the messages to broadcast.
+    pub fn accuse(&mut self, c: &Config, msg: ClientAccusationMessage) -> Vec<Message> {
+        info!("Slot {} on round {} is accused.", msg.slot, msg.round);
+        self.pending.push_back(msg);
+        self.start_next(c)
+    }
+
+    /// Returns the round and the deadline of the accusation being processed.
+    pub fn deadline(&self) -> Option<(usize, Instant)> {
+        self.current
+            .as_ref()
+            .map(|current| (current.accusation.round, current.deadline))
+    }
+
+    /// Records an opening for the current accusation. Returns the messages
+    /// to broadcast.
+    pub fn open(
+        &mut self,
+        c: &Config,
+        bulk_prf: &RelaySetup,
+        msg: ClientBlameMessage,
+    ) -> Vec<Message> {
+        if !bulk_prf.members.contains(&msg.nid) || self.excluded.contains(&msg.nid) {
+            warn!("Ignoring opening from {}.", msg.nid);
+            return Vec::new();
+        }
+        let openings = match self.current.as_mut() {
+            Some(current)
+                if current.accusation.round == msg.round && current.accusation.slot == msg.slot =>
+            {
+                &mut current.openings
+            }
+            _ => {
+                warn!(
+                    "Unexpected opening from {} for slot {} on round {}.",
+                    msg.nid, msg.slot, msg.round
+                );
+                return Vec::new();
+            }
+        };
+        if let Err(e) = insert_once(openings, msg.nid, msg.round, msg) {
+            error!("Rejecting ClientBlameMessage: {}.", e);
+            return Vec::new();
+        }
+        let current = self.current.as_ref().unwrap();
+        if self
+            .expected(current.accusation.round)
+            .iter()
+            .any(|nid| !current.openings.contains_key(nid))
+        {
+            return Vec::new();
+        }
+        self.finish(c, bulk_prf)
+    }
+
+    /// Finishes the current accusation if its deadline has passed, finding
+    /// the clients that did not open guilty. Returns the messages to
+    /// broadcast.
+    pub fn expire(&mut self, c: &Config, bulk_prf: &RelaySetup) -> Vec<Message> {
+        match self.current.as_ref() {
+            Some(current) if current.deadline <= Instant::now() => {
+                warn!(
+                    "Blame on slot {} of round {} timed out.",
+                    current.accusation.slot, current.accusation.round
+                );
+                self.finish(c, bulk_prf)
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    /// Returns the clients that must open a slot group of `round`, i.e. the
+    /// ones that sent a bulk message and are not excluded yet.
+    fn expected(&self, round: usize) -> Vec<usize> {
+        let mut expected: Vec<usize> = self
+            .records
+            .get(&round)
+            .map(|record| {
+                record
+                    .messages
+                    .keys()
+                    .copied()
+                    .filter(|nid| !self.excluded.contains(nid))
+                    .collect()
+            })
+            .unwrap_or_default();
+        expected.sort_unstable();
+        expected
+    }
+
+    /// Finds the guilty clients of the current accusation and starts the next
+    /// one. Returns the messages to broadcast.
+    fn finish(&mut self, c: &Config, bulk_prf: &RelaySetup) -> Vec<Message> {
+        let current = match self.current.take() {
+            Some(current) => current,
+            None => return Vec::new(),
+        };
+        let accusation = &current.accusation;
+        let expected = self.expected(accusation.round);
+        let guilty = match self.records.get(&accusation.round) {
+            Some(record) => find_guilty(
+                c,
+                bulk_prf,
+                accusation.round,
+                accusation.slot,
+                &accusation.digest,
+                &expected,
+                &record.messages,
+                &current.openings,
+            ),
+            None => Vec::new(),
+        };
+        warn!(
+            "Blame on slot {} of round {} finds {:?} guilty.",
+            accusation.slot, accusation.round, guilty
+        );
+        self.excluded.extend(guilty.iter());
+        let result = Message::ServerBlameResultMessage(ServerBlameResultMessage {
+            round: accusation.round,
+            slot: Some(accusation.slot),
+            guilty,
+        });
+        std::iter::once(result).chain(self.start_next(c)).collect()
+    }
+
+    /// Starts processing the next valid accusation if none is in progress.
+    /// An accusation is valid if its secret matches the ID of a slot group
+    /// of a recent round that was not accused before.
+    fn start_next(&mut self, c: &Config) -> Vec<Message> {
+        while self.current.is_none() {
+            let accusation = match self.pending.pop_front() {
+                Some(accusation) => accusation,
+                None => break,
+            };
+            let owned = self.records.get(&accusation.round).is_some_and(|record| {
+                record.perm.get(accusation.slot)
+                    == Some(&slot_id(&c.base_params, &accusation.secret))
+                    && record
+                        .messages
+                        .values()
+                        .all(|msg| slot_range(c, accusation.slot).end <= msg.slot_messages.len())
+            });
+            if !owned || !self.accused.insert((accusation.round, accusation.slot)) {
+                warn!(
+                    "Dropping accusation of slot {} on round {}.",
+                    accusation.slot, accusation.round
+                );
+                continue;
+            }
+            let request = ServerBlameRequestMessage {
+                round: accusation.round,
+                slot: accusation.slot,
+                secret: accusation.secret.clone(),
+            };
+            self.current = Some(Current {
+                accusation,
+                openings: HashMap::new(),
+                deadline: blame_deadline(c),
+            });
+            return vec![Message::ServerBlameRequestMessage(request)];
+        }
+        Vec::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::blame::{find_base_guilty, find_guilty, open_base, open_slot, slot_id, BlameState};
+    use crate::client::{
+        encode_payload, generate_client_base_message, generate_client_bulk_message, payload_digest,
+    };
+    use crate::config::{Config, ProtocolParams};
+    use crate::guard::{commit, gen_blinding, generate_sum_shares, ClientSetup, RelaySetup};
+    use crate::message::{ClientAccusationMessage, ClientBaseMessage, ClientBulkMessage, Message};
+    use crate::packed::{width, PackedVec};
+    use crate::prf::Masks;
+    use crate::rng::Rng;
+    use rug::Integer;
+    use std::collections::HashMap;
+
+    fn get_config() -> Config {
+        let mut c = Config::new("127.0.0.1:8001".parse().unwrap(), 3, 1, 1);
+        c.base_params.vector_len = 16;
+        c.bulk_params.vector_len = 8;
+        c.do_blame = true;
+        c
+    }
+
+    /// Returns the setup of `n` clients and the relay under `params`.
+    fn get_setup(params: &ProtocolParams, n: usize) -> (Vec<ClientSetup>, RelaySetup) {
+        let mut rng = Rng::from_seed(0, 0);
+        let shares: Vec<Vec<Integer>> = (0..params.vector_len)
+            .map(|_| generate_sum_shares(n, &params.ring_v.order, &Integer::from(1), &mut rng))
+            .collect();
+        let clients: Vec<_> = (0..n)
+            .map(|i| ClientSetup {
+                seeds: Vec::new(),
+                share: shares.iter().map(|v| v[i].clone()).collect(),
+                blinding: gen_blinding(params, &mut rng),
+            })
+            .collect();
+        let relay = RelaySetup {
+            members: (0..n).collect(),
+            offsets: Vec::new(),
+            commitments: Some(
+                clients
+                    .iter()
+                    .map(|client| commit(params, &client.share, &client.blinding))
+                    .collect(),
+            ),
+        };
+        (clients, relay)
+    }
+
+    /// Returns the bulk messages of round 1, in which client 0 writes `abc`
+    /// into slot group 0 and every other client into its own slot group,
+    /// except for `disruptor`, which writes `payload` into slot group 0.
+    fn get_messages(
+        c: &Config,
+        clients: &[ClientSetup],
+        disruptor: Option<usize>,
+        payload: &[u8],
+    ) -> HashMap<usize, ClientBulkMessage> {
+        let params = &c.bulk_params;
+        (0..c.client_size)
+            .map(|i| {
+                let (posid, payload) = match i {
+                    0 => (0, b"abc".as_slice()),
+                    _ if Some(i) == disruptor => (0, payload),
+                    _ => (i, b"abc".as_slice()),
+                };
+                let slot_messages = PackedVec::pack(
+                    &generate_client_bulk_message(
+                        c,
+                        &Masks::new(params, &clients[i].share, 1, false).get(params, 1, 0),
+                        c.client_size,
+                        vec![(posid, encode_payload(c, payload).unwrap())],
+                    ),
+                    width(&params.q),
+                );
+                let msg = ClientBulkMessage {
+                    round: 1,
+                    nid: i,
+                    slot_messages,
+                    self_shares: None,
+                };
+                (i, msg)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn find_guilty_test() {
+        let c = get_config();
+        let (clients, relay) = get_setup(&c.bulk_params, c.client_size);
+        let mut openings: HashMap<_, _> = (0..c.client_size)
+            .map(|i| (i, open_slot(&c, i, &clients[i], 1, 0)))
+            .collect();
+        let digest = payload_digest(b"abc");
+        let all = [0, 1, 2];
+        // Nobody but the owner writes into slot group 0, so nobody is guilty
+        // whoever the owner is.
+        let messages = get_messages(&c, &clients, None, b"");
+        assert!(find_guilty(&c, &relay, 1, 0, &digest, &all, &messages, &openings).is_empty());
+        // A client writing into the slot group of another is guilty, and
+        // the owner cannot be framed.
+        let messages = get_messages(&c, &clients, Some(2), b"xyz");
+        assert_eq!(
+            find_guilty(&c, &relay, 1, 0, &digest, &all, &messages, &openings),
+            vec![2]
+        );
+        // An owner accusing with the digest of another payload finds itself.
+        assert_eq!(
+            find_guilty(
+                &c,
+                &relay,
+                1,
+                0,
+                &payload_digest(b"xyz"),
+                &all,
+                &messages,
+                &openings
+            ),
+            vec![0]
+        );
+        // An opening that does not match the commitments is guilty, as is one
+        // of another round and a missing one.
+        openings.get_mut(&1).unwrap().opening.share[0] += 1;
+        assert_eq!(
+            find_guilty(&c, &relay, 1, 0, &digest, &all, &messages, &openings),
+            vec![1, 2]
+        );
+        openings.insert(1, open_slot(&c, 1, &clients[1], 2, 0));
+        assert_eq!(
+            find_guilty(&c, &relay, 1, 0, &digest, &all, &messages, &openings),
+            vec![1, 2]
+        );
+        openings.remove(&1);
+        assert_eq!(
+            find_guilty(&c, &relay, 1, 0, &digest, &all, &messages, &openings),
+            vec![1, 2]
+        );
+    }
+
+    #[test]
+    fn accusation_test() {
+        let mut c = get_config();
+        let (clients, relay) = get_setup(&c.bulk_params, c.client_size);
+        let secrets: Vec<Vec<u8>> = (0..c.client_size as u8).map(|i| vec![i; 32]).collect();
+        let perm: Vec<Integer> = secrets
+            .iter()
+            .map(|secret| slot_id(&c.base_params, secret))
+            .collect();
+        let mut blame = BlameState::default();
+        blame.record(1, perm, get_messages(&c, &clients, Some(2), b"xyz"));
+        let accusation = |slot: usize, secret: &[u8]| ClientAccusationMessage {
+            round: 1,
+            slot,
+            secret: secret.to_vec(),
+            digest: payload_digest(b"abc"),
+        };
+        // Only the owner of a slot group can accuse it, in a recorded round.
+        assert!(blame.accuse(&c, accusation(0, &secrets[1])).is_empty());
+        assert!(blame.accuse(&c, accusation(3, &secrets[0])).is_empty());
+        let mut wrong_round = accusation(0, &secrets[0]);
+        wrong_round.round = 2;
+        assert!(blame.accuse(&c, wrong_round).is_empty());
+        assert!(matches!(
+            blame.accuse(&c, accusation(0, &secrets[0])).as_slice(),
+            [Message::ServerBlameRequestMessage(msg)] if msg.slot == 0
+        ));
+        for (i, client) in clients.iter().enumerate() {
+            let outputs = blame.open(&c, &relay, open_slot(&c, i, client, 1, 0));
+            // Each client opens once.
+            assert!(blame
+                .open(&c, &relay, open_slot(&c, i, client, 1, 0))
+                .is_empty());
+            if i + 1 < clients.len() {
+                assert!(outputs.is_empty());
+            } else {
+                assert!(matches!(
+                    outputs.as_slice(),
+                    [Message::ServerBlameResultMessage(msg)] if msg.guilty == vec![2]
+                ));
+            }
+        }
+        assert!(blame.excluded.contains(&2));
+        // A slot group is opened once.
+        assert!(blame.accuse(&c, accusation(0, &secrets[0])).is_empty());
+        // Clients that do not open in time are guilty.
+        c.round_timeout = Some(0);
+        assert_eq!(blame.accuse(&c, accusation(1, &secrets[1])).len(), 1);
+        assert!(blame.deadline().is_some());
+        blame.open(&c, &relay, open_slot(&c, 1, &clients[1], 1, 1));
+        assert!(matches!(
+            blame.expire(&c, &relay).as_slice(),
+            [Message::ServerBlameResultMessage(msg)] if msg.guilty == vec![0]
+        ));
+        assert!(blame.deadline().is_none());
+    }
+
+    #[test]
+    fn find_base_guilty_test() {
+        let c = get_config();
+        let params = &c.base_params;
+        let (clients, relay) = get_setup(params, c.client_size);
+        let attempt = 1;
+        let ids: Vec<Vec<Integer>> = vec![vec![Integer::from(5)], vec![], vec![Integer::from(7)]];
+        let mut messages: HashMap<_, _> = (0..c.client_size)
+            .map(|i| {
+                let mask = Masks::new(params, &clients[i].share, 1, false).get(params, 1, attempt);
+                let msg = ClientBaseMessage {
+                    round: 1,
+                    attempt,
+                    nid: i,
+                    slot_messages: PackedVec::pack(
+                        &generate_client_base_message(&c, &mask, &ids[i]),
+                        width(&params.q),
+                    ),
+                    self_shares: None,
+                };
+                (i, msg)
+            })
+            .collect();
+        let mut openings: HashMap<_, _> = (0..c.client_size)
+            .map(|i| (i, open_base(&c, i, &clients[i], 1, attempt, &ids[i])))
+            .collect();
+        let all = [0, 1, 2];
+        assert!(find_base_guilty(&c, &relay, 1, attempt, &all, &messages, &openings).is_empty());
+        // Client 2 claims a reservation it does not fill.
+        let mask = Masks::new(params, &clients[2].share, 1, false).get(params, 1, attempt);
+        let mut garbage = generate_client_base_message(&c, &mask, &ids[2]);
+        garbage[0] += c.base_params.error_scale;
+        messages.get_mut(&2).unwrap().slot_messages = PackedVec::pack(&garbage, width(&params.q));
+        assert_eq!(
+            find_base_guilty(&c, &relay, 1, attempt, &all, &messages, &openings),
+            vec![2]
+        );
+        // Client 1 reveals IDs it did not use, and client 0 does not open.
+        openings.get_mut(&1).unwrap().ids = vec![Integer::from(3)];
+        openings.remove(&0);
+        assert_eq!(
+            find_base_guilty(&c, &relay, 1, attempt, &all, &messages, &openings),
+            vec![0, 1, 2]
+        );
+    }
+}
@@ -1,58 +1,224 @@
-use crate::config::Config;
-use crate::guard::SetupValues;
-use crate::message::{ClientBaseMessage, ClientBulkMessage, ClientPrifiMessage, Message};
-use crate::net::{read_stream, write_stream};
-use rug::Integer;
+use crate::blame::{open_base, open_slot, slot_id, BLAME_WINDOW, SECRET_LEN};
+use crate::config::{Config, ProtocolParams};
+use crate::error::Error;
+use crate::guard::{ClientSetup, SEED_LEN};
+use crate::message::{
+    Capabilities, ClientAccusationMessage, ClientBaseMessage, ClientBulkMessage,
+    ClientPrifiMessage, ClientRecoveryMessage, Hello, Message, Phase, SelfShares,
+    ServerBlameRequestMessage, Welcome,
+};
+use crate::net::{read_stream, write_stream, Limits};
+use crate::packed::{width, PackedVec};
+use crate::prf::Masks;
+use crate::recovery::{
+    client_recovery, deal_self_shares, members_of, open_self_share, recovery_threshold, self_mask,
+    self_seed,
+};
+use crate::rng::Rng;
+use crate::secure::{Identity, Peer, SecureStream};
+use crate::server::solve_sums;
+use rug::{integer::Order, Integer};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
 use std::net::TcpStream;
+use std::time::Duration;
 
-/// Adds randomness to generate the cipher text for the base round.
-pub fn generate_client_base_message(
+/// Number of bits reserved for the payload length in the bulk round.
+const PAYLOAD_LEN_BITS: usize = 32;
+
+/// Delay before the first reconnect.
+const RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Largest delay between two reconnects.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(10);
+
+/// Number of reconnects after which the client gives up.
+const MAX_RECONNECTS: usize = 10;
+
+/// Setup of the base or the bulk round with the masks of its key share.
+struct Prf {
+    /// Setup of the round.
+    setup: ClientSetup,
+    /// Masks of the key share.
+    masks: Masks,
+    /// Secret from which the self masks of the round are derived.
+    secret: Vec<u8>,
+}
+
+impl Prf {
+    /// Returns the first `len` elements of the mask of `nid` for `attempt` of
+    /// `round` in `phase`. With `Config::self_masks`, adds the self mask and
+    /// returns the shares of its seed to send along.
+    #[allow(clippy::too_many_arguments)]
+    fn mask(
+        &self,
+        c: &Config,
+        params: &ProtocolParams,
+        nid: usize,
+        phase: Phase,
+        round: usize,
+        attempt: usize,
+        len: usize,
+    ) -> (Vec<Integer>, Option<SelfShares>) {
+        let mut scaled = self.masks.get(params, round, attempt);
+        scaled.truncate(len);
+        if !c.self_masks() {
+            return (scaled, None);
+        }
+        let seed = self_seed(&self.secret, round, phase, attempt);
+        for (x, m) in scaled.iter_mut().zip(self_mask(params, &seed, len)) {
+            *x = (&*x + m) % &params.q;
+        }
+        let shares = deal_self_shares(nid, &self.secret, &self.setup.seeds, round, phase, attempt);
+        (scaled, Some(shares))
+    }
+}
+
+/// Slot group IDs the relay published in the recent rounds, kept to check
+/// the blame requests against.
+#[derive(Default)]
+struct BlameLog {
+    /// Slot group IDs of each recent round, in the order of the slot groups.
+    perms: BTreeMap<usize, Vec<Integer>>,
+    /// Rounds and slot groups already opened.
+    opened: HashSet<(usize, usize)>,
+}
+
+impl BlameLog {
+    /// Records the slot group IDs of `round` and forgets the rounds too old
+    /// to be blamed.
+    fn record(&mut self, c: &Config, round: usize, perm: Vec<Integer>) {
+        self.perms.insert(round, perm);
+        let recent = |r: usize| r + BLAME_WINDOW + c.pipeline_depth > round;
+        self.perms.retain(|r, _| recent(*r));
+        self.opened.retain(|(r, _)| recent(*r));
+    }
+
+    /// Checks that the blame request reveals the secret of a slot group of a
+    /// recent round, and that the slot group was not opened yet.
+    fn admit(&mut self, c: &Config, msg: &ServerBlameRequestMessage) -> bool {
+        let owned = self
+            .perms
+            .get(&msg.round)
+            .and_then(|perm| perm.get(msg.slot))
+            .is_some_and(|id| *id == slot_id(&c.base_params, &msg.secret));
+        owned && self.opened.insert((msg.round, msg.slot))
+    }
+}
+
+/// Returns how many slot groups all clients may reserve in one round, i.e.
+/// the number of power sums in the base round.
+pub fn reservation_capacity(c: &Config) -> usize {
+    c.client_size * c.max_reservation
+}
+
+/// Adds randomness to generate the cipher text for the base round. The first
+/// `reservation_capacity` elements hold the power sums of `ids`, one ID per
+/// reserved slot group, and the next element holds the number of IDs.
+pub fn generate_client_base_message(c: &Config, prf: &[Integer], ids: &[Integer]) -> Vec<Integer> {
+    let capacity = reservation_capacity(c);
+    let mut powers = ids.to_vec();
+    let mut slot_messages = Vec::<Integer>::with_capacity(prf.len());
+    for (i, p) in prf.iter().enumerate() {
+        let slot_msg = if i < capacity {
+            let sum = powers.iter().fold(Integer::new(), |acc, x| acc + x) % &c.base_params.p;
+            for (power, id) in powers.iter_mut().zip(ids) {
+                *power *= id;
+                *power %= &c.base_params.p;
+            }
+            sum
+        } else if i == capacity {
+            Integer::from(ids.len())
+        } else {
+            Integer::new()
+        };
+        let msg_to_append = (p + c.base_params.error_scale * slot_msg) % &c.base_params.q;
+        slot_messages.push(msg_to_append);
+    }
+    slot_messages
+}
+
+/// Returns how many bytes of payload fit into the slots of one client in the bulk round.
+pub fn payload_capacity(c: &Config) -> usize {
+    (c.slot_per_round * c.bulk_params.bits).saturating_sub(PAYLOAD_LEN_BITS) / 8
+}
+
+/// Splits the payload into `bulk_params.bits`-sized chunks, one for each slot
+/// the client owns in the bulk round. The payload is prefixed with its length
+/// so that the relay can reassemble it. Returns `None` if the payload does not
+/// fit.
+pub fn encode_payload(c: &Config, payload: &[u8]) -> Option<Vec<Integer>> {
+    if payload.len() > payload_capacity(c) {
+        return None;
+    }
+    let bits = c.bulk_params.bits as u32;
+    let mut digits = (payload.len() as u32).to_le_bytes().to_vec();
+    digits.extend_from_slice(payload);
+    let packed = Integer::from_digits(&digits, Order::Lsf);
+    Some(
+        (0..c.slot_per_round as u32)
+            .map(|i| Integer::from(&packed >> (i * bits)).keep_bits(bits))
+            .collect(),
+    )
+}
+
+/// Returns the digest the relay publishes for a decoded payload.
+pub fn payload_digest(payload: &[u8]) -> Vec<u8> {
+    Sha256::digest(payload).to_vec()
+}
+
+/// Adds randomness to generate the cipher text for the bulk round of `groups`
+/// slot groups, with each payload written into its slot group `posid`.
+pub fn generate_client_bulk_message(
     c: &Config,
     prf: &[Integer],
-    message_ele: &Integer,
+    groups: usize,
+    chunks: Vec<(usize, Vec<Integer>)>,
 ) -> Vec<Integer> {
-    let mut slot_msg = Integer::from(1);
-    let mut slot_messages = Vec::<Integer>::with_capacity(c.client_size);
-    for p in prf {
-        slot_msg *= message_ele;
-        slot_msg %= &c.base_params.p;
-        let msg_to_append = Integer::from(p + 1000 * &slot_msg) % &c.base_params.q;
-        slot_messages.push(msg_to_append);
+    let mut prf_evaluations = prf[0..c.slot_per_round * groups].to_vec();
+    for (posid, chunks) in chunks {
+        let slot_index_start = posid * c.slot_per_round;
+        let slot_index_end = (posid + 1) * c.slot_per_round;
+        for (eval, chunk) in prf_evaluations[slot_index_start..slot_index_end]
+            .iter_mut()
+            .zip(chunks)
+        {
+            *eval = (&*eval + c.bulk_params.error_scale * chunk) % &c.bulk_params.q;
+        }
     }
-    slot_messages
+    prf_evaluations
 }
 
-/// Processes and sends the base round message.
+/// Processes and sends the base round message. `base_prf` holds the masks of
+/// the base round.
 fn send_client_base_message(
     c: &Config,
     nid: usize,
-    base_prf: &SetupValues,
-    message_ele: &Integer,
-    socket: &mut TcpStream,
+    base_prf: &Prf,
+    ids: &[Integer],
+    socket: &mut SecureStream,
     round: usize,
-) {
-    let mut scaled = base_prf.share.scaled.clone();
-    // If we need to compute PRF on-demand...
-    if c.do_unzip {
-        scaled = crate::prf::compute(&c.base_params, base_prf);
-    }
+    attempt: usize,
+) -> Result<(), Error> {
+    let (scaled, self_shares) = base_prf.mask(
+        c,
+        &c.base_params,
+        nid,
+        Phase::Base,
+        round,
+        attempt,
+        c.base_params.vector_len,
+    );
     let message = bincode::serialize(&Message::ClientBaseMessage(ClientBaseMessage {
         round,
+        attempt,
         nid,
-        slot_messages: generate_client_base_message(c, &scaled, message_ele),
-        blame: if c.do_blame {
-            Some(base_prf.share.scaled.clone())
-        } else {
-            None
-        },
-        blame_blinding: if c.do_blame {
-            Some(base_prf.blinding.scaled.clone())
-        } else {
-            None
-        },
-        e: base_prf.e.clone(),
-    }))
-    .unwrap();
+        slot_messages: PackedVec::pack(
+            &generate_client_base_message(c, &scaled, ids),
+            width(&c.base_params.q),
+        ),
+        self_shares,
+    }))?;
 
     // Sleep to mesaure the optimal round trip time.
     if c.do_delay && nid == 0 {
@@ -60,37 +226,59 @@ fn send_client_base_message(
     }
 
     info!("Sending ClientBaseMessage, size = {}...", message.len());
-    write_stream(socket, &message).unwrap();
+    socket.write(&message)?;
     info!("Sent ClientBaseMessage.");
+    Ok(())
 }
 
-/// Processes and sends the bulk round message.
+/// Processes and sends the bulk round message of `groups` slot groups, with
+/// each payload in its slot group. `bulk_prf` holds the masks of the bulk
+/// round. Returns the payloads that were actually sent.
+#[allow(clippy::too_many_arguments)]
 fn send_client_bulk_message(
     c: &Config,
     nid: usize,
-    posid: usize,
-    bulk_prf: &SetupValues,
-    socket: &mut TcpStream,
+    groups: usize,
+    bulk_prf: &Prf,
+    payloads: Vec<(usize, Vec<u8>)>,
+    socket: &mut SecureStream,
     round: usize,
-) {
-    let mut scaled = bulk_prf.share.scaled.clone();
-    // If we need to compute PRF on-demand...
-    if c.do_unzip {
-        scaled = crate::prf::compute(&c.bulk_params, bulk_prf);
-    }
-    let slot_index_start = posid * c.slot_per_round;
-    let slot_index_end = (posid + 1) * c.slot_per_round;
-    let mut prf_evaluations = scaled[0..c.slot_per_round * c.client_size].to_vec();
-    let message_ele = nid + 1;
-    for eval in prf_evaluations[slot_index_start..slot_index_end].iter_mut() {
-        *eval = (&*eval + Integer::from(1000 * message_ele)) % &c.bulk_params.q;
-    }
+) -> Result<Vec<(usize, Vec<u8>)>, Error> {
+    let (scaled, self_shares) = bulk_prf.mask(
+        c,
+        &c.bulk_params,
+        nid,
+        Phase::Bulk,
+        round,
+        0,
+        c.slot_per_round * groups,
+    );
+    let (payloads, chunks): (Vec<_>, Vec<_>) = payloads
+        .into_iter()
+        .map(|(posid, payload)| match encode_payload(c, &payload) {
+            Some(chunks) => ((posid, payload), (posid, chunks)),
+            None => {
+                error!(
+                    "Payload of size {} exceeds capacity {}. Sending an empty message.",
+                    payload.len(),
+                    payload_capacity(c)
+                );
+                (
+                    (posid, Vec::new()),
+                    (posid, encode_payload(c, &[]).unwrap()),
+                )
+            }
+        })
+        .unzip();
     let message = bincode::serialize(&Message::ClientBulkMessage(ClientBulkMessage {
         round,
         nid,
-        slot_messages: prf_evaluations,
-    }))
-    .unwrap();
+        slot_messages: PackedVec::pack(
+            &generate_client_bulk_message(c, &scaled, groups, chunks),
+            width(&c.bulk_params.q),
+        ),
+        self_shares,
+    }))?;
 
     // Sleep to mesaure the optimal round trip time.
     if c.do_delay && nid == 0 {
@@ -98,84 +286,616 @@ fn send_client_bulk_message(
     }
 
     info!("Sending ClientBulkMessage, size = {}...", message.len());
-    write_stream(socket, &message).unwrap();
+    socket.write(&message)?;
     info!("Sent ClientBulkMessage.");
+    Ok(payloads)
 }
 
-/// Overarching function.
-pub fn main(c: Config, nid: usize, base_prf: SetupValues, bulk_prf: SetupValues) {
-    debug!("Connecting to {:?}...", c.server_addr);
-    let mut socket = TcpStream::connect(c.server_addr).unwrap();
-    let mut round: usize = 0;
-    let mut rand = rug::rand::RandState::new();
-    rand.seed(&base_prf.share.scaled[0]);
-    loop {
-        if round < c.round {
-            round += 1;
-            info!("Round {}.", round);
+/// State of a round in flight on a client.
+enum InFlight {
+    /// Waiting for the base round, with the current attempt, the IDs drawn
+    /// for it and the payloads to send, one per ID.
+    Base {
+        /// Current attempt.
+        attempt: usize,
+        /// Secrets behind the IDs of the attempt.
+        secrets: Vec<Vec<u8>>,
+        /// IDs drawn for the attempt.
+        ids: Vec<Integer>,
+        /// Payloads to send.
+        payloads: Vec<Vec<u8>>,
+        /// Whether the attempt was opened for the blame protocol, so that
+        /// its IDs must not be used and its number of IDs is known.
+        opened: bool,
+    },
+    /// Waiting for the bulk round, with the number of slot groups and the
+    /// payloads sent in them.
+    Bulk {
+        /// Number of slot groups in the round.
+        groups: usize,
+        /// Slot groups and the payloads sent in them.
+        sent: Vec<(usize, Vec<u8>)>,
+        /// Secrets behind the IDs of the slot groups in `sent`.
+        secrets: Vec<Vec<u8>>,
+    },
+    /// Waiting for the end of a bulk round the client has no slot in.
+    Skipped,
+}
 
-            // Generate a random number for identification.
-            let message_ele = Integer::from(c.base_params.p.random_below_ref(&mut rand));
-            info!("Message in base round: {}", message_ele);
-            send_client_base_message(&c, nid, &base_prf, &message_ele, &mut socket, round);
+/// Draws `count` fresh secrets for `attempt` of the base round `round` and
+/// sends the base round message with the IDs derived from them. Returns the
+/// secrets and the IDs.
+#[allow(clippy::too_many_arguments)]
+fn draw_base_message(
+    c: &Config,
+    nid: usize,
+    base_prf: &Prf,
+    rng: &mut Rng,
+    socket: &mut SecureStream,
+    round: usize,
+    attempt: usize,
+    count: usize,
+) -> Result<(Vec<Vec<u8>>, Vec<Integer>), Error> {
+    // Generate a random secret for identification of each slot group. Only
+    // the ID derived from it is sent, so that revealing the secret proves
+    // ownership of the slot group in the blame protocol.
+    let secrets: Vec<Vec<u8>> = (0..count).map(|_| rng.bytes(SECRET_LEN)).collect();
+    let ids: Vec<Integer> = secrets
+        .iter()
+        .map(|secret| slot_id(&c.base_params, secret))
+        .collect();
+    info!("Message in base round: {:?}", ids);
+    send_client_base_message(c, nid, base_prf, &ids, socket, round, attempt)?;
+    Ok((secrets, ids))
+}
 
-            let buf = read_stream(&mut socket).unwrap();
-            let message: Message = bincode::deserialize(&buf).unwrap();
-            match message {
-                Message::ServerBaseMessage(msg) => {
-                    info!("Received ServerBaseMessage on round {}.", msg.round);
-                    if msg.round == round {
-                        send_client_bulk_message(
-                            &c,
+/// Reads the next message from the relay, answering blame and recovery
+/// requests on the way. A blame request is answered only if it reveals the
+/// secret of a slot group in `blame_log`, and only once per slot group. A
+/// recovery request is answered only for the current phase of a round
+/// `in_flight`, only once per phase as recorded in `recovered`, and only if
+/// enough members are left to keep the self masks of the others hidden.
+#[allow(clippy::too_many_arguments)]
+fn read_message(
+    c: &Config,
+    nid: usize,
+    base_prf: &Prf,
+    bulk_prf: &Prf,
+    blame_log: &mut BlameLog,
+    in_flight: &BTreeMap<usize, InFlight>,
+    recovered: &mut HashSet<(usize, Phase, usize)>,
+    socket: &mut SecureStream,
+) -> Result<Message, Error> {
+    loop {
+        let buf = socket.read()?;
+        let message: Message = bincode::deserialize(&buf)?;
+        match message {
+            Message::ServerRecoveryRequestMessage(msg) => {
+                if msg.missing.contains(&nid) {
+                    warn!("Missed the {:?} round {}.", msg.phase, msg.round);
+                    continue;
+                }
+                let (params, prf) = match msg.phase {
+                    Phase::Base => (&c.base_params, base_prf),
+                    Phase::Bulk => (&c.bulk_params, bulk_prf),
+                };
+                let seeds = &prf.setup.seeds;
+                let members = members_of(nid, seeds);
+                let present = members
+                    .iter()
+                    .filter(|member| !msg.missing.contains(member))
+                    .count();
+                let current = match (in_flight.get(&msg.round), msg.phase) {
+                    (Some(InFlight::Base { attempt, .. }), Phase::Base) => *attempt == msg.attempt,
+                    (Some(InFlight::Bulk { .. }), Phase::Bulk) => msg.attempt == 0,
+                    _ => false,
+                };
+                // A client that is both missing and present to the relay
+                // could be unmasked.
+                if !c.self_masks()
+                    || !current
+                    || present < recovery_threshold(members.len())
+                    || msg.shares.keys().any(|dealer| msg.missing.contains(dealer))
+                    || !recovered.insert((msg.round, msg.phase, msg.attempt))
+                {
+                    warn!(
+                        "Refusing to recover the masks of {:?} in the {:?} round {}.",
+                        msg.missing, msg.phase, msg.round
+                    );
+                    continue;
+                }
+                info!(
+                    "Recovering the masks of {:?} in the {:?} round {}.",
+                    msg.missing, msg.phase, msg.round
+                );
+                let recovery = if msg.missing.is_empty() {
+                    Vec::new()
+                } else {
+                    client_recovery(params, nid, seeds, &msg.missing, msg.round, msg.attempt)
+                };
+                let shares = msg
+                    .shares
+                    .iter()
+                    .filter_map(|(dealer, encrypted)| {
+                        let encrypted = encrypted.get(&nid).map_or(&[][..], Vec::as_slice);
+                        open_self_share(
                             nid,
-                            msg.perm.iter().position(|x| x == &message_ele).unwrap(),
-                            &bulk_prf,
-                            &mut socket,
-                            round,
+                            &prf.secret,
+                            seeds,
+                            *dealer,
+                            encrypted,
+                            msg.round,
+                            msg.phase,
+                            msg.attempt,
+                        )
+                        .map(|share| (*dealer, share))
+                    })
+                    .collect();
+                let message =
+                    bincode::serialize(&Message::ClientRecoveryMessage(ClientRecoveryMessage {
+                        round: msg.round,
+                        attempt: msg.attempt,
+                        phase: msg.phase,
+                        nid,
+                        recovery,
+                        shares,
+                    }))?;
+                socket.write(&message)?;
+            }
+            Message::ServerBlameRequestMessage(msg) => {
+                if !blame_log.admit(c, &msg) {
+                    warn!(
+                        "Refusing to open slot {} of round {} for the blame protocol.",
+                        msg.slot, msg.round
+                    );
+                    continue;
+                }
+                info!(
+                    "Opening slot {} of round {} for the blame protocol.",
+                    msg.slot, msg.round
+                );
+                let message = bincode::serialize(&Message::ClientBlameMessage(open_slot(
+                    c,
+                    nid,
+                    &bulk_prf.setup,
+                    msg.round,
+                    msg.slot,
+                )))?;
+                socket.write(&message)?;
+            }
+            Message::ServerBlameResultMessage(msg) => {
+                if msg.guilty.contains(&nid) {
+                    error!("Excluded by the blame protocol on round {}.", msg.round);
+                } else {
+                    info!(
+                        "Blame on slot {:?} of round {} finds {:?} guilty.",
+                        msg.slot, msg.round, msg.guilty
+                    );
+                }
+            }
+            _ => return Ok(message),
+        }
+    }
+}
+
+/// Exchanges `Hello` and `Welcome` with the relay, presenting the token of
+/// `session` to resume it. Returns the token of the session. Fails if the
+/// relay runs another protocol version or config.
+pub fn greet(c: &Config, socket: &mut SecureStream, session: Option<u64>) -> Result<u64, Error> {
+    let ours = Capabilities::from_config(c);
+    let hello = Hello {
+        capabilities: ours.clone(),
+        session,
+    };
+    socket.write(&bincode::serialize(&hello)?)?;
+    let welcome: Welcome = bincode::deserialize(&socket.read()?)?;
+    ours.check(&welcome.capabilities)?;
+    Ok(welcome.session)
+}
+
+/// Connects to the relay and greets it, resuming `session` if present.
+/// Returns the connection and the token of the session.
+fn connect(
+    c: &Config,
+    nid: usize,
+    identity: &Identity,
+    session: Option<u64>,
+) -> Result<(SecureStream, u64), Error> {
+    let mut socket = SecureStream::connect(
+        c.server_addr,
+        Peer::Client(nid),
+        identity,
+        Limits::from_config(c),
+    )?;
+    let session = greet(c, &mut socket, session)?;
+    Ok((socket, session))
+}
+
+/// Connects to the relay again after the connection dropped and resumes
+/// `session`. Waits twice as long after every failed attempt and gives up
+/// after `MAX_RECONNECTS` attempts.
+fn reconnect(
+    c: &Config,
+    nid: usize,
+    identity: &Identity,
+    session: u64,
+) -> Result<(SecureStream, u64), Error> {
+    let mut delay = RECONNECT_DELAY;
+    let mut attempt: usize = 1;
+    loop {
+        std::thread::sleep(delay);
+        info!(
+            "Reconnecting to {:?}, attempt {}...",
+            c.server_addr, attempt
+        );
+        match connect(c, nid, identity, Some(session)) {
+            Ok(connection) => return Ok(connection),
+            Err(Error::Net(e)) if attempt < MAX_RECONNECTS => {
+                warn!("Failed to reconnect: {}.", e);
+            }
+            Err(e) => return Err(e),
+        }
+        attempt += 1;
+        delay = std::cmp::min(delay * 2, MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Sends the messages of the rounds in flight again, in case the relay
+/// missed them while the client was away. The relay drops the ones it
+/// already has.
+fn resend(
+    c: &Config,
+    nid: usize,
+    base_prf: &Prf,
+    bulk_prf: &Prf,
+    socket: &mut SecureStream,
+    in_flight: &BTreeMap<usize, InFlight>,
+) -> Result<(), Error> {
+    for (&round, state) in in_flight {
+        match state {
+            InFlight::Base { attempt, ids, .. } => {
+                info!("Resending ClientBaseMessage of round {}.", round);
+                send_client_base_message(c, nid, base_prf, ids, socket, round, *attempt)?;
+            }
+            InFlight::Bulk { groups, sent, .. } => {
+                info!("Resending ClientBulkMessage of round {}.", round);
+                send_client_bulk_message(c, nid, *groups, bulk_prf, sent.clone(), socket, round)?;
+            }
+            InFlight::Skipped => {}
+        }
+    }
+    Ok(())
+}
+
+/// Runs the rounds from `next_round` on, after resending the messages of the
+/// rounds already `in_flight`. Returns once every round is over, or on the
+/// first error, leaving `next_round`, `in_flight`, `blame_log` and
+/// `recovered` ready to go on with.
+#[allow(clippy::too_many_arguments)]
+fn run_rounds(
+    c: &Config,
+    nid: usize,
+    base_prf: &Prf,
+    bulk_prf: &Prf,
+    blame_log: &mut BlameLog,
+    recovered: &mut HashSet<(usize, Phase, usize)>,
+    rng: &mut Rng,
+    socket: &mut SecureStream,
+    next_round: &mut usize,
+    in_flight: &mut BTreeMap<usize, InFlight>,
+    payload: &mut impl FnMut(usize) -> Vec<Vec<u8>>,
+) -> Result<(), Error> {
+    resend(c, nid, base_prf, bulk_prf, socket, in_flight)?;
+    loop {
+        // Start new rounds until `pipeline_depth` rounds are in flight.
+        while in_flight.len() < c.pipeline_depth && *next_round <= c.round {
+            info!("Round {}.", next_round);
+            let mut payloads = payload(*next_round);
+            if payloads.len() > c.max_reservation {
+                warn!(
+                    "Dropping {} payloads beyond max_reservation on round {}.",
+                    payloads.len() - c.max_reservation,
+                    next_round
+                );
+                payloads.truncate(c.max_reservation);
+            }
+            let (secrets, ids) = draw_base_message(
+                c,
+                nid,
+                base_prf,
+                rng,
+                socket,
+                *next_round,
+                0,
+                payloads.len(),
+            )?;
+            in_flight.insert(
+                *next_round,
+                InFlight::Base {
+                    attempt: 0,
+                    secrets,
+                    ids,
+                    payloads,
+                    opened: false,
+                },
+            );
+            *next_round += 1;
+        }
+        if in_flight.is_empty() {
+            // Sleep a little bit after everything finishes to ensure that the message is sent.
+            std::thread::sleep(std::time::Duration::from_secs(5));
+            return Ok(());
+        }
+
+        let message = read_message(
+            c, nid, base_prf, bulk_prf, blame_log, in_flight, recovered, socket,
+        )?;
+        match message {
+            // Draw a new number if the relay finds a collision.
+            Message::ServerBaseRetryMessage(msg) => {
+                if let Some(InFlight::Base {
+                    attempt,
+                    secrets,
+                    ids,
+                    opened,
+                    ..
+                }) = in_flight.get_mut(&msg.round)
+                {
+                    if msg.attempt <= *attempt {
+                        continue;
+                    }
+                    warn!(
+                        "Received ServerBaseRetryMessage on round {}, attempt {}.",
+                        msg.round, msg.attempt
+                    );
+                    // An opened attempt revealed the number of IDs, so the
+                    // retry reserves the most slot groups, some of them empty.
+                    let count = if *opened {
+                        c.max_reservation
+                    } else {
+                        ids.len()
+                    };
+                    (*secrets, *ids) = draw_base_message(
+                        c,
+                        nid,
+                        base_prf,
+                        rng,
+                        socket,
+                        msg.round,
+                        msg.attempt,
+                        count,
+                    )?;
+                    *attempt = msg.attempt;
+                    *opened = false;
+                }
+            }
+            // Open the current attempt of the base round once, since its IDs
+            // are discarded anyway, but only if its sums do not solve. The
+            // opening reveals the number of IDs.
+            Message::ServerBaseBlameRequestMessage(msg) => {
+                if let Some(InFlight::Base {
+                    attempt,
+                    ids,
+                    opened,
+                    ..
+                }) = in_flight.get_mut(&msg.round)
+                {
+                    if msg.attempt != *attempt || *opened {
+                        continue;
+                    }
+                    if msg.sums.len() != reservation_capacity(c) + 1
+                        || solve_sums(c, &msg.sums).is_ok()
+                    {
+                        error!(
+                            "Refusing to open attempt {} of the base round {}, which solves.",
+                            msg.attempt, msg.round
                         );
+                        continue;
                     }
+                    warn!(
+                        "Opening attempt {} of the base round {} for the blame protocol.",
+                        msg.attempt, msg.round
+                    );
+                    let message = bincode::serialize(&Message::ClientBaseBlameMessage(open_base(
+                        c,
+                        nid,
+                        &base_prf.setup,
+                        msg.round,
+                        msg.attempt,
+                        ids,
+                    )))?;
+                    socket.write(&message)?;
+                    *opened = true;
                 }
-                _ => {
-                    error!("Unknown message {:?}.", message);
+            }
+            Message::ServerBaseMessage(msg) => {
+                info!("Received ServerBaseMessage on round {}.", msg.round);
+                let round = msg.round;
+                if round >= *next_round {
+                    // The relay went on without us, so catch up with it.
+                    warn!("Catching up with round {}.", round);
+                    *next_round = round + 1;
+                }
+                // The relay runs the base rounds in order, so the earlier ones are over.
+                in_flight.retain(|r, state| {
+                    let over = *r < round && matches!(state, InFlight::Base { .. });
+                    if over {
+                        warn!("Missed the base round {}. Skipping the bulk round.", r);
+                    }
+                    !over
+                });
+                if c.do_blame {
+                    blame_log.record(c, round, msg.perm.clone());
+                }
+                // The round stays in the base round until the bulk message is out, so
+                // that the relay can send the base round message again.
+                if let Some(InFlight::Base {
+                    secrets,
+                    ids,
+                    payloads,
+                    opened,
+                    ..
+                }) = in_flight.get(&round)
+                {
+                    let posids: Option<Vec<usize>> = ids
+                        .iter()
+                        .map(|id| msg.perm.iter().position(|x| x == id))
+                        .collect();
+                    let state = if *opened {
+                        warn!("Opened the base round {}. Skipping the bulk round.", round);
+                        InFlight::Skipped
+                    } else if msg.perm.is_empty() && !ids.is_empty() {
+                        warn!(
+                            "The relay gave up the base round {}. Skipping the bulk round.",
+                            round
+                        );
+                        InFlight::Skipped
+                    } else if let Some(posids) = posids {
+                        let sent = send_client_bulk_message(
+                            c,
+                            nid,
+                            msg.perm.len(),
+                            bulk_prf,
+                            posids.into_iter().zip(payloads.clone()).collect(),
+                            socket,
+                            round,
+                        )?;
+                        InFlight::Bulk {
+                            groups: msg.perm.len(),
+                            sent,
+                            secrets: secrets.clone(),
+                        }
+                    } else {
+                        warn!("Missed the base round {}. Skipping the bulk round.", round);
+                        InFlight::Skipped
+                    };
+                    in_flight.insert(round, state);
                 }
             }
-
-            let buf = read_stream(&mut socket).unwrap();
-            let message: Message = bincode::deserialize(&buf).unwrap();
-            match message {
-                Message::ServerBulkMessage => {
-                    info!("Received ServerBulkMessage.");
+            Message::ServerBulkMessage(msg) => {
+                info!("Received ServerBulkMessage on round {}.", msg.round);
+                if msg.round >= *next_round {
+                    warn!("Catching up with round {}.", msg.round);
+                    *next_round = msg.round + 1;
                 }
-                _ => {
-                    error!("Unknown message {:?}.", message);
+                // Check that our payloads went out intact and accuse the slots otherwise.
+                // Nothing went out if the relay gave up the round.
+                let (sent, secrets) = match in_flight.get(&msg.round) {
+                    Some(InFlight::Bulk { sent, secrets, .. }) if !msg.digests.is_empty() => {
+                        (sent.as_slice(), secrets.as_slice())
+                    }
+                    Some(InFlight::Bulk { sent, .. }) if !sent.is_empty() => {
+                        warn!("The relay gave up the bulk round {}.", msg.round);
+                        (&[][..], &[][..])
+                    }
+                    _ => (&[][..], &[][..]),
+                };
+                for ((posid, payload), secret) in sent.iter().zip(secrets) {
+                    let posid = *posid;
+                    let digest = payload_digest(payload);
+                    if msg.digests.get(posid) != Some(&Some(digest.clone())) {
+                        warn!("Slot {} on round {} is corrupted.", posid, msg.round);
+                        if c.do_blame {
+                            // Reveal the secret behind the slot group to prove
+                            // ownership, without naming ourselves.
+                            let message = bincode::serialize(&Message::ClientAccusationMessage(
+                                ClientAccusationMessage {
+                                    round: msg.round,
+                                    slot: posid,
+                                    secret: secret.clone(),
+                                    digest,
+                                },
+                            ))?;
+                            socket.write(&message)?;
+                        }
+                    }
                 }
+                // The relay runs the bulk rounds in order, so this one and the earlier ones are over.
+                in_flight.retain(|r, _| *r > msg.round);
+                recovered.retain(|(r, _, _)| *r > msg.round);
+            }
+            _ => {
+                error!("Unknown message {:?}.", message);
             }
-        } else {
-            // Sleep a little bit after everything finishes to ensure that the message is sent.
-            std::thread::sleep(std::time::Duration::from_secs(5));
-            return;
         }
     }
 }
 
-/// Code to time Prifi.
-pub fn main_prifi(c: Config, nid: usize) {
+/// Overarching function. `base_prf` and `bulk_prf` hold the setup of the
+/// base and bulk rounds. `identity` authenticates the client to the relay.
+/// `payload` is called once per round with the round number and returns the
+/// payloads to send in the bulk round. The client reserves one slot group per
+/// payload, up to `max_reservation`, so an empty list reserves none. If the
+/// connection drops, the client reconnects and resumes its session. Fails if
+/// the relay cannot be reached, runs another config or breaks the protocol.
+pub fn main(
+    c: Config,
+    nid: usize,
+    base_prf: ClientSetup,
+    bulk_prf: ClientSetup,
+    identity: Identity,
+    mut payload: impl FnMut(usize) -> Vec<Vec<u8>>,
+) -> Result<(), Error> {
     debug!("Connecting to {:?}...", c.server_addr);
-    let mut socket = TcpStream::connect(c.server_addr).unwrap();
+    let (mut socket, mut session) = connect(&c, nid, &identity, None)?;
+    let mut rng = Rng::from_config(&c, nid);
+    let mut next_round: usize = 1;
+    let mut in_flight = BTreeMap::<usize, InFlight>::new();
+    let mut blame_log = BlameLog::default();
+    let mut recovered = HashSet::new();
+    // Precompute the masks of every round unless they are computed on demand.
+    let base_prf = Prf {
+        masks: Masks::new(&c.base_params, &base_prf.share, c.round, !c.do_unzip),
+        setup: base_prf,
+        secret: rng.bytes(SEED_LEN),
+    };
+    let bulk_prf = Prf {
+        masks: Masks::new(&c.bulk_params, &bulk_prf.share, c.round, !c.do_unzip),
+        setup: bulk_prf,
+        secret: rng.bytes(SEED_LEN),
+    };
+    loop {
+        match run_rounds(
+            &c,
+            nid,
+            &base_prf,
+            &bulk_prf,
+            &mut blame_log,
+            &mut recovered,
+            &mut rng,
+            &mut socket,
+            &mut next_round,
+            &mut in_flight,
+            &mut payload,
+        ) {
+            Ok(()) => return Ok(()),
+            Err(Error::Net(e)) => {
+                warn!("Lost the connection to the relay: {}.", e);
+                (socket, session) = reconnect(&c, nid, &identity, session)?;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Code to time Prifi. Fails if the relay cannot be reached or sends a
+/// message that does not decode.
+pub fn main_prifi(c: Config, nid: usize) -> Result<(), Error> {
+    debug!("Connecting to {:?}...", c.server_addr);
+    let mut socket = TcpStream::connect(c.server_addr)?;
+    let limits = Limits::from_config(&c);
     let mut round: usize = 0;
+    let mut rng = Rng::from_config(&c, nid);
     loop {
         if round < c.round {
             round += 1;
             info!("Round {}.", round);
             let nbits: usize = c.slot_per_round * 8;
             let nguards: usize = 10;
-            let mut rand = rug::rand::RandState::new();
-            let prgs: Vec<Integer> = std::iter::repeat_with(|| {
-                Integer::from(Integer::random_bits(nbits as u32, &mut rand))
-            })
-            .take(nguards)
-            .collect();
-            let message = Integer::from(Integer::random_bits(nbits as u32, &mut rand));
+            let prgs: Vec<Integer> = std::iter::repeat_with(|| rng.bits(nbits as u32))
+                .take(nguards)
+                .collect();
+            let message = rng.bits(nbits as u32);
             let slot_messages: Vec<Integer> = std::iter::repeat_with(|| message.clone())
                 .take(c.client_size)
                 .collect();
@@ -196,10 +916,10 @@ pub fn main_prifi(c: Config, nid: usize) {
                 std::thread::sleep(std::time::Duration::from_secs(2))
             }
             info!("Sending ClientPrifiMessage, size = {}...", message.len());
-            write_stream(&mut socket, &message).unwrap();
+            write_stream(&mut socket, &message, &limits)?;
             info!("Sent ClientPrifiMessage.");
-            let buf = read_stream(&mut socket).unwrap();
-            let message: Message = bincode::deserialize(&buf).unwrap();
+            let buf = read_stream(&mut socket, &limits)?;
+            let message: Message = bincode::deserialize(&buf)?;
             match message {
                 Message::Ok => {
                     info!("Received Server Ok Message.");
@@ -210,7 +930,7 @@ pub fn main_prifi(c: Config, nid: usize) {
             }
         } else {
             std::thread::sleep(std::time::Duration::from_secs(5));
-            return;
+            return Ok(());
         }
     }
 }
@@ -1,5 +1,9 @@
-use openssl::{ec::EcGroup, nid::Nid};
-use rug::integer::ParseIntegerError;
+use openssl::{
+    ec::{EcGroup, EcPoint},
+    error::ErrorStack,
+    nid::Nid,
+};
+use rug::integer::{IsPrime, ParseIntegerError};
 use rug::ops::Pow;
 use rug::Integer;
 use serde::{Deserialize, Serialize};
@@ -18,11 +22,84 @@ pub struct ProtocolParams {
     pub vector_len: usize,
     /// Total number of bits.
     pub bits: usize,
+    /// Factor by which the messages are scaled, so that rounding removes the
+    /// error of the almost key-homomorphic PRF. Must exceed twice the error
+    /// bound, see `Config::error_bound`.
+    #[serde(default = "default_error_scale")]
+    pub error_scale: u64,
     /// ECC group id, specified by OpenSSL.
     pub group_nid: i32,
     /// ECC group.
     #[serde(skip)]
     pub group: Option<EcGroup>,
+    /// Generator `h` of the ECC group, derived from `group_nid`.
+    #[serde(skip)]
+    pub h: Option<EcPoint>,
+}
+
+impl ProtocolParams {
+    /// Loads the ECC group specified by `group_nid` and derives `h`.
+    pub fn init_group(&mut self) -> Result<(), ErrorStack> {
+        self.group = Some(EcGroup::from_curve_name(Nid::from_raw(self.group_nid))?);
+        self.h = Some(crate::ecc::derive_h(self));
+        Ok(())
+    }
+
+    /// Checks the parameters of the round called `name` that do not depend on
+    /// the rest of the config. Adds the problems found to `diagnostics`.
+    fn validate(&self, name: &str, diagnostics: &mut Vec<Diagnostic>) {
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                diagnostics.push(Diagnostic {
+                    field: format!("{}.{}", name, field),
+                    message,
+                });
+            }
+        };
+        let v = &self.ring_v;
+        check(
+            v.order.is_probably_prime(30) != IsPrime::No,
+            "ring_v.order",
+            format!("{} is not prime", v.order),
+        );
+        // NTT of radix 2 needs a root of order `scale`, a power of two, so
+        // `root^(scale / 2)` must be -1.
+        let is_power_of_two = v.scale > 0 && v.scale.is_power_of_two();
+        check(
+            is_power_of_two,
+            "ring_v.scale",
+            format!("{} is not a power of two", v.scale),
+        );
+        if is_power_of_two && v.order > 1 {
+            let half = v.scale.clone() >> 1;
+            let order_ok = v.root.clone().pow_mod(&v.scale, &v.order).ok()
+                == Some(Integer::from(1))
+                && v.root.clone().pow_mod(&half, &v.order).ok()
+                    == Some(Integer::from(&v.order - 1));
+            check(
+                order_ok,
+                "ring_v.root",
+                format!(
+                    "{} does not have order {} modulo {}",
+                    v.root, v.scale, v.order
+                ),
+            );
+        }
+        check(
+            self.vector_len > 0 && v.scale.is_divisible(&Integer::from(self.vector_len)),
+            "vector_len",
+            format!(
+                "{} does not divide ring_v.scale {}",
+                self.vector_len, v.scale
+            ),
+        );
+        check(
+            self.p > 1 && self.p.is_probably_prime(30) != IsPrime::No,
+            "p",
+            format!("{} is not prime", self.p),
+        );
+        check(self.bits > 0, "bits", "must be positive".to_string());
+    }
 }
 
 /// Config for the protocol.
@@ -42,6 +119,12 @@ pub struct Config {
     pub round: usize,
     /// How many slots a client needs in the bulk round.
     pub slot_per_round: usize,
+    /// Largest number of slot groups of `slot_per_round` slots a client may
+    /// reserve in one round. The base round holds `client_size *
+    /// max_reservation` reservations, which must be below the `vector_len` of
+    /// the base round, and as many slot groups must fit into the bulk round.
+    #[serde(default = "default_max_reservation")]
+    pub max_reservation: usize,
     /// Whether or not to test the blame protocol.
     #[serde(default)]
     pub do_blame: bool,
@@ -54,6 +137,243 @@ pub struct Config {
     /// Whether ot not to simulate a ping to WWW.
     #[serde(default)]
     pub do_ping: bool,
+    /// How the relay reaches the outside world when `do_ping` is set.
+    #[serde(default)]
+    pub egress: Egress,
+    /// Where the relay publishes the payloads of the bulk round.
+    #[serde(default)]
+    pub output: Output,
+    /// Deadline of each phase of a round in milliseconds, after which the
+    /// relay finishes the phase with the clients that responded.
+    #[serde(default)]
+    pub round_timeout: Option<u64>,
+    /// How many rounds are in flight at once. With more than one, the base
+    /// round of the next rounds overlaps with the bulk round of this one.
+    #[serde(default = "default_pipeline_depth")]
+    pub pipeline_depth: usize,
+    /// Largest frame accepted from a node in bytes. Defaults to a bound
+    /// derived from `client_size` and `vector_len`.
+    #[serde(default)]
+    pub max_frame_size: Option<usize>,
+    /// Deadline in milliseconds to receive the rest of a frame once its
+    /// header has arrived, and to send a frame.
+    #[serde(default)]
+    pub io_timeout: Option<u64>,
+    /// Fixed seed for all randomness. For testing only.
+    #[serde(default)]
+    pub test_seed: Option<u64>,
+}
+
+impl Config {
+    /// Returns the config with the fields a config file must set, and the
+    /// defaults of a config file for the others.
+    pub fn new(
+        server_addr: SocketAddr,
+        client_size: usize,
+        round: usize,
+        slot_per_round: usize,
+    ) -> Config {
+        Config {
+            server_addr,
+            client_size,
+            base_params: default_base_params(),
+            bulk_params: default_bulk_params(),
+            round,
+            slot_per_round,
+            max_reservation: default_max_reservation(),
+            do_blame: false,
+            do_unzip: false,
+            do_delay: false,
+            do_ping: false,
+            egress: Egress::default(),
+            output: Output::default(),
+            round_timeout: None,
+            pipeline_depth: default_pipeline_depth(),
+            max_frame_size: None,
+            io_timeout: None,
+            test_seed: None,
+        }
+    }
+
+    /// Returns whether the clients add a self mask to their messages. Only
+    /// with `round_timeout` or `do_blame` may the relay recover the masks of
+    /// missing clients, and the self mask keeps a client hidden even if the
+    /// relay claims it is missing while it is not.
+    pub fn self_masks(&self) -> bool {
+        self.round_timeout.is_some() || self.do_blame
+    }
+
+    /// Returns the largest error of the PRF in an element of the sum of all
+    /// messages. Each of the clients and the relay rounds its mask down on its
+    /// own, which is off by less than one from rounding the sum of the masks.
+    /// Every element is rounded on its own, so the bound does not grow with
+    /// `vector_len`.
+    pub fn error_bound(&self) -> u64 {
+        self.client_size as u64 + 1
+    }
+
+    /// Checks that the protocol can run with the config. Returns every problem
+    /// found, not only the first one.
+    pub fn validate(&self) -> Result<(), Vec<Diagnostic>> {
+        let mut diagnostics = Vec::new();
+        self.base_params.validate("base_params", &mut diagnostics);
+        self.bulk_params.validate("bulk_params", &mut diagnostics);
+        let mut check = |ok: bool, field: &str, message: String| {
+            if !ok {
+                diagnostics.push(Diagnostic {
+                    field: field.to_string(),
+                    message,
+                });
+            }
+        };
+        for (field, value) in [
+            ("client_size", self.client_size),
+            ("round", self.round),
+            ("slot_per_round", self.slot_per_round),
+            ("max_reservation", self.max_reservation),
+            ("pipeline_depth", self.pipeline_depth),
+        ] {
+            check(value > 0, field, "must be positive".to_string());
+        }
+        // The base round holds the power sums of all reservations and their
+        // number.
+        let capacity = self.client_size * self.max_reservation;
+        check(
+            self.base_params.vector_len > capacity,
+            "base_params.vector_len",
+            format!(
+                "{} cannot hold {} reservations (client_size {} * max_reservation {}) and their number",
+                self.base_params.vector_len, capacity, self.client_size, self.max_reservation
+            ),
+        );
+        check(
+            self.bulk_params.vector_len >= self.slot_per_round * capacity,
+            "bulk_params.vector_len",
+            format!(
+                "{} cannot hold {} slot groups of {} slots",
+                self.bulk_params.vector_len, capacity, self.slot_per_round
+            ),
+        );
+        // Rounding only removes the error of the PRF if it is below half of
+        // the scale.
+        let bound = self.error_bound();
+        for (name, params) in [
+            ("base_params", &self.base_params),
+            ("bulk_params", &self.bulk_params),
+        ] {
+            check(
+                params.error_scale > 2 * bound,
+                &format!("{}.error_scale", name),
+                format!(
+                    "{} does not exceed twice the error bound {} of {} clients",
+                    params.error_scale, bound, self.client_size
+                ),
+            );
+        }
+        // The scaled sum of all messages and the error must stay below `q`.
+        // In the base round, every client adds values below `p`; in the bulk
+        // round, one client writes a slot with a value of `bits` bits.
+        let base_max = Integer::from(self.base_params.error_scale)
+            * Integer::from(self.client_size)
+            * &self.base_params.p
+            + bound;
+        check(
+            self.base_params.q > base_max,
+            "base_params.q",
+            format!(
+                "{} is too small: the scaled sums of {} clients below p reach {}",
+                self.base_params.q, self.client_size, base_max
+            ),
+        );
+        let bulk_max =
+            (Integer::from(self.bulk_params.error_scale) << self.bulk_params.bits as u32) + bound;
+        check(
+            self.bulk_params.q > bulk_max,
+            "bulk_params.q",
+            format!(
+                "{} is too small: scaled slots of {} bits reach {}",
+                self.bulk_params.q, self.bulk_params.bits, bulk_max
+            ),
+        );
+        if let Egress::Latency(Latency::Uniform { min, max }) = self.egress {
+            check(
+                min <= max,
+                "egress",
+                format!("uniform latency has min {} above max {}", min, max),
+            );
+        }
+        if diagnostics.is_empty() {
+            Ok(())
+        } else {
+            Err(diagnostics)
+        }
+    }
+}
+
+/// Problem found by `Config::validate`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Path of the field, e.g. `base_params.vector_len`.
+    pub field: String,
+    /// What is wrong with it.
+    pub message: String,
+}
+
+impl std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Destination of the payloads the relay decodes.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Output {
+    /// Only log the payloads.
+    #[default]
+    Log,
+    /// Print the payloads to the standard output.
+    Stdout,
+    /// Append the payloads to the file at the path.
+    File(String),
+    /// Forward the payloads over TCP to the address.
+    Tcp(SocketAddr),
+}
+
+/// Round trip of the relay to the outside world.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Egress {
+    /// Wait for a simulated latency.
+    Latency(Latency),
+    /// Send the output to the echo server at the address and read it back.
+    Echo(SocketAddr),
+}
+
+impl Default for Egress {
+    fn default() -> Self {
+        Egress::Latency(Latency::Fixed(20))
+    }
+}
+
+/// Distribution of a simulated latency in milliseconds.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Latency {
+    /// Always the same latency.
+    Fixed(u64),
+    /// Uniform between `min` and `max`, both included.
+    Uniform {
+        /// Smallest latency.
+        min: u64,
+        /// Largest latency.
+        max: u64,
+    },
+    /// Exponential with the given mean.
+    Exponential {
+        /// Mean latency.
+        mean: u64,
+    },
 }
 
 /// Config-related error.
@@ -67,8 +387,29 @@ pub enum ConfigError {
     IOError(std::io::Error),
     /// JSON-related error.
     JsonError(serde_json::Error),
+    /// The ECC group cannot be loaded.
+    CryptoError(ErrorStack),
+    /// The config is inconsistent, see `Config::validate`.
+    Invalid(Vec<Diagnostic>),
 }
 
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::AddrParseError(e) => write!(f, "{}", e),
+            ConfigError::ParseIntegerError(e) => write!(f, "{}", e),
+            ConfigError::IOError(e) => write!(f, "{}", e),
+            ConfigError::JsonError(e) => write!(f, "{}", e),
+            ConfigError::CryptoError(e) => write!(f, "{}", e),
+            ConfigError::Invalid(diagnostics) => {
+                let diagnostics: Vec<String> = diagnostics.iter().map(|d| d.to_string()).collect();
+                write!(f, "{}", diagnostics.join("; "))
+            }
+        }
+    }
+}
+impl std::error::Error for ConfigError {}
+
 impl From<AddrParseError> for ConfigError {
     fn from(e: AddrParseError) -> Self {
         ConfigError::AddrParseError(e)
@@ -87,6 +428,12 @@ impl From<std::io::Error> for ConfigError {
     }
 }
 
+impl From<ErrorStack> for ConfigError {
+    fn from(e: ErrorStack) -> Self {
+        ConfigError::CryptoError(e)
+    }
+}
+
 impl From<serde_json::Error> for ConfigError {
     fn from(e: serde_json::Error) -> Self {
         ConfigError::JsonError(e)
@@ -114,9 +461,24 @@ impl NttField {
     }
 }
 
+/// Returns the default error scale, enough for up to 498 clients.
+pub fn default_error_scale() -> u64 {
+    1000
+}
+
+/// Returns the default reservation limit, one slot group per client.
+pub fn default_max_reservation() -> usize {
+    1
+}
+
+/// Returns the default pipeline depth, which runs the rounds one by one.
+pub fn default_pipeline_depth() -> usize {
+    1
+}
+
 /// Returns default base parameters.
 pub fn default_base_params() -> ProtocolParams {
-    ProtocolParams {
+    let mut params = ProtocolParams {
         p: Integer::from(2).pow(64) - 59,
         q: Integer::from(2).pow(84) - 35,
         ring_v: NttField {
@@ -126,14 +488,18 @@ pub fn default_base_params() -> ProtocolParams {
         },
         vector_len: 2048,
         bits: 64,
+        error_scale: default_error_scale(),
         group_nid: Nid::SECP256K1.as_raw(),
-        group: Some(EcGroup::from_curve_name(Nid::SECP256K1).unwrap()),
-    }
+        group: None,
+        h: None,
+    };
+    params.init_group().unwrap();
+    params
 }
 
 /// Returns default bulk parameters.
 pub fn default_bulk_params() -> ProtocolParams {
-    ProtocolParams {
+    let mut params = ProtocolParams {
         p: Integer::from(2).pow(226) - 5,
         // order of secp256k1
         q: Integer::from_str_radix(
@@ -148,23 +514,87 @@ pub fn default_bulk_params() -> ProtocolParams {
         },
         vector_len: 8192,
         bits: 226,
+        error_scale: default_error_scale(),
         group_nid: Nid::SECT571K1.as_raw(),
-        group: Some(EcGroup::from_curve_name(Nid::SECT571K1).unwrap()),
-    }
+        group: None,
+        h: None,
+    };
+    params.init_group().unwrap();
+    params
 }
 
-/// Loads config from a file.
+/// Loads config from a file. Fails if the config does not validate.
 pub fn load_config(filename: &str) -> Result<Config, ConfigError> {
     let mut c: Config = serde_json::from_str(&std::fs::read_to_string(filename)?)?;
-    c.base_params.group =
-        Some(EcGroup::from_curve_name(Nid::from_raw(c.base_params.group_nid)).unwrap());
-    c.bulk_params.group =
-        Some(EcGroup::from_curve_name(Nid::from_raw(c.bulk_params.group_nid)).unwrap());
+    c.validate().map_err(ConfigError::Invalid)?;
+    c.base_params.init_group()?;
+    c.bulk_params.init_group()?;
     Ok(c)
 }
 
 /// Dumps the current config into a file.
 pub fn dump_config(filename: &str, c: &Config) -> Result<(), ConfigError> {
-    std::fs::write(&filename, serde_json::to_string_pretty(&c).unwrap())?;
+    std::fs::write(filename, serde_json::to_string_pretty(&c)?)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Config, Egress, Latency};
+    use rug::Integer;
+
+    #[test]
+    fn validate_test() {
+        let json = r#"{"server_addr": "127.0.0.1:8001", "client_size": 4, "round": 2, "slot_per_round": 2}"#;
+        let mut c: Config = serde_json::from_str(json).unwrap();
+        assert_eq!(c.validate(), Ok(()));
+        // The defaults of `Config::new` are those of a config file.
+        let defaults = Config::new("127.0.0.1:8001".parse().unwrap(), 4, 2, 2);
+        assert_eq!(
+            serde_json::to_value(&defaults).unwrap(),
+            serde_json::to_value(&c).unwrap()
+        );
+
+        c.base_params.vector_len = 4;
+        c.bulk_params.ring_v.root += 1;
+        c.bulk_params.q = Integer::from(1000) << c.bulk_params.bits as u32;
+        c.pipeline_depth = 0;
+        c.egress = Egress::Latency(Latency::Uniform { min: 2, max: 1 });
+        let fields: Vec<String> = c
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "bulk_params.ring_v.root",
+                "pipeline_depth",
+                "base_params.vector_len",
+                "bulk_params.q",
+                "egress"
+            ]
+        );
+
+        let mut c: Config = serde_json::from_str(json).unwrap();
+        c.base_params.vector_len = 4096;
+        c.client_size = 5000;
+        let fields: Vec<String> = c
+            .validate()
+            .unwrap_err()
+            .into_iter()
+            .map(|d| d.field)
+            .collect();
+        assert_eq!(
+            fields,
+            vec![
+                "base_params.vector_len",
+                "bulk_params.vector_len",
+                "base_params.error_scale",
+                "bulk_params.error_scale",
+                "base_params.q"
+            ]
+        );
+    }
+}
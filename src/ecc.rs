@@ -4,9 +4,13 @@ use openssl::{
     ec::{EcPoint, EcPointRef, PointConversionForm},
 };
 use rug::{integer::Order, Integer};
+use sha2::{Digest, Sha512};
 
 // Helper functions for ECC operations.
 
+/// Domain separation string for the derivation of `h`.
+const H_DOMAIN: &[u8] = b"OrgAn Pedersen generator h";
+
 /// Generates a `BigNumContext`.
 pub fn new_big_num_context() -> BigNumContext {
     BigNumContext::new().unwrap()
@@ -23,15 +27,51 @@ pub fn get_g(params: &crate::config::ProtocolParams) -> EcPoint {
         .unwrap()
 }
 
-/// Returns the generator `h`.
+/// Returns the generator `h`, using the value cached in `params` if present.
 pub fn get_h(params: &crate::config::ProtocolParams) -> EcPoint {
-    params
-        .group
-        .as_ref()
-        .unwrap()
-        .generator()
-        .to_owned(params.group.as_ref().unwrap())
-        .unwrap()
+    match params.h.as_ref() {
+        Some(h) => (**h).to_owned(params.group.as_ref().unwrap()).unwrap(),
+        None => derive_h(params),
+    }
+}
+
+/// Derives a generator `h` whose discrete log with respect to `g` is unknown.
+///
+/// The x-coordinate is hashed from a domain separation string and `group_nid`
+/// with a counter that is incremented until it lands on the curve. The point
+/// is then multiplied by the cofactor to move it into the prime-order subgroup.
+pub fn derive_h(params: &crate::config::ProtocolParams) -> EcPoint {
+    let group = params.group.as_ref().unwrap();
+    let mut ctx = new_big_num_context();
+    let mut cofactor = BigNum::new().unwrap();
+    group.cofactor(&mut cofactor, &mut ctx).unwrap();
+    let degree = group.degree() as usize;
+    let field_len = degree.div_ceil(8);
+    for counter in 0u32.. {
+        let mut x = Vec::<u8>::with_capacity(field_len + 64);
+        for block in 0u32.. {
+            if x.len() >= field_len {
+                break;
+            }
+            let mut hasher = Sha512::new();
+            hasher.update(H_DOMAIN);
+            hasher.update(params.group_nid.to_le_bytes());
+            hasher.update(counter.to_le_bytes());
+            hasher.update(block.to_le_bytes());
+            x.extend_from_slice(&hasher.finalize());
+        }
+        x.truncate(field_len);
+        x[0] &= 0xff >> (field_len * 8 - degree);
+        let encoded: Vec<u8> = std::iter::once(0x02).chain(x).collect();
+        if let Ok(point) = EcPoint::from_bytes(group, &encoded, &mut ctx) {
+            let mut h = EcPoint::new(group).unwrap();
+            h.mul(group, &point, &cofactor, &ctx).unwrap();
+            if !h.is_infinity(group) {
+                return h;
+            }
+        }
+    }
+    unreachable!()
 }
 
 /// Adds two EC points.
@@ -82,6 +122,17 @@ pub fn from_bytes(params: &crate::config::ProtocolParams, buf: &[u8]) -> EcPoint
     .unwrap()
 }
 
+/// Deserializes an EC point, or returns `None` if `buf` holds no point of the
+/// group.
+pub fn try_from_bytes(params: &crate::config::ProtocolParams, buf: &[u8]) -> Option<EcPoint> {
+    EcPoint::from_bytes(
+        params.group.as_ref().unwrap(),
+        buf,
+        &mut new_big_num_context(),
+    )
+    .ok()
+}
+
 /// Finds the order of the group.
 pub fn get_order(params: &crate::config::ProtocolParams) -> Integer {
     let mut ret = BigNum::new().unwrap();
@@ -106,8 +157,9 @@ pub fn to_scalar(x: &Integer) -> BigNum {
 
 #[cfg(test)]
 mod tests {
-    use crate::config::default_base_params;
+    use crate::config::{default_base_params, default_bulk_params};
     use crate::ecc;
+    use rug::Integer;
     #[test]
     fn convert_test() {
         let c = default_base_params();
@@ -118,4 +170,28 @@ mod tests {
             .eq(&c.group.unwrap(), &conv, &mut ecc::new_big_num_context())
             .unwrap());
     }
+
+    #[test]
+    fn h_test() {
+        for c in [default_base_params(), default_bulk_params()] {
+            let group = c.group.as_ref().unwrap();
+            let mut ctx = ecc::new_big_num_context();
+            let g = ecc::get_g(&c);
+            let h = ecc::get_h(&c);
+            // `h` is a nothing-up-my-sleeve point: it is re-derived from the
+            // public hash alone and does not depend on `g`.
+            assert!(h.eq(group, &ecc::derive_h(&c), &mut ctx).unwrap());
+            // `h` lies in the subgroup generated by `g`.
+            assert!(h.is_on_curve(group, &mut ctx).unwrap());
+            assert!(ecc::mul(&c, &h, &ecc::get_order(&c)).is_infinity(group));
+            // No small multiple of `g` hits `h`, so the obvious discrete logs
+            // (including the old `h == g`) are ruled out.
+            for k in 1..1000 {
+                let gk = ecc::mul(&c, &g, &Integer::from(k));
+                assert!(!h.eq(group, &gk, &mut ctx).unwrap());
+                let gk = ecc::mul(&c, &g, &-Integer::from(k));
+                assert!(!h.eq(group, &gk, &mut ctx).unwrap());
+            }
+        }
+    }
 }
@@ -0,0 +1,185 @@
+use crate::config::{Egress, Latency};
+use crate::rng::Rng;
+use async_std::io::{ReadExt, WriteExt};
+use async_std::net::{TcpListener, TcpStream};
+use futures::future::LocalBoxFuture;
+use futures::StreamExt;
+use rug::Integer;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+// Egress of the relay. With `do_ping`, the relay sends the output of each
+// round to the outside world and waits for the answer before it goes on, so
+// that the timings include a realistic round trip. The round trip is either
+// simulated or made to a local echo server, so it does not depend on the
+// internet.
+
+/// Round trip of the relay to the outside world.
+pub trait Uplink {
+    /// Sends `data` out and waits for the answer. Returns the time it took.
+    fn round_trip<'a>(
+        &'a mut self,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, std::io::Result<Duration>>;
+}
+
+/// Returns a latency in milliseconds drawn from `latency`.
+pub fn draw_latency(latency: &Latency, rng: &mut Rng) -> u64 {
+    match *latency {
+        Latency::Fixed(ms) => ms,
+        Latency::Uniform { min, max } => {
+            let span = Integer::from(max.saturating_sub(min)) + 1;
+            min + rng.below(&span).to_u64().unwrap()
+        }
+        Latency::Exponential { mean } => {
+            // Inverse transform of a uniform draw in (0, 1].
+            let u = (rng.bits(53).to_f64() + 1.0) / (1u64 << 53) as f64;
+            (-(mean as f64) * u.ln()).round() as u64
+        }
+    }
+}
+
+/// Uplink that waits for a latency drawn from a distribution.
+pub struct SimulatedUplink {
+    /// Distribution of the latency.
+    latency: Latency,
+    /// Source of the draws.
+    rng: Rng,
+}
+
+impl SimulatedUplink {
+    /// Returns an uplink with latencies drawn from `latency` with `rng`.
+    pub fn new(latency: Latency, rng: Rng) -> Self {
+        SimulatedUplink { latency, rng }
+    }
+}
+
+impl Uplink for SimulatedUplink {
+    fn round_trip<'a>(
+        &'a mut self,
+        _data: &'a [u8],
+    ) -> LocalBoxFuture<'a, std::io::Result<Duration>> {
+        let latency = Duration::from_millis(draw_latency(&self.latency, &mut self.rng));
+        Box::pin(async move {
+            async_std::task::sleep(latency).await;
+            Ok(latency)
+        })
+    }
+}
+
+/// Uplink that sends the data to an echo server and reads it back. Connects
+/// again on the next round trip after the connection fails.
+pub struct EchoUplink {
+    /// Address of the echo server.
+    addr: SocketAddr,
+    /// Connection, if there is one.
+    stream: Option<TcpStream>,
+}
+
+impl EchoUplink {
+    /// Returns an uplink to the echo server at `addr`.
+    pub fn new(addr: SocketAddr) -> Self {
+        EchoUplink { addr, stream: None }
+    }
+
+    /// Sends `data` and reads it back.
+    async fn echo(&mut self, data: &[u8]) -> std::io::Result<Duration> {
+        let start = Instant::now();
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => self.stream.insert(TcpStream::connect(self.addr).await?),
+        };
+        stream.write_all(data).await?;
+        stream.flush().await?;
+        let mut buf = vec![0u8; data.len()];
+        stream.read_exact(&mut buf).await?;
+        if buf != data {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "echo does not match",
+            ));
+        }
+        Ok(start.elapsed())
+    }
+}
+
+impl Uplink for EchoUplink {
+    fn round_trip<'a>(
+        &'a mut self,
+        data: &'a [u8],
+    ) -> LocalBoxFuture<'a, std::io::Result<Duration>> {
+        Box::pin(async move {
+            // Send at least one byte so that there is something to wait for.
+            let data = if data.is_empty() { &[0][..] } else { data };
+            let result = self.echo(data).await;
+            if result.is_err() {
+                self.stream = None;
+            }
+            result
+        })
+    }
+}
+
+/// Returns the uplink of `egress`.
+pub fn open(egress: &Egress, rng: Rng) -> Box<dyn Uplink> {
+    match egress {
+        Egress::Latency(latency) => Box::new(SimulatedUplink::new(latency.clone(), rng)),
+        Egress::Echo(addr) => Box::new(EchoUplink::new(*addr)),
+    }
+}
+
+/// Runs an echo server on `listener`, a local stand-in for the outside world.
+pub async fn serve_echo(listener: TcpListener) {
+    listener
+        .incoming()
+        .for_each_concurrent(None, |stream| async move {
+            if let Ok(stream) = stream {
+                let (mut reader, mut writer) = (&stream, &stream);
+                if let Err(e) = async_std::io::copy(&mut reader, &mut writer).await {
+                    debug!("Echo connection closed: {}.", e);
+                }
+            }
+        })
+        .await;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Latency;
+    use crate::egress::{draw_latency, serve_echo, EchoUplink, SimulatedUplink, Uplink};
+    use crate::rng::Rng;
+    use async_std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn latency_test() {
+        let mut rng = Rng::from_seed(0, 0);
+        assert_eq!(draw_latency(&Latency::Fixed(7), &mut rng), 7);
+        let uniform = Latency::Uniform { min: 10, max: 12 };
+        let draws: Vec<u64> = (0..100).map(|_| draw_latency(&uniform, &mut rng)).collect();
+        assert!(draws.iter().all(|x| (10..=12).contains(x)));
+        assert!((10..=12).all(|x| draws.contains(&x)));
+        let exponential = Latency::Exponential { mean: 20 };
+        let mean = (0..2000)
+            .map(|_| draw_latency(&exponential, &mut rng))
+            .sum::<u64>()
+            / 2000;
+        assert!((15..=25).contains(&mean));
+    }
+
+    #[test]
+    fn uplink_test() {
+        async_std::task::block_on(async {
+            let mut uplink = SimulatedUplink::new(Latency::Fixed(5), Rng::from_seed(0, 0));
+            assert_eq!(
+                uplink.round_trip(b"out").await.unwrap(),
+                Duration::from_millis(5)
+            );
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut uplink = EchoUplink::new(listener.local_addr().unwrap());
+            async_std::task::spawn(serve_echo(listener));
+            uplink.round_trip(b"out").await.unwrap();
+            uplink.round_trip(b"").await.unwrap();
+        });
+    }
+}
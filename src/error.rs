@@ -0,0 +1,113 @@
+use crate::config::ConfigError;
+use crate::flint::SolveError;
+use crate::message::MismatchError;
+use crate::net::NetError;
+use crate::server::ProtocolError;
+use openssl::error::ErrorStack;
+
+/// Error of a client or the relay.
+#[derive(Debug)]
+pub enum Error {
+    /// The config is invalid.
+    Config(ConfigError),
+    /// The network failed.
+    Net(NetError),
+    /// A cryptographic operation failed.
+    Crypto(ErrorStack),
+    /// The equation of the base round does not solve.
+    Solve(SolveError),
+    /// A node broke the protocol.
+    Protocol(ProtocolError),
+    /// The node and the relay run different protocols or configs.
+    Mismatch(MismatchError),
+    /// A message failed to decode.
+    Decode(bincode::Error),
+    /// Other IO error, e.g. on a local file or socket.
+    Io(std::io::Error),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Config(e) => write!(f, "Config Error: {}", e),
+            Error::Net(e) => write!(f, "{}", e),
+            Error::Crypto(e) => write!(f, "Crypto Error: {}", e),
+            Error::Solve(e) => write!(f, "Solve Error: {}", e),
+            Error::Protocol(e) => write!(f, "Protocol Error: {}", e),
+            Error::Mismatch(e) => write!(f, "Mismatch Error: {}", e),
+            Error::Decode(e) => write!(f, "Decode Error: {}", e),
+            Error::Io(e) => write!(f, "IO Error: {}", e),
+        }
+    }
+}
+impl std::error::Error for Error {}
+
+impl From<ConfigError> for Error {
+    fn from(e: ConfigError) -> Self {
+        Error::Config(e)
+    }
+}
+
+impl From<NetError> for Error {
+    fn from(e: NetError) -> Self {
+        Error::Net(e)
+    }
+}
+
+impl From<ErrorStack> for Error {
+    fn from(e: ErrorStack) -> Self {
+        Error::Crypto(e)
+    }
+}
+
+impl From<SolveError> for Error {
+    fn from(e: SolveError) -> Self {
+        Error::Solve(e)
+    }
+}
+
+impl From<ProtocolError> for Error {
+    fn from(e: ProtocolError) -> Self {
+        Error::Protocol(e)
+    }
+}
+
+impl From<MismatchError> for Error {
+    fn from(e: MismatchError) -> Self {
+        Error::Mismatch(e)
+    }
+}
+
+impl From<bincode::Error> for Error {
+    fn from(e: bincode::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::error::Error;
+    use crate::flint::SolveError;
+    use crate::secure::Peer;
+    use crate::server::ProtocolError;
+
+    #[test]
+    fn error_test() {
+        let e: Error = ProtocolError::Malformed(Some(Peer::Client(3))).into();
+        assert!(matches!(e, Error::Protocol(ProtocolError::Malformed(_))));
+        assert_eq!(
+            e.to_string(),
+            "Protocol Error: Some(Client(3)) sent a malformed message"
+        );
+        let e: Error = SolveError::NonLinearFactor(2).into();
+        assert_eq!(e.to_string(), "Solve Error: irreducible factor of degree 2");
+        let e: Error = std::io::Error::from(std::io::ErrorKind::AddrInUse).into();
+        assert!(matches!(e, Error::Io(_)));
+    }
+}
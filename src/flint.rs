@@ -26,8 +26,37 @@ pub fn int_to_fmpz(i: &rug::Integer) -> flint_sys::fmpz::fmpz {
     }
 }
 
-/// An implementation in flint to solve the Newton Power equation.
-pub fn solve_impl(p: &Integer, sums: &[Integer]) -> Vec<Integer> {
+/// Error in solving the Newton Power equation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum SolveError {
+    /// The polynomial has an irreducible factor of the given degree, i.e. it
+    /// does not split into linear factors.
+    NonLinearFactor(usize),
+    /// The roots, counted with multiplicity, do not match the number of sums.
+    RootCountMismatch {
+        /// Number of sums, i.e. the degree of the polynomial.
+        expected: usize,
+        /// Number of roots found.
+        found: usize,
+    },
+}
+
+impl std::fmt::Display for SolveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SolveError::NonLinearFactor(d) => write!(f, "irreducible factor of degree {}", d),
+            SolveError::RootCountMismatch { expected, found } => {
+                write!(f, "expected {} roots, found {}", expected, found)
+            }
+        }
+    }
+}
+impl std::error::Error for SolveError {}
+
+/// An implementation in flint to solve the Newton Power equation. Fails if
+/// the polynomial does not fully split into `sums.len()` roots, which happens
+/// when a sum is tampered with or a mask is wrong.
+pub fn solve_impl(p: &Integer, sums: &[Integer]) -> Result<Vec<Integer>, SolveError> {
     unsafe {
         let n: i64 = sums.len() as i64;
 
@@ -84,6 +113,12 @@ pub fn solve_impl(p: &Integer, sums: &[Integer]) -> Vec<Integer> {
         let exp = std::slice::from_raw_parts(factors.exp, factors.num as usize);
         let poly = std::slice::from_raw_parts_mut(factors.poly, factors.num as usize);
         for i in 0..factors.num as usize {
+            // Only a linear factor gives a root.
+            if poly[i].length != 2 {
+                return Err(SolveError::NonLinearFactor(
+                    (poly[i].length as usize).saturating_sub(1),
+                ));
+            }
             let mut x = int_to_fmpz(&Integer::from(0));
             flint_sys::fmpz_mod_poly::fmpz_mod_poly_get_coeff_fmpz(
                 &mut x,
@@ -95,18 +130,25 @@ pub fn solve_impl(p: &Integer, sums: &[Integer]) -> Vec<Integer> {
             if x > 0 {
                 x = p - x;
             }
-            for _ in 0..exp[i as usize] {
+            for _ in 0..exp[i] {
                 messages.push(x.clone());
             }
         }
 
-        messages
+        if messages.len() != sums.len() {
+            return Err(SolveError::RootCountMismatch {
+                expected: sums.len(),
+                found: messages.len(),
+            });
+        }
+
+        Ok(messages)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::flint::solve_impl;
+    use crate::flint::{solve_impl, SolveError};
     use num_traits::Pow;
     use rug::Integer;
     #[test]
@@ -125,9 +167,24 @@ mod tests {
                 })
             })
             .collect();
-        let mut result = solve_impl(&modulus, &powers);
+        let mut result = solve_impl(&modulus, &powers).unwrap();
         vars.sort();
         result.sort();
         assert_eq!(vars, result);
     }
+
+    #[test]
+    fn split_test() {
+        let modulus: Integer = Integer::from(2).pow(64) - 59;
+        // x^2 - r has no root for a non-residue r.
+        let r = (2..)
+            .map(Integer::from)
+            .find(|r| r.legendre(&modulus) == -1)
+            .unwrap();
+        let powers = vec![Integer::from(0), 2 * r % &modulus];
+        assert_eq!(
+            solve_impl(&modulus, &powers),
+            Err(SolveError::NonLinearFactor(2))
+        );
+    }
 }
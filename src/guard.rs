@@ -1,65 +1,93 @@
 use crate::config::ProtocolParams;
-use crate::ecc::{add, get_g, get_h, get_order, mul, new_big_num_context, to_bytes};
-use openssl::ec::EcPoint;
+use crate::ecc::{add, get_g, get_h, mul, to_bytes};
+use crate::prf::ntt;
+use crate::rng::Rng;
 use rayon::prelude::*;
-use rug::{Complete, Integer};
-use rug_fft::{bit_rev_radix_2_intt, bit_rev_radix_2_ntt};
+use rug::Integer;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
 
-/// Setup value of one vector.
-#[derive(Serialize, Clone, Deserialize, Default, Debug)]
-pub struct SetupVector {
-    /// Original value.
-    pub value: Vec<Integer>,
-    /// Original value, processed by NTT.
-    pub value_ntt: Vec<Integer>,
-    /// Value after NTT and multiplied by the hash values.
-    pub product_ntt: Vec<Integer>,
-    /// Value after INTT.
-    pub product: Vec<Integer>,
-    /// Value after scaling.
-    pub scaled: Vec<Integer>,
-    /// Value `e` used in the blame protocol.
-    pub e: Vec<Integer>,
+/// Seed shared with each other client, `None` where there is no shared seed,
+/// e.g. at the index of the client itself.
+pub type Seeds = Vec<Option<Vec<u8>>>;
+
+/// Setup of a client. The masks of each round are derived from the key share
+/// when needed, see `prf::Masks`.
+#[derive(Serialize, Clone, Deserialize)]
+pub struct ClientSetup {
+    /// Pairwise seeds of the client.
+    pub seeds: Seeds,
+    /// Key share of the client.
+    pub share: Vec<Integer>,
+    /// Blinding vector of the key share, used in the blame protocol.
+    pub blinding: Vec<Integer>,
 }
 
-/// A pair of setup values used in one round.
+/// Setup of the relay.
 #[derive(Serialize, Clone, Deserialize, Debug)]
-pub struct SetupValues {
-    /// Value of the share.
-    pub share: SetupVector,
-    /// Value of the blinding vector.
-    pub blinding: SetupVector,
-    /// Value `e` used in the blame protocol.
-    pub e: Option<Vec<Vec<u8>>>,
+pub struct RelaySetup {
+    /// IDs of the current members, in increasing order.
+    pub members: Vec<usize>,
+    /// Offset in the key of each client, 0 for IDs without a member.
+    pub offsets: Vec<Integer>,
+    /// Commitments to the key share of each client, empty for IDs without a
+    /// member, or `None` without the blame protocol.
+    pub commitments: Option<Vec<Vec<Vec<u8>>>>,
 }
 
-/// Setup values of the relay.
+/// Setup of a node.
 #[derive(Serialize, Clone, Deserialize)]
-pub struct SetupRelay {
-    /// Values used in the protocol.
-    pub values: SetupValues,
-    /// Value `qw` used in the blame protocol.
-    pub qw: Option<Vec<Vec<Vec<u8>>>>,
+pub enum Setup {
+    /// Setup of the client.
+    SetupValues(ClientSetup),
+    /// Setup of the relay.
+    SetupRelay(RelaySetup),
 }
 
-/// Setup values.
-#[derive(Serialize, Clone, Deserialize)]
-pub enum Setup {
-    /// Setup value of the client.
-    SetupValues(SetupValues),
-    /// Setup value of the relay.
-    SetupRelay(SetupRelay),
+/// Change of the member set.
+#[derive(Clone, Copy, Debug)]
+pub enum MemberChange {
+    /// Adds a client with the given ID.
+    Add(usize),
+    /// Removes the client with the given ID.
+    Remove(usize),
 }
 
+/// Reason a member change does not apply to the current member set.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MemberError {
+    /// The client to add is already a member.
+    AlreadyMember(usize),
+    /// The client to remove is not a member.
+    NotMember(usize),
+    /// The member set already holds the given number of clients, the most the
+    /// parameters allow for.
+    Full(usize),
+}
+
+impl std::fmt::Display for MemberError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MemberError::AlreadyMember(nid) => write!(f, "client {} is already a member", nid),
+            MemberError::NotMember(nid) => write!(f, "client {} is not a member", nid),
+            MemberError::Full(size) => write!(f, "the member set already holds {} clients", size),
+        }
+    }
+}
+
+/// Length of a pairwise seed in bytes.
+pub const SEED_LEN: usize = 32;
+
 /// Generate all shares for the nodes so that they sum up to `sum`.
-pub fn generate_sum_shares(n: usize, modulus: &Integer, sum: &Integer) -> Vec<Integer> {
-    let mut rand = rug::rand::RandState::new();
-    let mut shares: Vec<Integer> =
-        std::iter::repeat_with(|| Integer::from(modulus.random_below_ref(&mut rand)))
-            .take(n - 1)
-            .collect();
+pub fn generate_sum_shares(
+    n: usize,
+    modulus: &Integer,
+    sum: &Integer,
+    rng: &mut Rng,
+) -> Vec<Integer> {
+    let mut shares: Vec<Integer> = std::iter::repeat_with(|| rng.below(modulus))
+        .take(n - 1)
+        .collect();
     shares.push(
         shares
             .iter()
@@ -68,312 +96,293 @@ pub fn generate_sum_shares(n: usize, modulus: &Integer, sum: &Integer) -> Vec<In
     shares
 }
 
-/// Compute the hash for the PRF.
-pub fn compute_hash(slot_number: usize, vec_length: usize, ring_v: &Integer) -> Vec<Integer> {
+/// Expands a pairwise seed into a vector over `ring_v`.
+pub fn expand_seed(seed: &[u8], vec_length: usize, ring_v: &Integer) -> Vec<Integer> {
     (0..vec_length)
         .map(|i| {
             let mut hasher = Sha512::new();
-            hasher.update(slot_number.to_string());
-            hasher.update(i.to_string());
+            hasher.update(seed);
+            hasher.update((i as u64).to_le_bytes());
             Integer::from_digits(&hasher.finalize(), rug::integer::Order::Lsf) % ring_v
         })
         .collect()
 }
 
-/// Generate setup values for one round. (base or bulk)
-pub fn gen_setup_vector(params: &ProtocolParams, mut shares: Vec<Integer>) -> SetupVector {
-    let mut result: SetupVector = SetupVector::default();
-    let mut hash_vector = compute_hash(0, params.vector_len, &params.ring_v.order);
-    let root_of_unity = params.ring_v.root_of_unity(params.vector_len);
-    result.value = shares.clone();
-    bit_rev_radix_2_ntt(&mut shares, &params.ring_v.order, &root_of_unity);
-    bit_rev_radix_2_ntt(&mut hash_vector, &params.ring_v.order, &root_of_unity);
-    result.value_ntt = shares.clone();
-    result.product_ntt = shares
-        .into_iter()
-        .zip(hash_vector)
-        .map(|(a, b)| a * b)
-        .collect();
-    result.product = result.product_ntt.clone();
-    bit_rev_radix_2_intt(&mut result.product, &params.ring_v.order, &root_of_unity);
-    result.scaled = result
-        .product
-        .iter()
-        .map(|i| Integer::from(i * &params.q) / &params.ring_v.order)
-        .collect();
-    result.e = result
-        .product
+/// Sums the vectors `nid` shares with each of the `peers`. The vector of a
+/// pair is added by the smaller ID and subtracted by the larger one, so that
+/// it cancels out over all clients. Peers without a seed are skipped.
+pub fn pairwise_sum(
+    params: &ProtocolParams,
+    nid: usize,
+    seeds: &[Option<Vec<u8>>],
+    peers: &[usize],
+) -> Vec<Integer> {
+    let order = &params.ring_v.order;
+    peers
         .iter()
-        .zip(result.scaled.iter())
-        .map(|(w, z)| Integer::from(w * &params.q) - z * &params.ring_v.order)
-        .collect();
-    result
+        .filter_map(|peer| seeds[*peer].as_ref().map(|seed| (peer, seed)))
+        .fold(
+            vec![Integer::from(0); params.vector_len],
+            |acc, (peer, seed)| {
+                let expanded = expand_seed(seed, params.vector_len, order);
+                acc.into_iter()
+                    .zip(expanded)
+                    .map(|(a, b)| {
+                        if nid < *peer {
+                            (a + b) % order
+                        } else {
+                            (a - b + order) % order
+                        }
+                    })
+                    .collect()
+            },
+        )
 }
 
-/// Generate setup values for one full round. (base and bulk)
-pub fn gen_setup_values(
+/// Derives the key of `nid` from its offset and its pairwise seeds. The keys
+/// of all clients sum up to 1 as long as the offsets do, while the relay can
+/// still reconstruct the sum over any subset of clients from the offsets and
+/// the pairwise vectors with the others.
+pub fn derive_key(
     params: &ProtocolParams,
-    shares: &[Integer],
-    do_blame: bool,
-) -> SetupValues {
-    let share = gen_setup_vector(params, Vec::from(shares));
-    let blinding = gen_setup_vector(params, Vec::from(shares));
-    SetupValues {
-        e: if do_blame {
-            Some(
-                (0..params.vector_len)
-                    .map(|i| {
-                        to_bytes(
-                            params,
-                            &add(
-                                params,
-                                &mul(params, &get_g(params), &share.e[i]),
-                                &mul(params, &get_h(params), &blinding.e[i]),
-                            ),
-                        )
-                    })
-                    .collect(),
+    nid: usize,
+    offset: &Integer,
+    seeds: &[Option<Vec<u8>>],
+) -> Vec<Integer> {
+    let peers: Vec<usize> = (0..seeds.len()).collect();
+    pairwise_sum(params, nid, seeds, &peers)
+        .into_iter()
+        .map(|x| (x + offset) % &params.ring_v.order)
+        .collect()
+}
+
+/// Generates the offsets and pairwise seeds of `n` clients. Returns the
+/// offsets, which sum up to 1, and the seeds of each client.
+pub fn generate_keys(
+    n: usize,
+    params: &ProtocolParams,
+    rng: &mut Rng,
+) -> (Vec<Integer>, Vec<Seeds>) {
+    let offsets = generate_sum_shares(n, &params.ring_v.order, &Integer::from(1), rng);
+    let mut seeds: Vec<Seeds> = vec![vec![None; n]; n];
+    for (i, j) in (0..n).flat_map(|i| (i + 1..n).map(move |j| (i, j))) {
+        let seed = rng.bytes(SEED_LEN);
+        seeds[i][j] = Some(seed.clone());
+        seeds[j][i] = Some(seed);
+    }
+    (offsets, seeds)
+}
+
+/// Draws a random blinding vector for a key share.
+pub fn gen_blinding(params: &ProtocolParams, rng: &mut Rng) -> Vec<Integer> {
+    (0..params.vector_len)
+        .map(|_| rng.below(&params.ring_v.order))
+        .collect()
+}
+
+/// Commits to the key `share` with its `blinding`, i.e. `g^s h^b` for each
+/// element `s` and `b` of both after NTT. The relay checks the openings of the
+/// blame protocol against the commitments, whatever the round.
+pub fn commit(params: &ProtocolParams, share: &[Integer], blinding: &[Integer]) -> Vec<Vec<u8>> {
+    let share = ntt(params, share);
+    let blinding = ntt(params, blinding);
+    share
+        .par_iter()
+        .zip(blinding.par_iter())
+        .map(|(s, b)| {
+            to_bytes(
+                params,
+                &add(
+                    params,
+                    &mul(params, &get_g(params), s),
+                    &mul(params, &get_h(params), b),
+                ),
             )
-        } else {
-            None
-        },
-        share,
-        blinding,
+        })
+        .collect()
+}
+
+/// Applies `change` to the setup of the relay and the clients. `clients` holds
+/// the setup of each ID, `None` for IDs without a member, and grows to fit an
+/// added ID. Only the members sharing a seed with the changed client get new
+/// keys, with fresh offsets and blindings drawn from `rng` that still sum up
+/// to 1. Returns the IDs of the clients whose setup changed.
+pub fn change_members(
+    params: &ProtocolParams,
+    relay: &mut RelaySetup,
+    clients: &mut Vec<Option<ClientSetup>>,
+    change: MemberChange,
+    rng: &mut Rng,
+) -> Result<Vec<usize>, MemberError> {
+    let order = &params.ring_v.order;
+    let mut affected = match change {
+        MemberChange::Add(nid) => {
+            if relay.members.contains(&nid) {
+                return Err(MemberError::AlreadyMember(nid));
+            }
+            // Make room for an ID beyond the current ones.
+            let size = clients.len().max(relay.offsets.len()).max(nid + 1);
+            clients.resize(size, None);
+            for client in clients.iter_mut().flatten() {
+                client.seeds.resize(size, None);
+            }
+            relay.offsets.resize(size, Integer::from(0));
+            if let Some(commitments) = relay.commitments.as_mut() {
+                commitments.resize(size, Vec::new());
+            }
+            let mut seeds = vec![None; size];
+            for peer in relay.members.iter() {
+                let seed = rng.bytes(SEED_LEN);
+                clients[*peer].as_mut().unwrap().seeds[nid] = Some(seed.clone());
+                seeds[*peer] = Some(seed);
+            }
+            clients[nid] = Some(ClientSetup {
+                seeds,
+                share: Vec::new(),
+                blinding: gen_blinding(params, rng),
+            });
+            relay.offsets[nid] = Integer::from(0);
+            let mut affected = relay.members.clone();
+            affected.push(nid);
+            relay.members.push(nid);
+            relay.members.sort_unstable();
+            affected
+        }
+        MemberChange::Remove(nid) => {
+            if !relay.members.contains(&nid) {
+                return Err(MemberError::NotMember(nid));
+            }
+            relay.members.retain(|member| *member != nid);
+            clients[nid] = None;
+            if let Some(commitments) = relay.commitments.as_mut() {
+                commitments[nid] = Vec::new();
+            }
+            let mut affected: Vec<usize> = relay
+                .members
+                .iter()
+                .copied()
+                .filter(|member| clients[*member].as_ref().unwrap().seeds[nid].is_some())
+                .collect();
+            for member in affected.iter() {
+                clients[*member].as_mut().unwrap().seeds[nid] = None;
+            }
+            // Hand the offset over to another member.
+            let offset = std::mem::replace(&mut relay.offsets[nid], Integer::from(0));
+            if let Some(heir) = affected.first().or(relay.members.first()).copied() {
+                relay.offsets[heir] = (&relay.offsets[heir] + offset) % order;
+                if !affected.contains(&heir) {
+                    affected.push(heir);
+                }
+            }
+            affected
+        }
+    };
+    affected.sort_unstable();
+    // Re-randomise the offsets of the affected members, so that their new
+    // keys do not follow from the old ones.
+    let deltas = generate_sum_shares(affected.len(), order, &Integer::from(0), rng);
+    for (nid, delta) in affected.iter().zip(deltas) {
+        relay.offsets[*nid] = (&relay.offsets[*nid] + delta) % order;
+    }
+    for nid in affected.iter() {
+        info!("Generating key for node {}...", nid);
+        let client = clients[*nid].as_mut().unwrap();
+        client.share = derive_key(params, *nid, &relay.offsets[*nid], &client.seeds);
+        client.blinding = gen_blinding(params, rng);
+        if let Some(commitments) = relay.commitments.as_mut() {
+            commitments[*nid] = commit(params, &client.share, &client.blinding);
+        }
     }
+    Ok(affected)
 }
 
-/// Generate values for the relay to verify in the blame protocol.
-fn compute_d(
-    order: &Integer,
-    tau: &Integer,
-    _gamma: &[Integer],
-    omega: &[Integer],
-    omega_len: &[Integer],
-    product_ntt: &[Integer],
-    product: &[Integer],
+/// Compute the hash for the PRF in `round`. Every element is bound to the
+/// round number, the attempt within the round and its slot index so that each
+/// round and each retry of the base round gets a fresh mask.
+pub fn compute_hash(
+    round: usize,
+    attempt: usize,
+    vec_length: usize,
+    ring_v: &Integer,
 ) -> Vec<Integer> {
-    assert_eq!(product_ntt.len(), product.len());
-    (0..product_ntt.len())
-        .into_par_iter()
-        .map(|j| {
-            let val = (Integer::from(product_ntt.len()).invert(order).unwrap()
-                * Integer::sum(
-                    (0..product_ntt.len())
-                        .into_par_iter()
-                        .map(|k| {
-                            (&product_ntt[k] * &omega_len[j * k / product_ntt.len()]).complete()
-                                * &omega[j * k % product_ntt.len()]
-                        })
-                        .collect::<Vec<_>>()
-                        .iter(),
-                )
-                .complete())
-                - &product[j];
-            assert_eq!(Integer::from(&val % order), Integer::from(0));
-            val * Integer::from(order.invert_ref(tau).unwrap()) % tau
+    (0..vec_length)
+        .map(|i| {
+            let mut hasher = Sha512::new();
+            hasher.update((round as u64).to_le_bytes());
+            hasher.update((attempt as u64).to_le_bytes());
+            hasher.update((i as u64).to_le_bytes());
+            Integer::from_digits(&hasher.finalize(), rug::integer::Order::Lsf) % ring_v
         })
         .collect()
 }
 
-/// Generate setup values for the relay.
-pub fn gen_setup_relay(
-    params: &ProtocolParams,
-    client_values: &[SetupValues],
-    do_blame: bool,
-) -> SetupRelay {
-    let values = gen_setup_values(params, &vec![Integer::from(1); params.vector_len], do_blame);
-    if do_blame {
-        let gamma = params
-            .ring_v
-            .root_of_unity(params.vector_len * 2)
-            .invert(&params.ring_v.order)
-            .unwrap();
-        let omega = params
-            .ring_v
-            .root_of_unity(params.vector_len)
-            .invert(&params.ring_v.order)
-            .unwrap();
-        info!("Computing gamma...");
-        let gamma_inverse: Vec<Integer> = std::iter::once(Integer::from(1))
-            .chain((0..params.vector_len).scan(Integer::from(1), |acc, _| {
-                *acc *= &gamma;
-                *acc %= &params.ring_v.order;
-                Some(acc.clone())
-            }))
-            .collect();
-        info!("Computing omega...");
-        let omega_inverse: Vec<Integer> = std::iter::once(Integer::from(1))
-            .chain((0..params.vector_len).scan(Integer::from(1), |acc, _| {
-                *acc *= &omega;
-                *acc %= &params.ring_v.order;
-                Some(acc.clone())
-            }))
-            .collect();
-        let omega_len = Integer::from(
-            omega
-                .pow_mod_ref(&Integer::from(params.vector_len), &params.ring_v.order)
-                .unwrap(),
-        );
-        let omega_len_inverse: Vec<Integer> = std::iter::once(Integer::from(1))
-            .chain((0..params.vector_len).scan(Integer::from(1), |acc, _| {
-                *acc *= &omega_len;
-                *acc %= &params.ring_v.order;
-                Some(acc.clone())
-            }))
-            .collect();
+#[cfg(test)]
+mod tests {
+    use crate::config::default_base_params;
+    use crate::guard::{
+        change_members, commit, derive_key, gen_blinding, generate_keys, ClientSetup, MemberChange,
+        MemberError, RelaySetup,
+    };
+    use crate::rng::Rng;
+    use rug::Integer;
 
-        let mut hash_vector = compute_hash(0, params.vector_len, &params.ring_v.order);
-        let root_of_unity = params.ring_v.root_of_unity(params.vector_len);
-        bit_rev_radix_2_ntt(&mut hash_vector, &params.ring_v.order, &root_of_unity);
-        info!("Computing d...");
-        let d: Vec<Vec<Integer>> = (0..client_values.len())
-            .into_par_iter()
-            .map(|i| {
-                compute_d(
-                    &params.ring_v.order,
-                    &get_order(params),
-                    &gamma_inverse,
-                    &omega_inverse,
-                    &omega_len_inverse,
-                    &client_values[i].share.product_ntt,
-                    &client_values[i].share.product,
-                )
-            })
-            .collect();
-        debug!("d = {:?}", d);
-        info!("Computing d_...");
-        let d_blinding: Vec<Vec<Integer>> = (0..client_values.len())
-            .into_par_iter()
-            .map(|i| {
-                compute_d(
-                    &params.ring_v.order,
-                    &get_order(params),
-                    &gamma_inverse,
-                    &omega_inverse,
-                    &omega_len_inverse,
-                    &client_values[i].blinding.product_ntt,
-                    &client_values[i].blinding.product,
-                )
-            })
-            .collect();
-        info!("Computing ab...");
-        let ab: Vec<Vec<EcPoint>> = (0..client_values.len())
-            .into_par_iter()
-            .map(|i| {
-                (0..params.vector_len)
-                    .into_par_iter()
-                    .map(|j| {
-                        add(
-                            params,
-                            &mul(params, &get_g(params), &client_values[i].share.value_ntt[j]),
-                            &mul(
-                                params,
-                                &get_h(params),
-                                &client_values[i].blinding.value_ntt[j],
-                            ),
-                        )
-                    })
-                    .collect()
-            })
-            .collect();
-        info!("Computing qw...");
-        let qw: Vec<Vec<EcPoint>> = (0..client_values.len())
-            .into_par_iter()
+    #[test]
+    fn member_test() {
+        let mut params = default_base_params();
+        params.vector_len = 16;
+        let n = 4;
+        let mut rng = Rng::from_seed(0, 0);
+        let (offsets, seeds) = generate_keys(n, &params, &mut rng);
+        let mut clients: Vec<Option<ClientSetup>> = (0..n)
             .map(|i| {
-                (0..params.vector_len)
-                    .into_par_iter()
-                    .map(|k| {
-                        mul(
-                            params,
-                            &add(
-                                params,
-                                &add(
-                                    params,
-                                    &mul(
-                                        params,
-                                        &get_g(params),
-                                        &(-Integer::from(&params.ring_v.order) * &d[i][k]),
-                                    ),
-                                    &mul(
-                                        params,
-                                        &get_h(params),
-                                        &(-Integer::from(&params.ring_v.order) * &d_blinding[i][k]),
-                                    ),
-                                ),
-                                &(0..params.vector_len)
-                                    .into_par_iter()
-                                    .map(|j| {
-                                        mul(
-                                            params,
-                                            &ab[i][j],
-                                            &(Integer::from(params.vector_len)
-                                                .invert(&params.ring_v.order)
-                                                .unwrap()
-                                                * &hash_vector[j]
-                                                * &omega_len_inverse[j * k / params.vector_len]
-                                                * &omega_inverse[j * k % params.vector_len]),
-                                        )
-                                    })
-                                    .reduce_with(|a, b| add(params, &a, &b))
-                                    .unwrap(),
-                            ),
-                            &params.q,
-                        )
-                    })
-                    .collect()
+                Some(ClientSetup {
+                    seeds: seeds[i].clone(),
+                    share: derive_key(&params, i, &offsets[i], &seeds[i]),
+                    blinding: gen_blinding(&params, &mut rng),
+                })
             })
             .collect();
-        assert!(qw
+        let commitments = clients
             .iter()
-            .zip(
-                (0..client_values.len())
-                    .into_par_iter()
-                    .map(|i| {
-                        (0..params.vector_len)
-                            .into_par_iter()
-                            .map(|j| {
-                                mul(
-                                    params,
-                                    &add(
-                                        params,
-                                        &mul(
-                                            params,
-                                            &get_h(params),
-                                            &client_values[i].blinding.product[j],
-                                        ),
-                                        &mul(
-                                            params,
-                                            &get_g(params),
-                                            &client_values[i].share.product[j],
-                                        ),
-                                    ),
-                                    &params.q,
-                                )
-                            })
-                            .collect::<Vec<_>>()
-                    })
-                    .collect::<Vec<_>>()
-                    .iter()
-            )
-            .all(|(a, b)| {
-                a.iter().zip(b.iter()).all(|(c, d)| {
-                    c.eq(
-                        params.group.as_ref().unwrap(),
-                        d,
-                        &mut new_big_num_context(),
-                    )
-                    .unwrap()
-                })
-            }),);
-        SetupRelay {
-            values,
-            qw: Some(
-                qw.iter()
-                    .map(|a| a.iter().map(|b| to_bytes(params, b)).collect::<Vec<_>>())
-                    .collect(),
-            ),
+            .flatten()
+            .map(|client| commit(&params, &client.share, &client.blinding))
+            .collect();
+        let mut relay = RelaySetup {
+            members: (0..n).collect(),
+            offsets,
+            commitments: Some(commitments),
+        };
+        for change in [
+            MemberChange::Remove(1),
+            MemberChange::Add(1),
+            MemberChange::Remove(3),
+            MemberChange::Add(6),
+        ] {
+            change_members(&params, &mut relay, &mut clients, change, &mut rng).unwrap();
+            for (i, client) in clients.iter().enumerate() {
+                assert_eq!(client.is_some(), relay.members.contains(&i));
+                let commitment = client
+                    .as_ref()
+                    .map(|client| commit(&params, &client.share, &client.blinding))
+                    .unwrap_or_default();
+                assert_eq!(relay.commitments.as_ref().unwrap()[i], commitment);
+            }
+            for k in 0..params.vector_len {
+                let sum = relay.members.iter().fold(Integer::from(0), |acc, i| {
+                    acc + &clients[*i].as_ref().unwrap().share[k]
+                });
+                assert_eq!(sum % &params.ring_v.order, 1);
+            }
+        }
+        assert_eq!(relay.members, vec![0, 1, 2, 6]);
+        assert_eq!(clients.len(), 7);
+        for change in [MemberChange::Add(2), MemberChange::Remove(3)] {
+            let e = change_members(&params, &mut relay, &mut clients, change, &mut rng);
+            let expected = match change {
+                MemberChange::Add(nid) => MemberError::AlreadyMember(nid),
+                MemberChange::Remove(nid) => MemberError::NotMember(nid),
+            };
+            assert_eq!(e, Err(expected));
         }
-    } else {
-        SetupRelay { values, qw: None }
     }
 }
@@ -11,12 +11,20 @@
 #[macro_use]
 extern crate log;
 
+/// Handles the streaming aggregation of slot vectors on the relay.
+pub mod aggregate;
+/// Handles the blame protocol for bulk round disruption.
+pub mod blame;
 /// Handles client-side communication.
 pub mod client;
 /// Handles config file read/write.
 pub mod config;
 /// Handles elliptic curve computation.
 pub mod ecc;
+/// Handles the round trip of the relay to the outside world.
+pub mod egress;
+/// Handles the errors of the clients and the relay.
+pub mod error;
 /// Handles flint-related native operations.
 pub mod flint;
 /// Handles guard node setup operation.
@@ -25,7 +33,21 @@ pub mod guard;
 pub mod message;
 /// Handles network-related functionalities.
 pub mod net;
+/// Handles the compact encoding of slot vectors.
+pub mod packed;
+/// Handles the generation of protocol parameters.
+pub mod params;
 /// Handles additional on-demand PRF computation.
 pub mod prf;
+/// Handles the recovery of the masks of missing clients.
+pub mod recovery;
+/// Handles cryptographically secure randomness.
+pub mod rng;
+/// Handles the authenticated and encrypted transport.
+pub mod secure;
 /// Handles server-side communication.
 pub mod server;
+/// Handles the dealerless setup.
+pub mod setup;
+/// Handles the output of the relay to the outside world.
+pub mod sink;
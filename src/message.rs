@@ -1,21 +1,247 @@
+use crate::config::{Config, ProtocolParams};
+use crate::guard::RelaySetup;
+use crate::packed::PackedVec;
 use rug::Integer;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+
+/// Version of the wire protocol. Bump it whenever `Message` changes.
+pub const PROTOCOL_VERSION: u32 = 5;
+
+/// What a node runs, compared between a node and the relay before any round
+/// starts.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    /// Version of the wire protocol.
+    pub version: u32,
+    /// SHA-256 hash of the base round parameters.
+    pub base_params: Vec<u8>,
+    /// SHA-256 hash of the bulk round parameters.
+    pub bulk_params: Vec<u8>,
+    /// How many rounds to run.
+    pub round: usize,
+    /// How many slots a client needs in the bulk round.
+    pub slot_per_round: usize,
+    /// How many slot groups a client may reserve in one round.
+    pub max_reservation: usize,
+    /// Whether the blame protocol runs.
+    pub do_blame: bool,
+    /// Whether the masks are computed on demand.
+    pub do_unzip: bool,
+    /// Whether the relay tolerates dropouts, i.e. the clients add self masks
+    /// and answer recovery requests, see `Config::self_masks`.
+    pub self_masks: bool,
+}
+
+/// Mismatch between the capabilities of a node and the relay.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MismatchError {
+    /// The wire protocol versions differ.
+    Version {
+        /// Our version.
+        ours: u32,
+        /// Their version.
+        theirs: u32,
+    },
+    /// The base round parameters differ.
+    BaseParams,
+    /// The bulk round parameters differ.
+    BulkParams,
+    /// The round counts differ.
+    Round {
+        /// Our round count.
+        ours: usize,
+        /// Their round count.
+        theirs: usize,
+    },
+    /// The slot counts differ.
+    SlotPerRound {
+        /// Our slot count.
+        ours: usize,
+        /// Their slot count.
+        theirs: usize,
+    },
+    /// The reservation limits differ.
+    MaxReservation {
+        /// Our reservation limit.
+        ours: usize,
+        /// Their reservation limit.
+        theirs: usize,
+    },
+    /// One side runs the blame protocol and the other does not.
+    DoBlame {
+        /// Whether we run it.
+        ours: bool,
+        /// Whether they run it.
+        theirs: bool,
+    },
+    /// One side computes the masks on demand and the other does not.
+    DoUnzip {
+        /// Whether we do.
+        ours: bool,
+        /// Whether they do.
+        theirs: bool,
+    },
+    /// One side tolerates dropouts and the other does not.
+    SelfMasks {
+        /// Whether we do.
+        ours: bool,
+        /// Whether they do.
+        theirs: bool,
+    },
+}
+
+impl std::fmt::Display for MismatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MismatchError::Version { ours, theirs } => {
+                write!(f, "protocol version {} against {}", ours, theirs)
+            }
+            MismatchError::BaseParams => write!(f, "different base_params"),
+            MismatchError::BulkParams => write!(f, "different bulk_params"),
+            MismatchError::Round { ours, theirs } => {
+                write!(f, "round {} against {}", ours, theirs)
+            }
+            MismatchError::SlotPerRound { ours, theirs } => {
+                write!(f, "slot_per_round {} against {}", ours, theirs)
+            }
+            MismatchError::MaxReservation { ours, theirs } => {
+                write!(f, "max_reservation {} against {}", ours, theirs)
+            }
+            MismatchError::DoBlame { ours, theirs } => {
+                write!(f, "do_blame {} against {}", ours, theirs)
+            }
+            MismatchError::DoUnzip { ours, theirs } => {
+                write!(f, "do_unzip {} against {}", ours, theirs)
+            }
+            MismatchError::SelfMasks { ours, theirs } => {
+                write!(
+                    f,
+                    "dropout tolerance (round_timeout or do_blame) {} against {}",
+                    ours, theirs
+                )
+            }
+        }
+    }
+}
+impl std::error::Error for MismatchError {}
+
+/// Hashes the parameters that all nodes must share.
+fn hash_params(params: &ProtocolParams) -> Vec<u8> {
+    Sha256::digest(bincode::serialize(params).unwrap()).to_vec()
+}
+
+impl Capabilities {
+    /// Returns the capabilities of a node running `c`.
+    pub fn from_config(c: &Config) -> Self {
+        Capabilities {
+            version: PROTOCOL_VERSION,
+            base_params: hash_params(&c.base_params),
+            bulk_params: hash_params(&c.bulk_params),
+            round: c.round,
+            slot_per_round: c.slot_per_round,
+            max_reservation: c.max_reservation,
+            do_blame: c.do_blame,
+            do_unzip: c.do_unzip,
+            self_masks: c.self_masks(),
+        }
+    }
+
+    /// Checks that `theirs` can run the protocol with us.
+    pub fn check(&self, theirs: &Capabilities) -> Result<(), MismatchError> {
+        if self.version != theirs.version {
+            Err(MismatchError::Version {
+                ours: self.version,
+                theirs: theirs.version,
+            })
+        } else if self.base_params != theirs.base_params {
+            Err(MismatchError::BaseParams)
+        } else if self.bulk_params != theirs.bulk_params {
+            Err(MismatchError::BulkParams)
+        } else if self.round != theirs.round {
+            Err(MismatchError::Round {
+                ours: self.round,
+                theirs: theirs.round,
+            })
+        } else if self.slot_per_round != theirs.slot_per_round {
+            Err(MismatchError::SlotPerRound {
+                ours: self.slot_per_round,
+                theirs: theirs.slot_per_round,
+            })
+        } else if self.max_reservation != theirs.max_reservation {
+            Err(MismatchError::MaxReservation {
+                ours: self.max_reservation,
+                theirs: theirs.max_reservation,
+            })
+        } else if self.do_blame != theirs.do_blame {
+            Err(MismatchError::DoBlame {
+                ours: self.do_blame,
+                theirs: theirs.do_blame,
+            })
+        } else if self.do_unzip != theirs.do_unzip {
+            Err(MismatchError::DoUnzip {
+                ours: self.do_unzip,
+                theirs: theirs.do_unzip,
+            })
+        } else if self.self_masks != theirs.self_masks {
+            Err(MismatchError::SelfMasks {
+                ours: self.self_masks,
+                theirs: theirs.self_masks,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// First message of a node to the relay. Sent on its own rather than in
+/// `Message` so that every version can decode it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Hello {
+    /// Capabilities of the node.
+    pub capabilities: Capabilities,
+    /// Token of the session to resume after a reconnect, if any. Last so that
+    /// a relay of an earlier version still decodes the capabilities.
+    pub session: Option<u64>,
+}
+
+/// Answer of the relay to `Hello`. The relay closes the connection after it
+/// if the capabilities do not match.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Welcome {
+    /// Capabilities of the relay.
+    pub capabilities: Capabilities,
+    /// Token of the session, to present on a reconnect.
+    pub session: u64,
+}
 
 /// Client base round message.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientBaseMessage {
     /// Round number.
     pub round: usize,
+    /// Attempt within the round, starting from 0.
+    pub attempt: usize,
     /// Client ID.
     pub nid: usize,
-    /// Client message.
-    pub slot_messages: Vec<Integer>,
-    /// Opening of the share in the blame protocol message.
-    pub blame: Option<Vec<Integer>>,
-    /// Opening of the blinding vector in the blame protocol message.
-    pub blame_blinding: Option<Vec<Integer>>,
-    /// Value `e` in the blame protocol message.
-    pub e: Option<Vec<Vec<u8>>>,
+    /// Client message, packed at `ceil(log2 q)` bits per element.
+    pub slot_messages: PackedVec,
+    /// Shares of the seed of the self mask, if the clients add one, see
+    /// `Config::self_masks`.
+    pub self_shares: Option<SelfShares>,
+}
+
+/// Shamir shares of the seed of the self mask of a client for one phase of a
+/// round, dealt to every member.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct SelfShares {
+    /// Share of each other member by client ID, encrypted under the seed the
+    /// client shares with it.
+    pub encrypted: BTreeMap<usize, Vec<u8>>,
+    /// Digest of the share of each member by client ID, the client itself
+    /// included, against which the relay checks the revealed shares.
+    pub digests: BTreeMap<usize, Vec<u8>>,
 }
 
 /// Server base round message.
@@ -23,10 +249,68 @@ pub struct ClientBaseMessage {
 pub struct ServerBaseMessage {
     /// Round number.
     pub round: usize,
-    /// Permutation of client-generated one-time IDs.
+    /// Permutation of client-generated one-time IDs, one per reserved slot
+    /// group. The position of an ID is the slot group it reserved.
     pub perm: Vec<Integer>,
 }
 
+/// Server message asking the clients to re-run the base round because the
+/// one-time IDs collided.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBaseRetryMessage {
+    /// Round number.
+    pub round: usize,
+    /// The attempt to run next.
+    pub attempt: usize,
+}
+
+/// Phase of a round.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Phase {
+    /// Base round.
+    Base,
+    /// Bulk round.
+    Bulk,
+}
+
+/// Server message asking the present clients to recover the masks of the
+/// clients that missed the deadline, and to reveal their shares of the seeds
+/// of the self masks of the present clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerRecoveryRequestMessage {
+    /// Round number.
+    pub round: usize,
+    /// Attempt within the round. Always 0 in the bulk round.
+    pub attempt: usize,
+    /// Phase of the round.
+    pub phase: Phase,
+    /// Clients whose masks are missing.
+    pub missing: Vec<usize>,
+    /// Encrypted shares of the seed of the self mask of each present client,
+    /// by the ID of the dealer and then of the recipient. Empty without self
+    /// masks.
+    pub shares: BTreeMap<usize, BTreeMap<usize, Vec<u8>>>,
+}
+
+/// Client recovery message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientRecoveryMessage {
+    /// Round number.
+    pub round: usize,
+    /// Attempt within the round. Always 0 in the bulk round.
+    pub attempt: usize,
+    /// Phase of the round.
+    pub phase: Phase,
+    /// Client ID.
+    pub nid: usize,
+    /// PRF of the vectors shared with the missing clients, empty if no
+    /// client is missing.
+    pub recovery: Vec<Integer>,
+    /// Share of the seed of the self mask of each present client, by the ID
+    /// of the dealer.
+    pub shares: BTreeMap<usize, Integer>,
+}
+
 /// Client bulk round message.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ClientBulkMessage {
@@ -34,8 +318,167 @@ pub struct ClientBulkMessage {
     pub round: usize,
     /// Client ID.
     pub nid: usize,
-    /// Client message.
-    pub slot_messages: Vec<Integer>,
+    /// Client message, packed at `ceil(log2 q)` bits per element.
+    pub slot_messages: PackedVec,
+    /// Shares of the seed of the self mask, if the clients add one, see
+    /// `Config::self_masks`.
+    pub self_shares: Option<SelfShares>,
+}
+
+/// Server bulk round message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBulkMessage {
+    /// Round number.
+    pub round: usize,
+    /// SHA-256 digest of the payload decoded from each slot group, or `None`
+    /// if the slot group is malformed. Empty if the relay gave up the round
+    /// because it could not remove the masks.
+    pub digests: Vec<Option<Vec<u8>>>,
+}
+
+/// Client accusation message, filed when a client finds its slot corrupted.
+/// It carries no client ID, the secret behind the ID of the slot group proves
+/// that the accuser owns it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientAccusationMessage {
+    /// Round number.
+    pub round: usize,
+    /// Index of the corrupted slot group.
+    pub slot: usize,
+    /// Secret the ID of the slot group was derived from.
+    pub secret: Vec<u8>,
+    /// Digest of the payload the accuser wrote into the slot group.
+    pub digest: Vec<u8>,
+}
+
+/// Server blame request, asking every client to open one slot group.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBlameRequestMessage {
+    /// Round number.
+    pub round: usize,
+    /// Index of the slot group to open.
+    pub slot: usize,
+    /// Secret of the accuser, which the clients check against the ID of the
+    /// slot group before opening it.
+    pub secret: Vec<u8>,
+}
+
+/// Opening of the PRF of a client over a range of elements, checked against
+/// the commitments to its key share.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Opening {
+    /// Opening of the share.
+    pub share: Vec<Integer>,
+    /// Opening of the blinding vector.
+    pub blinding: Vec<Integer>,
+    /// Value `e` of each opened element.
+    pub e: Vec<Vec<u8>>,
+    /// Carry of the share in each opened element, which the relay cannot
+    /// compute without the key.
+    pub carry: Vec<Integer>,
+    /// Carry of the blinding vector in each opened element.
+    pub carry_blinding: Vec<Integer>,
+}
+
+/// Client blame message, opening the PRF values of one slot group.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientBlameMessage {
+    /// Round number.
+    pub round: usize,
+    /// Client ID.
+    pub nid: usize,
+    /// Index of the opened slot group.
+    pub slot: usize,
+    /// Opening of the slot group.
+    pub opening: Opening,
+}
+
+/// Server blame request for a failed attempt of the base round, asking the
+/// clients that took part to open it. The unmasked sums let the clients check
+/// that the attempt failed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBaseBlameRequestMessage {
+    /// Round number.
+    pub round: usize,
+    /// Failed attempt.
+    pub attempt: usize,
+    /// Unmasked sums of the attempt, before rounding.
+    pub sums: Vec<Integer>,
+}
+
+/// Client blame message, opening a failed attempt of the base round. The IDs
+/// of the attempt are revealed, since they are discarded anyway.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientBaseBlameMessage {
+    /// Round number.
+    pub round: usize,
+    /// Opened attempt.
+    pub attempt: usize,
+    /// Client ID.
+    pub nid: usize,
+    /// IDs the client drew in the attempt.
+    pub ids: Vec<Integer>,
+    /// Opening of the power sums and the number of IDs.
+    pub opening: Opening,
+}
+
+/// Server blame result message.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerBlameResultMessage {
+    /// Round number.
+    pub round: usize,
+    /// Index of the accused slot group, or `None` for a failed attempt of the
+    /// base round.
+    pub slot: Option<usize>,
+    /// Clients proven guilty, who are excluded from now on.
+    pub guilty: Vec<usize>,
+}
+
+/// Guard message handing a running relay the setup of a new member set.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuardReconfigureMessage {
+    /// First round of the new member set.
+    pub from_round: usize,
+    /// Setup of the base round.
+    pub base: RelaySetup,
+    /// Setup of the bulk round.
+    pub bulk: RelaySetup,
+    /// Public key of each client ID in DER, empty for the IDs without a
+    /// member. Replaces the keys the relay authenticates the clients with.
+    pub clients: Vec<Vec<u8>>,
+}
+
+/// Client message of the dealerless setup, announcing its public key.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientSetupKeyMessage {
+    /// Client ID.
+    pub nid: usize,
+    /// Serialized public key.
+    pub public_key: Vec<u8>,
+    /// Signature of the public key with the long-term identity of the client.
+    pub signature: Vec<u8>,
+}
+
+/// Server message of the dealerless setup, forwarding the public keys of all
+/// clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServerSetupKeysMessage {
+    /// Serialized public key of each client.
+    pub public_keys: Vec<Vec<u8>>,
+    /// Signature of each public key by its client.
+    pub signatures: Vec<Vec<u8>>,
+}
+
+/// Client message of the dealerless setup, handing the relay the commitments
+/// it needs in the blame protocol.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ClientSetupCommitMessage {
+    /// Client ID.
+    pub nid: usize,
+    /// Commitments to the key share of the base round.
+    pub base_commitments: Option<Vec<Vec<u8>>>,
+    /// Commitments to the key share of the bulk round.
+    pub bulk_commitments: Option<Vec<Vec<u8>>>,
 }
 
 /// Client message during the PriFi protocol, used in timing.
@@ -60,12 +503,101 @@ pub enum Message {
     ClientBaseMessage(ClientBaseMessage),
     /// Server base round message.
     ServerBaseMessage(ServerBaseMessage),
+    /// Server base round retry message.
+    ServerBaseRetryMessage(ServerBaseRetryMessage),
     /// Client bulk round message.
     ClientBulkMessage(ClientBulkMessage),
     /// Server bulk round message.
-    ServerBulkMessage,
+    ServerBulkMessage(ServerBulkMessage),
+    /// Client accusation message.
+    ClientAccusationMessage(ClientAccusationMessage),
+    /// Server blame request message.
+    ServerBlameRequestMessage(ServerBlameRequestMessage),
+    /// Client blame message.
+    ClientBlameMessage(ClientBlameMessage),
+    /// Server blame result message.
+    ServerBlameResultMessage(ServerBlameResultMessage),
+    /// Server base round blame request message.
+    ServerBaseBlameRequestMessage(ServerBaseBlameRequestMessage),
+    /// Client base round blame message.
+    ClientBaseBlameMessage(ClientBaseBlameMessage),
+    /// Server recovery request message.
+    ServerRecoveryRequestMessage(ServerRecoveryRequestMessage),
+    /// Client recovery message.
+    ClientRecoveryMessage(ClientRecoveryMessage),
+    /// Guard reconfigure message.
+    GuardReconfigureMessage(GuardReconfigureMessage),
+    /// Client setup key message.
+    ClientSetupKeyMessage(ClientSetupKeyMessage),
+    /// Server setup keys message.
+    ServerSetupKeysMessage(ServerSetupKeysMessage),
+    /// Client setup commit message.
+    ClientSetupCommitMessage(ClientSetupCommitMessage),
     /// Client message during the PriFi protocol, used in timing.
     ClientPrifiMessage(ClientPrifiMessage),
     /// Server OK message during the PriFi protocol, used in timing.
     Ok,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::message::{Capabilities, MismatchError, PROTOCOL_VERSION};
+
+    #[test]
+    fn capabilities_test() {
+        let ours = Capabilities {
+            version: PROTOCOL_VERSION,
+            base_params: vec![1],
+            bulk_params: vec![2],
+            round: 10,
+            slot_per_round: 3,
+            max_reservation: 1,
+            do_blame: false,
+            do_unzip: false,
+            self_masks: true,
+        };
+        assert_eq!(ours.check(&ours.clone()), Ok(()));
+        let mut theirs = ours.clone();
+        theirs.version += 1;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(MismatchError::Version {
+                ours: PROTOCOL_VERSION,
+                theirs: PROTOCOL_VERSION + 1
+            })
+        );
+        let mut theirs = ours.clone();
+        theirs.bulk_params = vec![3];
+        assert_eq!(ours.check(&theirs), Err(MismatchError::BulkParams));
+        let mut theirs = ours.clone();
+        theirs.slot_per_round = 4;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(MismatchError::SlotPerRound { ours: 3, theirs: 4 })
+        );
+        let mut theirs = ours.clone();
+        theirs.max_reservation = 2;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(MismatchError::MaxReservation { ours: 1, theirs: 2 })
+        );
+        let mut theirs = ours.clone();
+        theirs.do_blame = true;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(MismatchError::DoBlame {
+                ours: false,
+                theirs: true
+            })
+        );
+        let mut theirs = ours.clone();
+        theirs.self_masks = false;
+        assert_eq!(
+            ours.check(&theirs),
+            Err(MismatchError::SelfMasks {
+                ours: true,
+                theirs: false
+            })
+        );
+    }
+}
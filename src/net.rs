@@ -1,60 +1,205 @@
+use crate::config::{Config, ProtocolParams};
+use crate::packed::width;
+use crate::recovery::SHARE_LEN;
 use async_std::io::{ReadExt as AsyncRead, WriteExt as AsyncWrite};
 use std::io::{Read, Write};
 use std::net::TcpStream;
+use std::time::Duration;
 
-// Network helpers to wrap message.
+// Network helpers to wrap message. Every frame starts with a header of the
+// frame version and the length of the payload as a little-endian `u64`.
+
+/// Version of the frame format.
+pub const FRAME_VERSION: u8 = 1;
+
+/// Length of the frame header in bytes.
+const HEADER_LEN: usize = 9;
+
+/// Largest frame of the handshake in bytes.
+pub const HANDSHAKE_FRAME_LEN: usize = 4096;
+
+/// Largest part of a frame to allocate before it arrives.
+const CHUNK_LEN: usize = 1 << 16;
 
 /// Error during network operations.
 #[derive(Debug)]
-pub struct NetError;
+pub enum NetError {
+    /// The peer closed the stream.
+    Eof,
+    /// The frame has an unknown version.
+    Version(u8),
+    /// The frame is longer than allowed.
+    Oversize {
+        /// Length in the header.
+        len: u64,
+        /// Largest allowed length.
+        max: usize,
+    },
+    /// The frame did not arrive or could not be sent in time.
+    Timeout,
+    /// The handshake failed, e.g. on a bad signature or an unknown peer.
+    Handshake,
+    /// The frame failed to decrypt, i.e. it was changed, replayed or
+    /// reordered.
+    Decrypt,
+    /// Other IO error.
+    Io(std::io::Error),
+}
+
 impl std::fmt::Display for NetError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "Network Error")
+        match self {
+            NetError::Eof => write!(f, "Network Error: end of stream"),
+            NetError::Version(v) => write!(f, "Network Error: unknown frame version {}", v),
+            NetError::Oversize { len, max } => {
+                write!(f, "Network Error: frame of {} bytes exceeds {}", len, max)
+            }
+            NetError::Timeout => write!(f, "Network Error: timed out"),
+            NetError::Handshake => write!(f, "Network Error: handshake failed"),
+            NetError::Decrypt => write!(f, "Network Error: frame failed to decrypt"),
+            NetError::Io(e) => write!(f, "Network Error: {}", e),
+        }
     }
 }
 impl std::error::Error for NetError {}
 
-/// Read from a stream.
-pub fn read_stream(stream: &mut TcpStream) -> Result<Vec<u8>, NetError> {
-    let mut len_buf: [u8; 8] = [0; 8];
-    if stream.read_exact(&mut len_buf).is_err() {
-        return Err(NetError {});
+impl From<std::io::Error> for NetError {
+    fn from(e: std::io::Error) -> Self {
+        match e.kind() {
+            std::io::ErrorKind::UnexpectedEof => NetError::Eof,
+            std::io::ErrorKind::TimedOut | std::io::ErrorKind::WouldBlock => NetError::Timeout,
+            _ => NetError::Io(e),
+        }
+    }
+}
+
+/// Limits on the frames of a stream.
+#[derive(Clone, Copy, Debug)]
+pub struct Limits {
+    /// Largest accepted frame in bytes.
+    pub max_len: usize,
+    /// Deadline to finish a frame once its header has arrived, and to write a
+    /// frame.
+    pub timeout: Option<Duration>,
+}
+
+impl Limits {
+    /// Returns the limits given by the config.
+    pub fn from_config(c: &Config) -> Self {
+        Limits {
+            max_len: c
+                .max_frame_size
+                .unwrap_or_else(|| default_max_frame_size(c)),
+            timeout: c.io_timeout.map(Duration::from_millis),
+        }
     }
-    let len = u64::from_ne_bytes(len_buf);
-    let mut buf = Vec::<u8>::with_capacity(len.try_into().unwrap());
-    if stream.take(len).read_to_end(&mut buf).is_err() {
-        return Err(NetError {});
+
+    /// Returns the limits of the handshake.
+    pub fn handshake(c: &Config) -> Self {
+        Limits {
+            max_len: HANDSHAKE_FRAME_LEN,
+            ..Self::from_config(c)
+        }
     }
-    Ok(buf)
 }
 
-/// Write to a stream.
-pub fn write_stream(stream: &mut TcpStream, data: &[u8]) -> Result<(), NetError> {
-    let len_buf = u64::to_ne_bytes(data.len().try_into().unwrap());
-    if stream.write_all(&len_buf).is_err() {
-        return Err(NetError {});
+/// Returns an upper bound of the frame size for vectors of `client_size *
+/// vector_len` elements, which covers every message of a client and of the
+/// relay.
+pub fn default_max_frame_size(c: &Config) -> usize {
+    // Slot vectors go over the wire packed at `width(q)` bits per element.
+    // The integers of an opening or a recovery still go as hex strings with
+    // a few bytes of overhead, in at most five vectors of `vector_len`.
+    let bound = |params: &ProtocolParams| {
+        let packed = (c.client_size * params.vector_len * width(&params.q) as usize).div_ceil(8);
+        let integer =
+            params.ring_v.order.significant_bits().max(width(&params.q)) as usize / 4 + 32;
+        packed + 5 * params.vector_len * integer
+    };
+    // The relay hands every client the encrypted shares of the self mask
+    // seeds of all clients, with a few bytes of overhead each.
+    let shares = c.client_size * c.client_size * (SHARE_LEN + 16);
+    std::cmp::max(bound(&c.base_params), bound(&c.bulk_params)) + shares + HANDSHAKE_FRAME_LEN
+}
+
+/// Returns the header of a frame with `len` bytes.
+fn encode_header(len: usize) -> [u8; HEADER_LEN] {
+    let mut header = [0u8; HEADER_LEN];
+    header[0] = FRAME_VERSION;
+    header[1..].copy_from_slice(&(len as u64).to_le_bytes());
+    header
+}
+
+/// Returns the length of the frame with `header` if it is within `limits`.
+fn decode_header(header: &[u8; HEADER_LEN], limits: &Limits) -> Result<usize, NetError> {
+    if header[0] != FRAME_VERSION {
+        return Err(NetError::Version(header[0]));
     }
-    if stream.write_all(data).is_err() {
-        return Err(NetError {});
+    let len = u64::from_le_bytes(header[1..].try_into().unwrap());
+    if len > limits.max_len as u64 {
+        return Err(NetError::Oversize {
+            len,
+            max: limits.max_len,
+        });
     }
-    if stream.flush().is_err() {
-        return Err(NetError {});
+    Ok(len as usize)
+}
+
+/// Read from a stream.
+pub fn read_stream(stream: &mut TcpStream, limits: &Limits) -> Result<Vec<u8>, NetError> {
+    // Wait for the next frame as long as it takes.
+    stream.set_read_timeout(None)?;
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header)?;
+    let len = decode_header(&header, limits)?;
+    stream.set_read_timeout(limits.timeout)?;
+    let mut buf = Vec::<u8>::with_capacity(std::cmp::min(len, CHUNK_LEN));
+    stream.take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() < len {
+        return Err(NetError::Eof);
     }
+    Ok(buf)
+}
+
+/// Write to a stream.
+pub fn write_stream(stream: &mut TcpStream, data: &[u8], limits: &Limits) -> Result<(), NetError> {
+    stream.set_write_timeout(limits.timeout)?;
+    stream.write_all(&encode_header(data.len()))?;
+    stream.write_all(data)?;
+    stream.flush()?;
     Ok(())
 }
 
+/// Runs `future` within `timeout` if there is one.
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl std::future::Future<Output = std::io::Result<T>>,
+) -> Result<T, NetError> {
+    match timeout {
+        Some(timeout) => Ok(async_std::future::timeout(timeout, future)
+            .await
+            .map_err(|_| NetError::Timeout)??),
+        None => Ok(future.await?),
+    }
+}
+
 /// Read from a stream.
 pub async fn async_read_stream(
     stream: &mut async_std::net::TcpStream,
+    limits: &Limits,
 ) -> Result<Vec<u8>, NetError> {
-    let mut len_buf: [u8; 8] = [0; 8];
-    if stream.read_exact(&mut len_buf).await.is_err() {
-        return Err(NetError {});
-    }
-    let len = u64::from_ne_bytes(len_buf);
-    let mut buf = Vec::<u8>::with_capacity(len.try_into().unwrap());
-    if stream.take(len).read_to_end(&mut buf).await.is_err() {
-        return Err(NetError {});
+    // Wait for the next frame as long as it takes.
+    let mut header = [0u8; HEADER_LEN];
+    stream.read_exact(&mut header).await?;
+    let len = decode_header(&header, limits)?;
+    let mut buf = Vec::<u8>::with_capacity(std::cmp::min(len, CHUNK_LEN));
+    with_timeout(
+        limits.timeout,
+        stream.take(len as u64).read_to_end(&mut buf),
+    )
+    .await?;
+    if buf.len() < len {
+        return Err(NetError::Eof);
     }
     Ok(buf)
 }
@@ -63,16 +208,126 @@ pub async fn async_read_stream(
 pub async fn async_write_stream(
     stream: &mut async_std::net::TcpStream,
     data: &[u8],
+    limits: &Limits,
 ) -> Result<(), NetError> {
-    let len_buf = u64::to_ne_bytes(data.len().try_into().unwrap());
-    if stream.write_all(&len_buf).await.is_err() {
-        return Err(NetError {});
+    with_timeout(limits.timeout, async {
+        stream.write_all(&encode_header(data.len())).await?;
+        stream.write_all(data).await?;
+        stream.flush().await
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::guard::generate_keys;
+    use crate::message::{
+        ClientBaseMessage, ClientRecoveryMessage, Message, Phase, ServerRecoveryRequestMessage,
+    };
+    use crate::net::{
+        default_max_frame_size, read_stream, write_stream, Limits, NetError, FRAME_VERSION,
+    };
+    use crate::packed::{width, PackedVec};
+    use crate::recovery::deal_self_shares;
+    use crate::rng::Rng;
+    use rug::Integer;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::time::Duration;
+
+    /// Returns both ends of a local connection.
+    fn pair(listener: &TcpListener) -> (TcpStream, TcpStream) {
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        (client, listener.accept().unwrap().0)
     }
-    if stream.write_all(data).await.is_err() {
-        return Err(NetError {});
+
+    #[test]
+    fn frame_test() {
+        let limits = Limits {
+            max_len: 16,
+            timeout: Some(Duration::from_millis(100)),
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let (mut client, mut server) = pair(&listener);
+        write_stream(&mut client, b"hello", &limits).unwrap();
+        assert_eq!(read_stream(&mut server, &limits).unwrap(), b"hello");
+        // A frame over the limit is refused before its body is read.
+        write_stream(&mut client, &[0; 17], &limits).unwrap();
+        assert!(matches!(
+            read_stream(&mut server, &limits),
+            Err(NetError::Oversize { len: 17, max: 16 })
+        ));
+        // A frame whose body never arrives times out.
+        let (mut client, mut server) = pair(&listener);
+        client
+            .write_all(&[FRAME_VERSION, 4, 0, 0, 0, 0, 0, 0, 0])
+            .unwrap();
+        assert!(matches!(
+            read_stream(&mut server, &limits),
+            Err(NetError::Timeout)
+        ));
+        // A frame of another version is refused.
+        let (mut client, mut server) = pair(&listener);
+        client.write_all(&[FRAME_VERSION + 1; 9]).unwrap();
+        assert!(matches!(
+            read_stream(&mut server, &limits),
+            Err(NetError::Version(v)) if v == FRAME_VERSION + 1
+        ));
+        // A closed stream ends with EOF.
+        let (client, mut server) = pair(&listener);
+        drop(client);
+        assert!(matches!(
+            read_stream(&mut server, &limits),
+            Err(NetError::Eof)
+        ));
     }
-    if stream.flush().await.is_err() {
-        return Err(NetError {});
+
+    #[test]
+    fn frame_size_test() {
+        let mut c = Config::new("127.0.0.1:8001".parse().unwrap(), 5, 1, 1);
+        c.do_blame = true;
+        let max = default_max_frame_size(&c);
+        let params = &c.base_params;
+        let mut rng = Rng::from_seed(0, 0);
+        let (_, seeds) = generate_keys(c.client_size, params, &mut rng);
+        let shares: Vec<_> = (0..c.client_size)
+            .map(|i| deal_self_shares(i, &rng.bytes(32), &seeds[i], 1, Phase::Base, 0))
+            .collect();
+        let full = |q: &Integer| Integer::from(q - 1);
+        let base = Message::ClientBaseMessage(ClientBaseMessage {
+            round: 1,
+            attempt: 0,
+            nid: 0,
+            slot_messages: PackedVec::pack(
+                &vec![full(&params.q); params.vector_len],
+                width(&params.q),
+            ),
+            self_shares: Some(shares[0].clone()),
+        });
+        let recovery = Message::ClientRecoveryMessage(ClientRecoveryMessage {
+            round: 1,
+            attempt: 0,
+            phase: Phase::Base,
+            nid: 0,
+            recovery: vec![full(&params.q); params.vector_len],
+            shares: (0..c.client_size)
+                .map(|i| (i, full(&Integer::from(Integer::u_pow_u(2, 255)))))
+                .collect(),
+        });
+        let request = Message::ServerRecoveryRequestMessage(ServerRecoveryRequestMessage {
+            round: 1,
+            attempt: 0,
+            phase: Phase::Base,
+            missing: Vec::new(),
+            shares: shares
+                .into_iter()
+                .enumerate()
+                .map(|(i, shares)| (i, shares.encrypted))
+                .collect(),
+        });
+        for message in [base, recovery, request] {
+            assert!(bincode::serialize(&message).unwrap().len() <= max);
+        }
     }
-    Ok(())
 }
@@ -0,0 +1,160 @@
+use rug::{integer::Order, Integer};
+use serde::de::{Deserializer, Error, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+// Compact encoding of slot vectors. Every element is below `q`, so it takes
+// exactly `ceil(log2 q)` bits on the wire, packed back to back.
+
+/// Returns the number of bits of an element below `q`, i.e. `ceil(log2 q)`.
+pub fn width(q: &Integer) -> u32 {
+    Integer::from(q - 1).significant_bits()
+}
+
+/// Returns the number of bytes of `len` elements of `width` bits.
+fn byte_len(len: usize, width: u32) -> usize {
+    (len * width as usize).div_ceil(8)
+}
+
+/// Vector of non-negative integers packed into a fixed number of bits each,
+/// least significant bit first. Elements are decoded in place on access.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PackedVec {
+    /// Bits per element.
+    width: u32,
+    /// Number of elements.
+    len: usize,
+    /// Packed elements.
+    #[serde(
+        serialize_with = "serialize_bytes",
+        deserialize_with = "deserialize_bytes"
+    )]
+    bytes: Vec<u8>,
+}
+
+/// Serializes the packed elements as one byte string.
+fn serialize_bytes<S: Serializer>(bytes: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_bytes(bytes)
+}
+
+/// Deserializes the packed elements from one byte string.
+fn deserialize_bytes<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+    /// Visitor of a byte string.
+    struct BytesVisitor;
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Vec<u8>;
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a byte string")
+        }
+        fn visit_bytes<E: Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+            Ok(v.to_vec())
+        }
+        fn visit_byte_buf<E: Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+            Ok(v)
+        }
+    }
+    deserializer.deserialize_byte_buf(BytesVisitor)
+}
+
+impl PackedVec {
+    /// Packs `values`, each of which must fit into `width` bits.
+    pub fn pack(values: &[Integer], width: u32) -> Self {
+        let mut bytes = Vec::with_capacity(byte_len(values.len(), width));
+        let mut acc: u32 = 0;
+        let mut pending: u32 = 0;
+        for value in values {
+            assert!(*value >= 0 && value.significant_bits() <= width);
+            let digits = value.to_digits::<u8>(Order::Lsf);
+            for k in 0..width.div_ceil(8) {
+                let bits = std::cmp::min(8, width - 8 * k);
+                let byte = digits.get(k as usize).copied().unwrap_or(0) as u32;
+                acc |= (byte & ((1 << bits) - 1)) << pending;
+                pending += bits;
+                while pending >= 8 {
+                    bytes.push(acc as u8);
+                    acc >>= 8;
+                    pending -= 8;
+                }
+            }
+        }
+        if pending > 0 {
+            bytes.push(acc as u8);
+        }
+        PackedVec {
+            width,
+            len: values.len(),
+            bytes,
+        }
+    }
+
+    /// Returns the number of elements.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether there are no elements.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Checks that there are `len` elements of `width` bits, so that every
+    /// element can be decoded.
+    pub fn is_shaped(&self, len: usize, width: u32) -> bool {
+        self.len == len && self.width == width && self.bytes.len() == byte_len(len, width)
+    }
+
+    /// Decodes element `i`.
+    pub fn get(&self, i: usize) -> Integer {
+        assert!(i < self.len);
+        let offset = i * self.width as usize;
+        let start = offset / 8;
+        let shift = (offset % 8) as u32;
+        let end = start + (shift + self.width).div_ceil(8) as usize;
+        (Integer::from_digits(&self.bytes[start..end], Order::Lsf) >> shift).keep_bits(self.width)
+    }
+
+    /// Decodes the elements in `range`.
+    pub fn iter_range(&self, range: std::ops::Range<usize>) -> impl Iterator<Item = Integer> + '_ {
+        range.map(|i| self.get(i))
+    }
+
+    /// Decodes all elements.
+    pub fn unpack(&self) -> Vec<Integer> {
+        self.iter_range(0..self.len).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{default_base_params, default_bulk_params};
+    use crate::packed::{width, PackedVec};
+    use crate::rng::Rng;
+    use rug::Integer;
+
+    #[test]
+    fn pack_test() {
+        let mut rng = Rng::from_seed(0, 0);
+        for q in [
+            default_base_params().q,
+            default_bulk_params().q,
+            Integer::from(2),
+            Integer::from(257),
+        ] {
+            let w = width(&q);
+            let mut values: Vec<Integer> = (0..37).map(|_| rng.below(&q)).collect();
+            values[0] = Integer::from(&q - 1);
+            values[1] = Integer::from(0);
+            let packed = PackedVec::pack(&values, w);
+            assert!(packed.is_shaped(values.len(), w));
+            assert_eq!(packed.unpack(), values);
+            let decoded: PackedVec =
+                bincode::deserialize(&bincode::serialize(&packed).unwrap()).unwrap();
+            assert_eq!(decoded, packed);
+            // The encoding takes `ceil(log2 q)` bits per element plus a header.
+            assert!(
+                bincode::serialize(&packed).unwrap().len() <= (37 * w as usize).div_ceil(8) + 24
+            );
+        }
+        assert_eq!(width(&default_base_params().q), 84);
+        assert_eq!(width(&default_bulk_params().q), 256);
+    }
+}
@@ -0,0 +1,214 @@
+use crate::config::{default_error_scale, NttField, ProtocolParams};
+use crate::message::Phase;
+use openssl::ec::EcGroup;
+use openssl::nid::Nid;
+use rug::integer::IsPrime;
+use rug::ops::Pow;
+use rug::Integer;
+
+// Generator of protocol parameters. `p` is the largest prime of the requested
+// number of bits. The error scale is the smallest power of ten, at least the
+// default, that exceeds twice the error bound. `q` is the largest prime that
+// leaves room for the scaled sum of all messages and the error of the PRF.
+// The NTT field has a prime order `k * 2^m + 1` with odd `k`, at least
+// `2^margin_bits` times larger than `q`, and its root is `a^k` for the
+// smallest quadratic non-residue `a`, which has order `2^m`. The ECC group is
+// the smallest supported curve whose order exceeds the order of the NTT field.
+
+/// Curves to choose the ECC group from, from the smallest to the largest.
+const CURVES: [Nid; 4] = [
+    Nid::SECP256K1,
+    Nid::SECP384R1,
+    Nid::SECP521R1,
+    Nid::SECT571K1,
+];
+
+/// Number of Miller-Rabin rounds to test a prime with.
+const PRIME_REPS: u32 = 30;
+
+/// What to generate parameters for.
+#[derive(Debug, Clone)]
+pub struct ParamsRequest {
+    /// Round to generate the parameters of.
+    pub phase: Phase,
+    /// Bits of an ID in the base round, or of a slot in the bulk round.
+    pub bits: usize,
+    /// Number of clients.
+    pub client_size: usize,
+    /// Length of the vector, a power of two.
+    pub vector_len: usize,
+    /// Bits by which the order of the NTT field exceeds `q`.
+    pub margin_bits: usize,
+    /// Largest error of the PRF in an element of the sum of all messages, see
+    /// `Config::error_bound`.
+    pub error_bound: u64,
+}
+
+/// Reason no parameters can be generated.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParamsError {
+    /// `bits` is below 2, so there is no prime of that size.
+    Bits(usize),
+    /// The vector length is not a power of two.
+    VectorLen(usize),
+    /// No supported curve has an order above the order of the NTT field of
+    /// the given number of bits.
+    NoGroup(u32),
+}
+
+impl std::fmt::Display for ParamsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParamsError::Bits(bits) => write!(f, "no prime of {} bits", bits),
+            ParamsError::VectorLen(len) => write!(f, "vector length {} is not a power of two", len),
+            ParamsError::NoGroup(bits) => {
+                write!(f, "no supported curve has an order above {} bits", bits)
+            }
+        }
+    }
+}
+impl std::error::Error for ParamsError {}
+
+/// Returns the largest prime below `2^bits`.
+pub fn largest_prime_below(bits: u32) -> Integer {
+    let mut n: Integer = Integer::from(2).pow(bits) - 1;
+    while n.is_probably_prime(PRIME_REPS) == IsPrime::No {
+        n -= 2;
+    }
+    n
+}
+
+/// Returns the smallest prime `k * 2^m + 1` with odd `k`, and `k`.
+pub fn ntt_prime(m: u32) -> (Integer, Integer) {
+    let step = Integer::from(2).pow(m);
+    let mut k = Integer::from(1);
+    loop {
+        let order: Integer = Integer::from(&k * &step) + 1;
+        if order.is_probably_prime(PRIME_REPS) != IsPrime::No {
+            return (order, k);
+        }
+        k += 2;
+    }
+}
+
+/// Returns a root of order `(order - 1) / k` modulo the prime `order`, where
+/// `(order - 1) / k` is a power of two, i.e. `a^k` for the smallest quadratic
+/// non-residue `a`.
+pub fn find_root(order: &Integer, k: &Integer) -> Integer {
+    let mut a = Integer::from(2);
+    while a.jacobi(order) != -1 {
+        a += 1;
+    }
+    a.pow_mod(k, order).unwrap()
+}
+
+/// Returns the smallest supported curve whose order exceeds `bound`.
+fn find_group(bound: &Integer) -> Option<Nid> {
+    CURVES.into_iter().find(|nid| {
+        let group = EcGroup::from_curve_name(*nid).unwrap();
+        group.order_bits() > bound.significant_bits()
+    })
+}
+
+/// Generates the parameters of `request`.
+pub fn generate(request: &ParamsRequest) -> Result<ProtocolParams, ParamsError> {
+    if request.bits < 2 {
+        return Err(ParamsError::Bits(request.bits));
+    }
+    if !request.vector_len.is_power_of_two() {
+        return Err(ParamsError::VectorLen(request.vector_len));
+    }
+    let mut error_scale = default_error_scale();
+    while error_scale <= 2 * request.error_bound {
+        error_scale *= 10;
+    }
+    let p = largest_prime_below(request.bits as u32);
+    // In the base round, every client adds power sums below `p`. In the bulk
+    // round, one client writes each slot.
+    let max_sum = match request.phase {
+        Phase::Base => Integer::from(request.client_size) * &p,
+        Phase::Bulk => Integer::from(2).pow(request.bits as u32),
+    };
+    let bound = max_sum * error_scale + request.error_bound;
+    let mut q = largest_prime_below(bound.significant_bits());
+    if q <= bound {
+        q = largest_prime_below(bound.significant_bits() + 1);
+    }
+    let m = std::cmp::max(
+        q.significant_bits() + request.margin_bits as u32,
+        request.vector_len.trailing_zeros(),
+    );
+    let (order, k) = ntt_prime(m);
+    let root = find_root(&order, &k);
+    let group_nid = find_group(&order)
+        .ok_or(ParamsError::NoGroup(order.significant_bits()))?
+        .as_raw();
+    Ok(ProtocolParams {
+        p,
+        q,
+        ring_v: NttField {
+            order,
+            root,
+            scale: Integer::from(2).pow(m),
+        },
+        vector_len: request.vector_len,
+        bits: request.bits,
+        error_scale,
+        group_nid,
+        group: None,
+        h: None,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+    use crate::message::Phase;
+    use crate::params::{
+        find_root, generate, largest_prime_below, ntt_prime, ParamsError, ParamsRequest,
+    };
+    use rug::ops::Pow;
+    use rug::Integer;
+
+    #[test]
+    fn params_test() {
+        assert_eq!(largest_prime_below(64), Integer::from(2).pow(64) - 59);
+        assert_eq!(largest_prime_below(226), Integer::from(2).pow(226) - 5);
+        let (order, k) = ntt_prime(290);
+        assert_eq!(k, 7);
+        assert_eq!(order, Integer::from(7) * Integer::from(2).pow(290) + 1);
+        assert_eq!(find_root(&order, &k), 2187);
+
+        let json = r#"{"server_addr": "127.0.0.1:8001", "client_size": 8, "round": 2, "slot_per_round": 2}"#;
+        let mut c: Config = serde_json::from_str(json).unwrap();
+        let mut request = ParamsRequest {
+            phase: Phase::Base,
+            bits: 32,
+            client_size: c.client_size,
+            vector_len: 16,
+            margin_bits: 8,
+            error_bound: c.client_size as u64 + 1,
+        };
+        c.base_params = generate(&request).unwrap();
+        request.phase = Phase::Bulk;
+        request.bits = 300;
+        c.bulk_params = generate(&request).unwrap();
+        assert_eq!(c.validate(), Ok(()));
+        c.bulk_params.init_group().unwrap();
+        assert!(c.bulk_params.group_nid != c.base_params.group_nid);
+
+        request.vector_len = 12;
+        assert_eq!(generate(&request).err(), Some(ParamsError::VectorLen(12)));
+        // A larger organisation needs a larger scale.
+        c.client_size = 600;
+        request.vector_len = 2048;
+        request.client_size = c.client_size;
+        request.error_bound = c.error_bound();
+        c.bulk_params = generate(&request).unwrap();
+        assert_eq!(c.bulk_params.error_scale, 10000);
+        request.phase = Phase::Base;
+        request.bits = 32;
+        c.base_params = generate(&request).unwrap();
+        assert_eq!(c.validate(), Ok(()));
+    }
+}
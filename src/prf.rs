@@ -1,25 +1,143 @@
+use crate::config::ProtocolParams;
 use rayon::prelude::*;
 use rug::Integer;
 use rug_fft::{bit_rev_radix_2_intt, bit_rev_radix_2_ntt};
 
-/// Compute PRF on demand.
+/// Masks of one key. The masks of the first attempt of each round are either
+/// computed up front, which is faster during the rounds, or on demand, which
+/// keeps only the key in memory.
+pub struct Masks {
+    /// Key, processed by NTT.
+    key_ntt: Vec<Integer>,
+    /// Mask of the first attempt of each round, empty if computed on demand.
+    precomputed: Vec<Vec<Integer>>,
+}
+
+impl Masks {
+    /// Readies the masks of `key` for rounds 1 to `rounds`, computing them up
+    /// front if `precompute` is set.
+    pub fn new(params: &ProtocolParams, key: &[Integer], rounds: usize, precompute: bool) -> Masks {
+        let key_ntt = ntt(params, key);
+        let precomputed = if precompute {
+            (1..rounds + 1)
+                .map(|round| {
+                    info!("Computing the mask of round {}...", round);
+                    compute(params, &key_ntt, round, 0)
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Masks {
+            key_ntt,
+            precomputed,
+        }
+    }
+
+    /// Readies the masks of the relay, whose key is all ones.
+    pub fn relay(params: &ProtocolParams, rounds: usize, precompute: bool) -> Masks {
+        Masks::new(
+            params,
+            &vec![Integer::from(1); params.vector_len],
+            rounds,
+            precompute,
+        )
+    }
+
+    /// Returns the mask for `attempt` of `round`.
+    pub fn get(&self, params: &ProtocolParams, round: usize, attempt: usize) -> Vec<Integer> {
+        match round.checked_sub(1).and_then(|r| self.precomputed.get(r)) {
+            Some(mask) if attempt == 0 => mask.clone(),
+            _ => compute(params, &self.key_ntt, round, attempt),
+        }
+    }
+
+    /// Returns the product of the key and the hash for `attempt` of `round`,
+    /// before scaling.
+    pub fn product(&self, params: &ProtocolParams, round: usize, attempt: usize) -> Vec<Integer> {
+        compute_product(params, &self.key_ntt, round, attempt)
+    }
+}
+
+/// Processes `value` by NTT.
+pub fn ntt(params: &ProtocolParams, value: &[Integer]) -> Vec<Integer> {
+    let mut value = value.to_vec();
+    bit_rev_radix_2_ntt(
+        &mut value,
+        &params.ring_v.order,
+        &params.ring_v.root_of_unity(params.vector_len),
+    );
+    value
+}
+
+/// Compute PRF on demand for `attempt` of `round` from a key, already
+/// processed by NTT.
 pub fn compute(
-    params: &crate::config::ProtocolParams,
-    prf: &crate::guard::SetupValues,
+    params: &ProtocolParams,
+    key_ntt: &[Integer],
+    round: usize,
+    attempt: usize,
+) -> Vec<Integer> {
+    scale(params, &compute_product(params, key_ntt, round, attempt))
+}
+
+/// Compute the product of a key, already processed by NTT, and the hash for
+/// `attempt` of `round`, before scaling.
+pub fn compute_product(
+    params: &ProtocolParams,
+    value_ntt: &[Integer],
+    round: usize,
+    attempt: usize,
 ) -> Vec<Integer> {
     let root_of_unity = params.ring_v.root_of_unity(params.vector_len);
-    let mut hash_vector = crate::guard::compute_hash(0, params.vector_len, &params.ring_v.order);
+    let mut hash_vector =
+        crate::guard::compute_hash(round, attempt, params.vector_len, &params.ring_v.order);
     bit_rev_radix_2_ntt(&mut hash_vector, &params.ring_v.order, &root_of_unity);
-    let mut product = prf
-        .share
-        .value_ntt
+    let mut product = value_ntt
         .par_iter()
         .zip(hash_vector)
         .map(|(a, b)| a * b)
         .collect::<Vec<_>>();
     bit_rev_radix_2_intt(&mut product, &params.ring_v.order, &root_of_unity);
+    product
+}
+
+/// Scale the product down to `q`, rounding away the lower bits.
+pub fn scale(params: &ProtocolParams, product: &[Integer]) -> Vec<Integer> {
     product
         .par_iter()
         .map(|i| Integer::from(i * &params.q) / &params.ring_v.order)
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::default_base_params;
+    use crate::guard::generate_sum_shares;
+    use crate::prf::Masks;
+    use crate::rng::Rng;
+    use rug::Integer;
+    #[test]
+    fn round_test() {
+        let mut params = default_base_params();
+        params.vector_len = 16;
+        let mut rng = Rng::from_seed(0, 0);
+        let shares: Vec<Integer> = (0..params.vector_len)
+            .map(|_| {
+                generate_sum_shares(2, &params.ring_v.order, &Integer::from(1), &mut rng)[0].clone()
+            })
+            .collect();
+        let precomputed = Masks::new(&params, &shares, 2, true);
+        let on_demand = Masks::new(&params, &shares, 2, false);
+        let first = precomputed.get(&params, 1, 0);
+        let second = precomputed.get(&params, 2, 0);
+        assert_ne!(first, second);
+        assert_eq!(on_demand.get(&params, 1, 0), first);
+        assert_eq!(on_demand.get(&params, 2, 0), second);
+        assert_ne!(on_demand.get(&params, 1, 1), first);
+        assert_eq!(precomputed.get(&params, 1, 1), on_demand.get(&params, 1, 1));
+        // Rounds beyond the precomputed ones are computed on demand.
+        assert_eq!(precomputed.get(&params, 3, 0), on_demand.get(&params, 3, 0));
+        assert_eq!(precomputed.get(&params, 0, 0), on_demand.get(&params, 0, 0));
+    }
+}
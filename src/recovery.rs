@@ -0,0 +1,473 @@
+use crate::config::ProtocolParams;
+use crate::guard::{pairwise_sum, RelaySetup};
+use crate::message::{Phase, SelfShares};
+use crate::prf::{compute_product, scale, Masks};
+use rayon::prelude::*;
+use rug::{integer::Order, Integer};
+use rug_fft::bit_rev_radix_2_ntt;
+use sha2::{Digest, Sha512};
+use std::collections::BTreeMap;
+
+// Double masking: with `Config::self_masks`, each client adds a self mask to
+// the masks derived from its key, drawn anew for each phase of each round
+// from a seed it deals Shamir shares of to the other members. For each
+// client, the relay gets either the shares of its seed if the client is
+// present, or the PRF of the vectors the others share with it if it is
+// missing, but never both in the same phase, since a client answers one
+// recovery request per phase. Unmasking a single client takes both.
+
+/// Length of an encoded share of a self mask seed in bytes.
+pub const SHARE_LEN: usize = 32;
+
+/// Domain separation string for the seed of a self mask and the other
+/// coefficients of its polynomial.
+const COEFFICIENT_DOMAIN: &[u8] = b"OrgAn self mask coefficient";
+
+/// Domain separation string for the expansion of a self mask seed.
+const MASK_DOMAIN: &[u8] = b"OrgAn self mask";
+
+/// Domain separation string for the encryption of a share.
+const KEY_DOMAIN: &[u8] = b"OrgAn self mask share key";
+
+/// Domain separation string for the digest of a share.
+const DIGEST_DOMAIN: &[u8] = b"OrgAn self mask share digest";
+
+/// Returns the prime field of the shares, of order 2^255 - 19.
+fn share_field() -> Integer {
+    (Integer::from(1) << 255) - 19
+}
+
+/// Returns the number of shares that reconstruct a self mask seed among
+/// `members` members, a majority of them. The relay cannot unmask a client
+/// unless as many clients collude with it.
+pub fn recovery_threshold(members: usize) -> usize {
+    members / 2 + 1
+}
+
+/// Returns the members `nid` shares a seed with, and itself.
+pub fn members_of(nid: usize, seeds: &[Option<Vec<u8>>]) -> Vec<usize> {
+    (0..seeds.len())
+        .filter(|j| *j == nid || seeds[*j].is_some())
+        .collect()
+}
+
+/// Hashes `domain`, the phase of `attempt` of `round` and `parts`.
+fn hash(domain: &[u8], round: usize, phase: Phase, attempt: usize, parts: &[&[u8]]) -> Vec<u8> {
+    let mut hasher = Sha512::new();
+    hasher.update(domain);
+    hasher.update((round as u64).to_le_bytes());
+    hasher.update([phase as u8]);
+    hasher.update((attempt as u64).to_le_bytes());
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().to_vec()
+}
+
+/// Encodes a share into `SHARE_LEN` bytes.
+fn encode(x: &Integer) -> Vec<u8> {
+    let mut bytes = x.to_digits::<u8>(Order::Lsf);
+    bytes.resize(SHARE_LEN, 0);
+    bytes
+}
+
+/// Returns the coefficient `k` of the polynomial behind the self mask seed of
+/// a phase, derived from the `secret` of the client. The coefficient 0 is the
+/// seed.
+fn coefficient(secret: &[u8], round: usize, phase: Phase, attempt: usize, k: usize) -> Integer {
+    let digest = hash(
+        COEFFICIENT_DOMAIN,
+        round,
+        phase,
+        attempt,
+        &[secret, &(k as u64).to_le_bytes()],
+    );
+    Integer::from_digits(&digest, Order::Lsf) % share_field()
+}
+
+/// Returns the seed of the self mask of a phase, derived from the `secret` of
+/// the client.
+pub fn self_seed(secret: &[u8], round: usize, phase: Phase, attempt: usize) -> Integer {
+    coefficient(secret, round, phase, attempt, 0)
+}
+
+/// Returns the share of `member` of the self mask seed of a phase, among
+/// `threshold` needed ones.
+fn evaluate(
+    secret: &[u8],
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+    threshold: usize,
+    member: usize,
+) -> Integer {
+    let x = Integer::from(member + 1);
+    (0..threshold).rev().fold(Integer::new(), |acc, k| {
+        (acc * &x + coefficient(secret, round, phase, attempt, k)) % share_field()
+    })
+}
+
+/// Returns the key stream encrypting the share `dealer` deals to `recipient`.
+fn share_key(
+    seed: &[u8],
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+    dealer: usize,
+    recipient: usize,
+) -> Vec<u8> {
+    let mut key = hash(
+        KEY_DOMAIN,
+        round,
+        phase,
+        attempt,
+        &[
+            seed,
+            &(dealer as u64).to_le_bytes(),
+            &(recipient as u64).to_le_bytes(),
+        ],
+    );
+    key.truncate(SHARE_LEN);
+    key
+}
+
+/// Returns the digest of the `share` `dealer` deals to `recipient`.
+fn share_digest(
+    share: &Integer,
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+    dealer: usize,
+    recipient: usize,
+) -> Vec<u8> {
+    hash(
+        DIGEST_DOMAIN,
+        round,
+        phase,
+        attempt,
+        &[
+            &encode(share),
+            &(dealer as u64).to_le_bytes(),
+            &(recipient as u64).to_le_bytes(),
+        ],
+    )
+}
+
+/// Expands a self mask `seed` into `len` elements modulo `q`.
+pub fn self_mask(params: &ProtocolParams, seed: &Integer, len: usize) -> Vec<Integer> {
+    let seed = encode(seed);
+    (0..len)
+        .into_par_iter()
+        .map(|i| {
+            let mut hasher = Sha512::new();
+            hasher.update(MASK_DOMAIN);
+            hasher.update(&seed);
+            hasher.update((i as u64).to_le_bytes());
+            Integer::from_digits(&hasher.finalize(), Order::Lsf) % &params.q
+        })
+        .collect()
+}
+
+/// Deals the shares of the self mask seed of `nid` for `attempt` of `round`
+/// to the members it shares `seeds` with.
+pub fn deal_self_shares(
+    nid: usize,
+    secret: &[u8],
+    seeds: &[Option<Vec<u8>>],
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+) -> SelfShares {
+    let members = members_of(nid, seeds);
+    let threshold = recovery_threshold(members.len());
+    let mut encrypted = BTreeMap::new();
+    let mut digests = BTreeMap::new();
+    for member in members {
+        let share = evaluate(secret, round, phase, attempt, threshold, member);
+        digests.insert(
+            member,
+            share_digest(&share, round, phase, attempt, nid, member),
+        );
+        if let Some(seed) = &seeds[member] {
+            let key = share_key(seed, round, phase, attempt, nid, member);
+            let bytes = encode(&share).iter().zip(key).map(|(a, b)| a ^ b).collect();
+            encrypted.insert(member, bytes);
+        }
+    }
+    SelfShares { encrypted, digests }
+}
+
+/// Returns the share `nid` holds of the self mask seed of `dealer` for
+/// `attempt` of `round`, decrypted from `encrypted`, or its own share if it
+/// is the dealer. Returns `None` if `nid` shares no seed with the dealer or
+/// the share does not decode.
+#[allow(clippy::too_many_arguments)]
+pub fn open_self_share(
+    nid: usize,
+    secret: &[u8],
+    seeds: &[Option<Vec<u8>>],
+    dealer: usize,
+    encrypted: &[u8],
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+) -> Option<Integer> {
+    if dealer == nid {
+        let threshold = recovery_threshold(members_of(nid, seeds).len());
+        return Some(evaluate(secret, round, phase, attempt, threshold, nid));
+    }
+    let seed = seeds.get(dealer)?.as_ref()?;
+    if encrypted.len() != SHARE_LEN {
+        return None;
+    }
+    let key = share_key(seed, round, phase, attempt, dealer, nid);
+    let bytes: Vec<u8> = encrypted.iter().zip(key).map(|(a, b)| a ^ b).collect();
+    Some(Integer::from_digits(&bytes, Order::Lsf))
+}
+
+/// Checks that `shares` of the self mask seed of `nid` are dealt to exactly
+/// the `members`.
+pub fn is_well_formed(shares: &SelfShares, nid: usize, members: &[usize]) -> bool {
+    shares.digests.keys().eq(members.iter())
+        && shares.digests.values().all(|digest| digest.len() == 64)
+        && shares
+            .encrypted
+            .keys()
+            .eq(members.iter().filter(|member| **member != nid))
+        && shares
+            .encrypted
+            .values()
+            .all(|share| share.len() == SHARE_LEN)
+}
+
+/// Reconstructs the self mask seed of `dealer` for `attempt` of `round` from
+/// the `revealed` shares by member. Only the shares that match the `digests`
+/// of the dealer count. Returns `None` if fewer than `threshold` do.
+pub fn reconstruct_seed(
+    dealer: usize,
+    digests: &BTreeMap<usize, Vec<u8>>,
+    revealed: &BTreeMap<usize, &Integer>,
+    threshold: usize,
+    round: usize,
+    phase: Phase,
+    attempt: usize,
+) -> Option<Integer> {
+    let field = share_field();
+    let valid: Vec<(Integer, &Integer)> = revealed
+        .iter()
+        .filter(|(member, share)| {
+            let (member, share): (usize, &Integer) = (**member, share);
+            *share >= 0
+                && *share < field
+                && digests.get(&member)
+                    == Some(&share_digest(share, round, phase, attempt, dealer, member))
+        })
+        .map(|(member, share)| (Integer::from(member + 1), *share))
+        .take(threshold)
+        .collect();
+    if valid.len() < threshold {
+        return None;
+    }
+    // Interpolate the polynomial at 0. The points are distinct and below
+    // the order of the field, so every denominator is invertible.
+    let mut seed = Integer::new();
+    for (x, y) in valid.iter() {
+        let (num, den) = valid.iter().filter(|(other, _)| other != x).fold(
+            (Integer::from(1), Integer::from(1)),
+            |(num, den), (other, _)| {
+                (
+                    num * other % &field,
+                    den * Integer::from(other - x) % &field,
+                )
+            },
+        );
+        let den = den.invert(&field).ok()?;
+        seed = (seed + Integer::from(*y) * num % &field * den) % &field;
+    }
+    Some((seed + &field) % &field)
+}
+
+/// Computes the recovery `nid` sends for the `missing` clients, i.e. the PRF
+/// for `attempt` of `round` of the vectors it shares with them. Only the scaled
+/// value is revealed, so the pairwise vectors stay hidden in later rounds.
+pub fn client_recovery(
+    params: &ProtocolParams,
+    nid: usize,
+    seeds: &[Option<Vec<u8>>],
+    missing: &[usize],
+    round: usize,
+    attempt: usize,
+) -> Vec<Integer> {
+    let mut value = pairwise_sum(params, nid, seeds, missing);
+    bit_rev_radix_2_ntt(
+        &mut value,
+        &params.ring_v.order,
+        &params.ring_v.root_of_unity(params.vector_len),
+    );
+    scale(params, &compute_product(params, &value, round, attempt))
+}
+
+/// Computes the sum of the masks of the `present` clients for `attempt` of
+/// `round` from their offsets and the recoveries they sent. `masks` holds the
+/// masks of the relay.
+pub fn relay_mask(
+    params: &ProtocolParams,
+    relay: &RelaySetup,
+    masks: &Masks,
+    round: usize,
+    attempt: usize,
+    present: &[usize],
+    recoveries: &[Vec<Integer>],
+) -> Vec<Integer> {
+    let order = &params.ring_v.order;
+    let offset = present.iter().fold(Integer::from(0), |acc, i| {
+        (acc + &relay.offsets[*i]) % order
+    });
+    // The key of the relay is all ones, so its product is the PRF of 1.
+    let product: Vec<Integer> = masks
+        .product(params, round, attempt)
+        .into_iter()
+        .map(|x| x * &offset % order)
+        .collect();
+    recoveries
+        .iter()
+        .fold(scale(params, &product), |acc, recovery| {
+            acc.into_iter()
+                .zip(recovery.iter())
+                .map(|(a, b)| (a + b) % &params.q)
+                .collect()
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::default_base_params;
+    use crate::guard::{derive_key, generate_keys, RelaySetup, SEED_LEN};
+    use crate::message::Phase;
+    use crate::prf::Masks;
+    use crate::recovery::*;
+    use crate::rng::Rng;
+    use rug::Integer;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn recovery_test() {
+        let mut params = default_base_params();
+        params.vector_len = 16;
+        let n = 4;
+        let mut rng = Rng::from_seed(0, 0);
+        let (offsets, seeds) = generate_keys(n, &params, &mut rng);
+        let keys: Vec<Vec<Integer>> = (0..n)
+            .map(|i| derive_key(&params, i, &offsets[i], &seeds[i]))
+            .collect();
+        for k in 0..params.vector_len {
+            let sum = keys.iter().fold(Integer::from(0), |acc, key| acc + &key[k]);
+            assert_eq!(sum % &params.ring_v.order, 1);
+        }
+        let masks: Vec<_> = keys
+            .iter()
+            .map(|key| Masks::new(&params, key, 1, false))
+            .collect();
+        let relay = RelaySetup {
+            members: (0..n).collect(),
+            offsets,
+            commitments: None,
+        };
+        let relay_masks = Masks::relay(&params, 1, false);
+        let present = [0, 2];
+        let missing = [1, 3];
+        for attempt in [0, 1] {
+            let recoveries: Vec<_> = present
+                .iter()
+                .map(|i| client_recovery(&params, *i, &seeds[*i], &missing, 1, attempt))
+                .collect();
+            let mask = relay_mask(
+                &params,
+                &relay,
+                &relay_masks,
+                1,
+                attempt,
+                &present,
+                &recoveries,
+            );
+            let scaled: Vec<_> = present
+                .iter()
+                .map(|i| masks[*i].get(&params, 1, attempt))
+                .collect();
+            for (k, m) in mask.iter().enumerate() {
+                let sum = scaled.iter().fold(Integer::from(0), |acc, v| acc + &v[k]);
+                let diff = Integer::from(&sum - m) % &params.q;
+                let diff = std::cmp::min(diff.clone().abs(), (diff.abs() - &params.q).abs());
+                assert!(diff < 10);
+            }
+        }
+    }
+
+    #[test]
+    fn self_share_test() {
+        let params = default_base_params();
+        let n = 5;
+        let mut rng = Rng::from_seed(0, 0);
+        let (_, seeds) = generate_keys(n, &params, &mut rng);
+        let secret = rng.bytes(SEED_LEN);
+        let (dealer, round, attempt) = (2, 3, 1);
+        let shares = deal_self_shares(dealer, &secret, &seeds[dealer], round, Phase::Bulk, attempt);
+        let members = members_of(dealer, &seeds[dealer]);
+        assert!(is_well_formed(&shares, dealer, &members));
+        let opened: BTreeMap<usize, Integer> = members
+            .iter()
+            .map(|member| {
+                let encrypted = shares.encrypted.get(member).cloned().unwrap_or_default();
+                let share = open_self_share(
+                    *member,
+                    &secret,
+                    &seeds[*member],
+                    dealer,
+                    &encrypted,
+                    round,
+                    Phase::Bulk,
+                    attempt,
+                );
+                (*member, share.unwrap())
+            })
+            .collect();
+        let threshold = recovery_threshold(n);
+        let seed = self_seed(&secret, round, Phase::Bulk, attempt);
+        // Any `threshold` shares give the seed back.
+        let revealed: BTreeMap<usize, &Integer> =
+            opened.iter().skip(1).map(|(m, s)| (*m, s)).collect();
+        let reconstruct = |revealed: &BTreeMap<usize, &Integer>| {
+            reconstruct_seed(
+                dealer,
+                &shares.digests,
+                revealed,
+                threshold,
+                round,
+                Phase::Bulk,
+                attempt,
+            )
+        };
+        assert_eq!(reconstruct(&revealed), Some(seed.clone()));
+        assert_eq!(
+            self_mask(&params, &seed, 8),
+            self_mask(&params, &reconstruct(&revealed).unwrap(), 8)
+        );
+        // A tampered share does not count.
+        let tampered = opened[&0].clone() + 1;
+        let mut revealed: BTreeMap<usize, &Integer> = opened
+            .iter()
+            .take(threshold)
+            .map(|(m, s)| (*m, s))
+            .collect();
+        revealed.insert(0, &tampered);
+        assert_eq!(reconstruct(&revealed), None);
+        // Neither do fewer than `threshold` shares.
+        let revealed: BTreeMap<usize, &Integer> = opened
+            .iter()
+            .take(threshold - 1)
+            .map(|(m, s)| (*m, s))
+            .collect();
+        assert_eq!(reconstruct(&revealed), None);
+    }
+}
@@ -0,0 +1,82 @@
+use crate::config::Config;
+use openssl::rand::rand_bytes;
+use rug::{integer::Order, rand::RandState, Integer};
+
+/// Source of randomness for share generation and one-time IDs.
+pub enum Rng {
+    /// OpenSSL CSPRNG seeded from OS entropy.
+    Os,
+    /// Deterministic generator with a fixed seed. For testing only.
+    Seeded(RandState<'static>),
+}
+
+impl Default for Rng {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rng {
+    /// Returns a CSPRNG seeded from OS entropy.
+    pub fn new() -> Self {
+        Rng::Os
+    }
+
+    /// Returns a deterministic generator. `stream` separates the outputs of
+    /// different nodes sharing the same `seed`. For testing only.
+    pub fn from_seed(seed: u64, stream: usize) -> Self {
+        let mut state = RandState::new();
+        state.seed(&((Integer::from(seed) << 64) + stream as u64));
+        Rng::Seeded(state)
+    }
+
+    /// Returns the generator requested by the config for the node `stream`.
+    pub fn from_config(c: &Config, stream: usize) -> Self {
+        match c.test_seed {
+            Some(seed) => {
+                warn!(
+                    "Using the fixed test seed {}. Never do this in deployment.",
+                    seed
+                );
+                Self::from_seed(seed, stream)
+            }
+            None => Self::new(),
+        }
+    }
+
+    /// Returns a uniformly random integer of at most `bits` bits.
+    pub fn bits(&mut self, bits: u32) -> Integer {
+        match self {
+            Rng::Os => {
+                let mut buf = vec![0u8; bits as usize / 8 + 1];
+                rand_bytes(&mut buf).unwrap();
+                Integer::from_digits(&buf, Order::Lsf).keep_bits(bits)
+            }
+            Rng::Seeded(state) => Integer::from(Integer::random_bits(bits, state)),
+        }
+    }
+
+    /// Returns `len` uniformly random bytes.
+    pub fn bytes(&mut self, len: usize) -> Vec<u8> {
+        let mut buf = self.bits(len as u32 * 8).to_digits::<u8>(Order::Lsf);
+        buf.resize(len, 0);
+        buf
+    }
+
+    /// Returns a uniformly random integer in `[0, bound)`.
+    pub fn below(&mut self, bound: &Integer) -> Integer {
+        match self {
+            Rng::Os => {
+                // Rejection sampling takes less than two tries on average.
+                let bits = bound.significant_bits();
+                loop {
+                    let x = self.bits(bits);
+                    if &x < bound {
+                        return x;
+                    }
+                }
+            }
+            Rng::Seeded(state) => Integer::from(bound.random_below_ref(state)),
+        }
+    }
+}
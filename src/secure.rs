@@ -0,0 +1,416 @@
+use crate::net::{
+    async_read_stream, async_write_stream, read_stream, write_stream, Limits, NetError,
+    HANDSHAKE_FRAME_LEN,
+};
+use openssl::derive::Deriver;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private, Public};
+use openssl::sign::{Signer, Verifier};
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::{SocketAddr, TcpStream};
+
+// Authenticated and encrypted transport between the nodes and the relay. The
+// handshake is a signed ephemeral ECDH in the style of SIGMA: both sides sign
+// the transcript with their long-term keys, and the shared secret keys one
+// AES-256-GCM channel per direction.
+
+/// Domain separation string for the handshake transcript.
+const HANDSHAKE_DOMAIN: &[u8] = b"OrgAn handshake";
+
+/// Length of the AES-256-GCM tag in bytes.
+const TAG_LEN: usize = 16;
+
+/// Long-term identity of a node, issued during the setup.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Identity {
+    /// Private key of the node in DER.
+    pub key: Vec<u8>,
+    /// Public key of the relay in DER.
+    pub relay: Vec<u8>,
+    /// Public key of each client in DER, indexed by ID. Only known to the relay.
+    pub clients: Vec<Vec<u8>>,
+    /// Public key of the guard in DER. Only known to the relay.
+    pub guard: Option<Vec<u8>>,
+}
+
+/// A node connecting to the relay.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Peer {
+    /// The client with the given ID.
+    Client(usize),
+    /// The guard.
+    Guard,
+}
+
+/// First handshake message, from the connecting node.
+#[derive(Serialize, Deserialize)]
+struct Hello {
+    /// Who the node claims to be.
+    peer: Peer,
+    /// Ephemeral public key in DER.
+    ephemeral: Vec<u8>,
+}
+
+/// Second handshake message, from the relay.
+#[derive(Serialize, Deserialize)]
+struct Reply {
+    /// Ephemeral public key in DER.
+    ephemeral: Vec<u8>,
+    /// Signature of the relay over the transcript.
+    signature: Vec<u8>,
+}
+
+/// Last handshake message, from the connecting node.
+#[derive(Serialize, Deserialize)]
+struct Finish {
+    /// Signature of the node over the transcript.
+    signature: Vec<u8>,
+}
+
+/// Encrypting half of a channel.
+pub struct Sealer {
+    /// AES-256-GCM key.
+    key: Vec<u8>,
+    /// Number of frames sealed so far, used as the nonce.
+    counter: u64,
+}
+
+/// Decrypting half of a channel.
+pub struct Opener {
+    /// AES-256-GCM key.
+    key: Vec<u8>,
+    /// Number of frames opened so far, used as the nonce.
+    counter: u64,
+}
+
+/// Channel established by the handshake.
+pub struct Session {
+    /// The authenticated node on the other side. Always the relay on a node.
+    pub peer: Peer,
+    /// Encrypting half.
+    pub sealer: Sealer,
+    /// Decrypting half.
+    pub opener: Opener,
+}
+
+/// Returns the nonce of frame `counter`.
+fn nonce(counter: u64) -> [u8; 12] {
+    let mut nonce = [0u8; 12];
+    nonce[..8].copy_from_slice(&counter.to_le_bytes());
+    nonce
+}
+
+impl Sealer {
+    /// Encrypts one frame.
+    pub fn seal(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut tag = [0u8; TAG_LEN];
+        let mut frame = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce(self.counter)),
+            &[],
+            data,
+            &mut tag,
+        )
+        .unwrap();
+        self.counter += 1;
+        frame.extend_from_slice(&tag);
+        frame
+    }
+}
+
+impl Opener {
+    /// Decrypts one frame. Fails on frames that were changed, replayed or
+    /// reordered.
+    pub fn open(&mut self, frame: &[u8]) -> Result<Vec<u8>, NetError> {
+        if frame.len() < TAG_LEN {
+            return Err(NetError::Decrypt);
+        }
+        let (data, tag) = frame.split_at(frame.len() - TAG_LEN);
+        let data = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce(self.counter)),
+            &[],
+            data,
+            tag,
+        )
+        .map_err(|_| NetError::Decrypt)?;
+        self.counter += 1;
+        Ok(data)
+    }
+}
+
+/// Returns the group of all keys used in the handshake.
+fn group() -> EcGroup {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()
+}
+
+/// Generates a key pair. Returns the private and the public key in DER.
+pub fn generate_key() -> (Vec<u8>, Vec<u8>) {
+    let key = EcKey::generate(&group()).unwrap();
+    let public = EcKey::from_public_key(&group(), key.public_key()).unwrap();
+    (
+        key.private_key_to_der().unwrap(),
+        public.public_key_to_der().unwrap(),
+    )
+}
+
+/// Issues the identities of `n` clients, the relay and the guard.
+pub fn issue_identities(n: usize) -> (Vec<Identity>, Identity, Identity) {
+    let (clients, client_keys): (Vec<_>, Vec<_>) = (0..n).map(|_| generate_key()).unzip();
+    let (relay, relay_key) = generate_key();
+    let (guard, guard_key) = generate_key();
+    let identity = |key: Vec<u8>| Identity {
+        key,
+        relay: relay_key.clone(),
+        clients: Vec::new(),
+        guard: None,
+    };
+    (
+        clients.into_iter().map(identity).collect(),
+        Identity {
+            clients: client_keys,
+            guard: Some(guard_key),
+            ..identity(relay)
+        },
+        identity(guard),
+    )
+}
+
+/// Loads a private key in DER.
+fn private_key(der: &[u8]) -> Result<PKey<Private>, NetError> {
+    EcKey::private_key_from_der(der)
+        .and_then(PKey::from_ec_key)
+        .map_err(|_| NetError::Handshake)
+}
+
+/// Loads a public key in DER.
+fn public_key(der: &[u8]) -> Result<PKey<Public>, NetError> {
+    EcKey::public_key_from_der(der)
+        .and_then(PKey::from_ec_key)
+        .map_err(|_| NetError::Handshake)
+}
+
+/// Generates an ephemeral key pair. Returns the private key and the public
+/// key in DER.
+fn ephemeral() -> (PKey<Private>, Vec<u8>) {
+    let (key, public) = generate_key();
+    (private_key(&key).unwrap(), public)
+}
+
+/// Signs the transcript `th` in the role `role` with `key`.
+pub fn sign(key: &[u8], th: &[u8], role: &[u8]) -> Result<Vec<u8>, NetError> {
+    let key = private_key(key)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|_| NetError::Handshake)?;
+    signer.update(th).map_err(|_| NetError::Handshake)?;
+    signer.update(role).map_err(|_| NetError::Handshake)?;
+    signer.sign_to_vec().map_err(|_| NetError::Handshake)
+}
+
+/// Verifies the signature of the transcript `th` in the role `role`.
+pub fn verify(key: &[u8], th: &[u8], role: &[u8], signature: &[u8]) -> Result<(), NetError> {
+    let key = public_key(key)?;
+    let mut verifier =
+        Verifier::new(MessageDigest::sha256(), &key).map_err(|_| NetError::Handshake)?;
+    verifier.update(th).map_err(|_| NetError::Handshake)?;
+    verifier.update(role).map_err(|_| NetError::Handshake)?;
+    match verifier.verify(signature) {
+        Ok(true) => Ok(()),
+        _ => Err(NetError::Handshake),
+    }
+}
+
+/// Hashes the transcript of the handshake.
+fn transcript(hello: &Hello, ephemeral: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(HANDSHAKE_DOMAIN);
+    hasher.update(bincode::serialize(hello).unwrap());
+    hasher.update(ephemeral);
+    hasher.finalize().to_vec()
+}
+
+/// Derives the keys of the channel from `key` and the ephemeral key of the
+/// other side. Returns the key of the node and the key of the relay.
+fn derive_keys(
+    key: &PKey<Private>,
+    ephemeral: &[u8],
+    th: &[u8],
+) -> Result<(Vec<u8>, Vec<u8>), NetError> {
+    let ephemeral = public_key(ephemeral)?;
+    let mut deriver = Deriver::new(key).map_err(|_| NetError::Handshake)?;
+    deriver
+        .set_peer(&ephemeral)
+        .map_err(|_| NetError::Handshake)?;
+    let shared = deriver.derive_to_vec().map_err(|_| NetError::Handshake)?;
+    let derive = |role: &[u8]| {
+        let mut hasher = Sha256::new();
+        hasher.update(&shared);
+        hasher.update(th);
+        hasher.update(role);
+        hasher.finalize().to_vec()
+    };
+    Ok((derive(b"node"), derive(b"relay")))
+}
+
+/// Deserializes a handshake message.
+fn decode<T: serde::de::DeserializeOwned>(buf: &[u8]) -> Result<T, NetError> {
+    bincode::deserialize(buf).map_err(|_| NetError::Handshake)
+}
+
+/// Stream to the relay.
+pub struct SecureStream {
+    /// Underlying stream.
+    stream: TcpStream,
+    /// Limits on the frames.
+    limits: Limits,
+    /// Channel to the relay.
+    session: Session,
+}
+
+impl SecureStream {
+    /// Connects to the relay at `addr` as `peer`, authenticating both sides
+    /// with their long-term keys. `limits` applies to the frames after the
+    /// handshake.
+    pub fn connect(
+        addr: SocketAddr,
+        peer: Peer,
+        identity: &Identity,
+        limits: Limits,
+    ) -> Result<Self, NetError> {
+        let handshake = Limits {
+            max_len: HANDSHAKE_FRAME_LEN,
+            ..limits
+        };
+        let mut stream = TcpStream::connect(addr)?;
+        let (key, ephemeral) = ephemeral();
+        let hello = Hello { peer, ephemeral };
+        write_stream(
+            &mut stream,
+            &bincode::serialize(&hello).unwrap(),
+            &handshake,
+        )?;
+        let reply: Reply = decode(&read_stream(&mut stream, &handshake)?)?;
+        let th = transcript(&hello, &reply.ephemeral);
+        verify(&identity.relay, &th, b"relay", &reply.signature)?;
+        let finish = Finish {
+            signature: sign(&identity.key, &th, b"node")?,
+        };
+        write_stream(
+            &mut stream,
+            &bincode::serialize(&finish).unwrap(),
+            &handshake,
+        )?;
+        let (send, recv) = derive_keys(&key, &reply.ephemeral, &th)?;
+        Ok(SecureStream {
+            stream,
+            limits,
+            session: Session {
+                peer,
+                sealer: Sealer {
+                    key: send,
+                    counter: 0,
+                },
+                opener: Opener {
+                    key: recv,
+                    counter: 0,
+                },
+            },
+        })
+    }
+
+    /// Reads one message from the relay.
+    pub fn read(&mut self) -> Result<Vec<u8>, NetError> {
+        let frame = read_stream(&mut self.stream, &self.limits)?;
+        self.session.opener.open(&frame)
+    }
+
+    /// Writes one message to the relay.
+    pub fn write(&mut self, data: &[u8]) -> Result<(), NetError> {
+        let frame = self.session.sealer.seal(data);
+        write_stream(&mut self.stream, &frame, &self.limits)
+    }
+}
+
+/// Runs the handshake of the relay on an incoming `stream` within `limits`.
+/// Returns the channel to the node, which is authenticated as the peer it
+/// claims to be.
+pub async fn accept(
+    stream: &mut async_std::net::TcpStream,
+    identity: &Identity,
+    limits: &Limits,
+) -> Result<Session, NetError> {
+    let hello: Hello = decode(&async_read_stream(stream, limits).await?)?;
+    let peer_key = match hello.peer {
+        Peer::Client(nid) => identity.clients.get(nid),
+        Peer::Guard => identity.guard.as_ref(),
+    }
+    .ok_or(NetError::Handshake)?;
+    let (key, ephemeral) = ephemeral();
+    let th = transcript(&hello, &ephemeral);
+    let reply = Reply {
+        signature: sign(&identity.key, &th, b"relay")?,
+        ephemeral,
+    };
+    async_write_stream(stream, &bincode::serialize(&reply).unwrap(), limits).await?;
+    let finish: Finish = decode(&async_read_stream(stream, limits).await?)?;
+    verify(peer_key, &th, b"node", &finish.signature)?;
+    let (recv, send) = derive_keys(&key, &hello.ephemeral, &th)?;
+    Ok(Session {
+        peer: hello.peer,
+        sealer: Sealer {
+            key: send,
+            counter: 0,
+        },
+        opener: Opener {
+            key: recv,
+            counter: 0,
+        },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::net::{async_read_stream, async_write_stream, Limits};
+    use crate::secure::{accept, issue_identities, Peer, SecureStream};
+    use async_std::net::TcpListener;
+
+    #[test]
+    fn handshake_test() {
+        let (clients, relay, _) = issue_identities(2);
+        let limits = Limits {
+            max_len: 4096,
+            timeout: None,
+        };
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            let client = std::thread::spawn(move || {
+                let mut stream =
+                    SecureStream::connect(addr, Peer::Client(1), &clients[1], limits).unwrap();
+                stream.write(b"hello").unwrap();
+                assert_eq!(stream.read().unwrap(), b"world");
+                // Claim to be another client.
+                let _ = SecureStream::connect(addr, Peer::Client(0), &clients[1], limits);
+            });
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut session = accept(&mut stream, &relay, &limits).await.unwrap();
+            assert_eq!(session.peer, Peer::Client(1));
+            let frame = async_read_stream(&mut stream, &limits).await.unwrap();
+            assert_eq!(session.opener.open(&frame).unwrap(), b"hello");
+            // Replaying a frame fails.
+            assert!(session.opener.open(&frame).is_err());
+            let frame = session.sealer.seal(b"world");
+            async_write_stream(&mut stream, &frame, &limits)
+                .await
+                .unwrap();
+            let (mut stream, _) = listener.accept().await.unwrap();
+            assert!(accept(&mut stream, &relay, &limits).await.is_err());
+            client.join().unwrap();
+        });
+    }
+}
@@ -1,34 +1,259 @@
+use crate::aggregate::Aggregate;
+use crate::blame::{blame_deadline, find_base_guilty, BlameState};
+use crate::client::{payload_capacity, payload_digest, reservation_capacity};
 use crate::config::{Config, ProtocolParams};
-use crate::ecc::{add, from_bytes, get_g, get_h, mul, new_big_num_context};
-use crate::flint::solve_impl;
-use crate::guard::SetupRelay;
+use crate::egress::Uplink;
+use crate::error::Error;
+use crate::flint::{solve_impl, SolveError};
+use crate::guard::RelaySetup;
 use crate::message::{
-    ClientBaseMessage, ClientBulkMessage, ClientPrifiMessage, Message, ServerBaseMessage,
+    Capabilities, ClientAccusationMessage, ClientBaseBlameMessage, ClientBaseMessage,
+    ClientBlameMessage, ClientBulkMessage, ClientPrifiMessage, ClientRecoveryMessage, Hello,
+    Message, Phase, SelfShares, ServerBaseBlameRequestMessage, ServerBaseMessage,
+    ServerBaseRetryMessage, ServerBlameResultMessage, ServerBulkMessage,
+    ServerRecoveryRequestMessage, Welcome,
 };
-use crate::net::{async_read_stream, async_write_stream};
+use crate::net::{async_read_stream, async_write_stream, Limits, NetError};
+use crate::packed::{width, PackedVec};
+use crate::prf::Masks;
+use crate::recovery::{
+    is_well_formed, reconstruct_seed, recovery_threshold, relay_mask, self_mask,
+};
+use crate::rng::Rng;
+use crate::secure::{accept, Identity, Peer, Session};
+use crate::sink::Sink;
 use async_std::channel::{unbounded, Receiver, Sender};
+use async_std::future::timeout;
 use async_std::net::{TcpListener, TcpStream};
 use futures::stream::StreamExt;
 use futures::{future::join, select, FutureExt};
 use rayon::prelude::*;
-use rug::{Complete, Integer};
-use std::collections::HashMap;
+use rug::{integer::Order, Complete, Integer};
+use std::cell::{Cell, RefCell};
+use std::collections::{hash_map::Entry, BTreeMap, HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+/// Number of attempts of a base round after which the relay gives up on it
+/// and runs the bulk round without slot groups.
+pub const MAX_ATTEMPTS: usize = 10;
+
+/// How long the relay waits for the answers to a recovery request without
+/// `round_timeout`.
+const RECOVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Counters of the relay, logged after every round.
+#[derive(Default, Debug)]
+pub struct Metrics {
+    /// Retries of the base round, over all rounds.
+    pub base_retries: Cell<usize>,
+    /// Base rounds given up after `MAX_ATTEMPTS` attempts, and bulk rounds
+    /// given up because they could not be unmasked.
+    pub aborted_rounds: Cell<usize>,
+}
+
+/// Input of the blame reactor.
+pub enum BlameInput {
+    /// Slot group IDs and bulk messages of a finished round.
+    Record(usize, Vec<Integer>, HashMap<usize, ClientBulkMessage>),
+    /// Accusation from a client.
+    Accusation(ClientAccusationMessage),
+    /// Opening from a client.
+    Opening(ClientBlameMessage),
+}
+
+/// Reason the relay rejects a message from a node.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// The message claims an ID other than the one the connection
+    /// authenticated as.
+    Impersonation {
+        /// The node that authenticated on the connection.
+        peer: Option<Peer>,
+        /// The ID the message claims.
+        claimed: usize,
+    },
+    /// The message may only come from the guard.
+    Unauthorized(Option<Peer>),
+    /// The client already sent this kind of message in the round.
+    Duplicate {
+        /// Client ID.
+        nid: usize,
+        /// Round number.
+        round: usize,
+    },
+    /// The message does not decode or has the wrong shape.
+    Malformed(Option<Peer>),
+    /// The client sent its bulk message before the base round ended.
+    Premature {
+        /// Client ID.
+        nid: usize,
+        /// Round number.
+        round: usize,
+    },
+    /// The client sent its base message for a round or an attempt beyond the
+    /// ones it may run.
+    OutOfWindow {
+        /// Client ID.
+        nid: usize,
+        /// Round number.
+        round: usize,
+        /// Attempt within the round.
+        attempt: usize,
+    },
+    /// The setup key of the client is not signed by its identity.
+    Unsigned(usize),
+    /// The client is not a member in the round of its message.
+    NotMember {
+        /// Client ID.
+        nid: usize,
+        /// Round number.
+        round: usize,
+    },
+}
+
+impl std::fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ProtocolError::Impersonation { peer, claimed } => {
+                write!(f, "{:?} claims to be client {}", peer, claimed)
+            }
+            ProtocolError::Unauthorized(peer) => write!(f, "{:?} is not the guard", peer),
+            ProtocolError::Duplicate { nid, round } => {
+                write!(f, "client {} sent twice on round {}", nid, round)
+            }
+            ProtocolError::Malformed(peer) => write!(f, "{:?} sent a malformed message", peer),
+            ProtocolError::Premature { nid, round } => {
+                write!(
+                    f,
+                    "client {} sent before the base round {} ended",
+                    nid, round
+                )
+            }
+            ProtocolError::OutOfWindow {
+                nid,
+                round,
+                attempt,
+            } => write!(
+                f,
+                "client {} sent attempt {} of round {} outside the pipeline window",
+                nid, attempt, round
+            ),
+            ProtocolError::Unsigned(nid) => {
+                write!(f, "the setup key of client {} is not signed by it", nid)
+            }
+            ProtocolError::NotMember { nid, round } => {
+                write!(f, "client {} is not a member on round {}", nid, round)
+            }
+        }
+    }
+}
+impl std::error::Error for ProtocolError {}
+
+/// Reason the sums of a base round do not give a permutation.
+#[derive(Debug, PartialEq, Eq)]
+pub enum EquationError {
+    /// The error of the PRF in the element at the index exceeds the error
+    /// bound, so rounding it could give a wrong value.
+    Ambiguous(usize),
+    /// The number of reserved slot groups exceeds the number of sums.
+    Reservation(Integer),
+    /// The power sums do not solve.
+    Solve(SolveError),
+}
+
+impl std::fmt::Display for EquationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EquationError::Ambiguous(i) => write!(f, "rounding of element {} is ambiguous", i),
+            EquationError::Reservation(count) => write!(f, "{} slot groups reserved", count),
+            EquationError::Solve(e) => write!(f, "{}", e),
+        }
+    }
+}
+impl std::error::Error for EquationError {}
+
+impl From<SolveError> for EquationError {
+    fn from(e: SolveError) -> Self {
+        EquationError::Solve(e)
+    }
+}
+
+/// Checks that a message claiming to be from `nid` came over the connection
+/// of that client.
+pub fn check_sender(peer: Option<Peer>, nid: usize) -> Result<(), ProtocolError> {
+    if peer == Some(Peer::Client(nid)) {
+        Ok(())
+    } else {
+        Err(ProtocolError::Impersonation { peer, claimed: nid })
+    }
+}
+
+/// Logs `e` and asks the sender to close the connection of `peer`. The peer
+/// may connect again later.
+async fn disconnect(disconnect_channel: &Sender<Peer>, peer: Option<Peer>, e: ProtocolError) {
+    error!("Disconnecting {:?}: {}.", peer, e);
+    if let Some(peer) = peer {
+        let _ = disconnect_channel.send(peer).await;
+    }
+}
+
+/// Stores the message of `nid` on `round` unless there already is one.
+pub fn insert_once<T>(
+    messages: &mut HashMap<usize, T>,
+    nid: usize,
+    round: usize,
+    msg: T,
+) -> Result<(), ProtocolError> {
+    match messages.entry(nid) {
+        Entry::Vacant(entry) => {
+            entry.insert(msg);
+            Ok(())
+        }
+        Entry::Occupied(_) => Err(ProtocolError::Duplicate { nid, round }),
+    }
+}
 
-/// Solves the equation to find the permutation for the base round.
+/// Setups of the base and bulk rounds for one member set.
+#[derive(Clone)]
+pub struct Epoch {
+    /// First round of the member set.
+    pub from_round: usize,
+    /// Setup of the base round.
+    pub base: Rc<RelaySetup>,
+    /// Setup of the bulk round.
+    pub bulk: Rc<RelaySetup>,
+}
+
+/// Returns the epoch `round` belongs to.
+fn get_epoch(epochs: &RefCell<Vec<Epoch>>, round: usize) -> Epoch {
+    let epochs = epochs.borrow();
+    epochs
+        .iter()
+        .rev()
+        .find(|epoch| epoch.from_round <= round)
+        .unwrap_or(&epochs[0])
+        .clone()
+}
+
+/// Solves the equation to find the permutation for the base round from the
+/// `aggregate` of the client messages. The permutation holds one ID per
+/// reserved slot group.
 pub fn solve_equation(
     c: &Config,
     base_prf: &[Integer],
-    messages: &HashMap<usize, ClientBaseMessage>,
-) -> Vec<Integer> {
-    debug!("Client messages: {:?}", messages);
+    aggregate: &Aggregate,
+) -> Result<Vec<Integer>, EquationError> {
+    solve_sums(c, &unmask_sums(c, base_prf, aggregate))
+}
 
-    let relay_messages: Vec<Integer> = (0..c.client_size)
-        .into_par_iter()
-        .map(|i| messages.par_iter().map(|(_, b)| &b.slot_messages[i]).sum())
-        .collect();
+/// Takes the masks `base_prf` off the `aggregate` of the client messages of
+/// the base round. The sums are still scaled and carry the error of the PRF.
+pub fn unmask_sums(c: &Config, base_prf: &[Integer], aggregate: &Aggregate) -> Vec<Integer> {
+    let relay_messages = aggregate.sum();
     debug!("base_relay_messages: {:?}", relay_messages);
 
-    let final_values: Vec<Integer> = relay_messages
+    relay_messages
         .par_iter()
         .zip(base_prf.par_iter())
         .map(|(rmsg, prf)| {
@@ -37,39 +262,95 @@ pub fn solve_equation(
                 .complete();
             rem
         })
-        .collect();
-    debug!("final_values before rounding: {:?}", final_values);
+        .collect()
+}
+
+/// Solves the equation to find the permutation for the base round from the
+/// unmasked `sums`, which hold the power sums and the number of reserved slot
+/// groups. The clients solve the sums of a failed attempt again before they
+/// open it.
+pub fn solve_sums(c: &Config, sums: &[Integer]) -> Result<Vec<Integer>, EquationError> {
+    debug!("final_values before rounding: {:?}", sums);
     // Round to eliminate the error in almost key-homomorphic functions.
-    let final_values: Vec<Integer> = final_values
+    let final_values: Vec<Option<Integer>> = sums
         .par_iter()
-        .map(|x| (x + Integer::from(1000 / 2)) / 1000 % &c.base_params.p)
+        .map(|x| round_scaled(&c.base_params, x, c.error_bound()).map(|x| x % &c.base_params.p))
         .collect();
     debug!("final_values: {:?}", final_values);
+    let final_values: Vec<Integer> = match final_values.iter().position(Option::is_none) {
+        Some(i) => return Err(EquationError::Ambiguous(i)),
+        None => final_values.into_iter().flatten().collect(),
+    };
+
+    // The element after the power sums holds the number of reserved slot groups.
+    let capacity = reservation_capacity(c);
+    let groups = match final_values[capacity].to_usize() {
+        Some(groups) if groups <= capacity => groups,
+        _ => return Err(EquationError::Reservation(final_values[capacity].clone())),
+    };
+    if groups == 0 {
+        return Ok(Vec::new());
+    }
 
-    // Solve the equation.
-    let solve = solve_impl(&c.base_params.p, &final_values);
+    // Solve the equation. The polynomial has one root per reserved slot group.
+    let solve = solve_impl(&c.base_params.p, &final_values[0..groups]);
     debug!("solve: {:?}", solve);
 
-    solve
+    Ok(solve?)
+}
+
+/// Rounds `x`, a sum of messages scaled by the `error_scale` of `params` with
+/// an error of at most `bound`, to the sum of the messages. Returns `None` if
+/// the error is larger, since rounding could then give a wrong value.
+pub fn round_scaled(params: &ProtocolParams, x: &Integer, bound: u64) -> Option<Integer> {
+    // The error is negative as often as not, so that a zero message wraps
+    // around to just below `q`.
+    let lifted = Integer::from(&params.q - x);
+    let x = if lifted <= bound { -lifted } else { x.clone() };
+    let (quotient, rem) = x
+        .div_rem_round_ref(&Integer::from(params.error_scale))
+        .complete();
+    if rem.abs() <= bound {
+        Some(quotient)
+    } else {
+        None
+    }
+}
+
+/// Checks whether the base round failed to give each reserved slot group a
+/// distinct slot, i.e. whether two IDs collided.
+pub fn has_collision(perm: &[Integer]) -> bool {
+    let mut sorted = perm.to_vec();
+    sorted.sort();
+    sorted.dedup();
+    sorted.len() != perm.len()
 }
 
-/// Computes the message for the bulk round.
+/// Reassembles the payload a client split across its slots in the bulk round.
+/// Returns `None` if the slots do not hold a well-formed payload.
+pub fn decode_payload(c: &Config, slots: &[Integer]) -> Option<Vec<u8>> {
+    let bits = c.bulk_params.bits as u32;
+    let packed = slots.iter().rev().fold(Integer::new(), |acc, slot| {
+        (acc << bits) + Integer::from(slot.keep_bits_ref(bits))
+    });
+    let mut digits = packed.to_digits::<u8>(Order::Lsf);
+    digits.resize(payload_capacity(c) + 4, 0);
+    let len = u32::from_le_bytes(digits[0..4].try_into().unwrap()) as usize;
+    if len > payload_capacity(c) {
+        return None;
+    }
+    Some(digits[4..4 + len].to_vec())
+}
+
+/// Computes the message for the bulk round from the `aggregate` of the client
+/// messages and decodes the payload of each slot group.
 pub fn compute_message(
     c: &Config,
     bulk_prf: &[Integer],
-    messages: &HashMap<usize, ClientBulkMessage>,
-) -> Vec<Integer> {
-    let relay_messages: Vec<Integer> = (0..c.slot_per_round * c.client_size)
-        .into_par_iter()
-        .map(|i| {
-            let mut relay_msg_of_slot = Integer::from(0);
-            for (_nid, msg) in messages.iter() {
-                relay_msg_of_slot = (relay_msg_of_slot + &msg.slot_messages[i]) % &c.bulk_params.q;
-            }
-            relay_msg_of_slot
-        })
-        .collect();
-    let final_values: Vec<Integer> = relay_messages
+    aggregate: &Aggregate,
+) -> Vec<Option<Vec<u8>>> {
+    let final_values: Vec<Integer> = aggregate
+        .sum()
         .par_iter()
         .zip(bulk_prf.par_iter())
         .map(|(rmsg, prf)| {
@@ -77,80 +358,290 @@ pub fn compute_message(
         })
         .collect();
     debug!("final_values before rounding: {:?}", final_values);
-    // Round to eliminate the error in almost key-homomorphic functions.
-    let final_values: Vec<Integer> = final_values
+    // Round to eliminate the error in almost key-homomorphic functions. Unlike
+    // in the base round, there is no reduction modulo `p`: a chunk is below
+    // `2^bits` and a slot has a single writer, so the rounded value is below
+    // `2^bits` too, which the bulk `q` leaves room for. The chunks between `p`
+    // and `2^bits` would not survive the reduction. A slot written by several
+    // clients is garbage either way, and `decode_payload` only keeps its low
+    // `bits` bits.
+    let final_values: Vec<Option<Integer>> = final_values
         .par_iter()
-        .map(|x| (x + Integer::from(1000 / 2)) / 1000 % &c.bulk_params.p)
+        .map(|x| round_scaled(&c.bulk_params, x, c.error_bound()))
         .collect();
     debug!("final_values: {:?}", final_values);
 
-    final_values
+    // A slot group with an element that does not round unambiguously is
+    // malformed rather than decoded to a wrong value.
+    let payloads: Vec<Option<Vec<u8>>> = final_values
+        .chunks(c.slot_per_round)
+        .map(|slots| {
+            let slots: Option<Vec<Integer>> = slots.iter().cloned().collect();
+            decode_payload(c, &slots?)
+        })
+        .collect();
+    debug!("payloads: {:?}", payloads);
+
+    payloads
 }
 
-/// Connection handler.
+/// Connection handler. `session` encrypts the connection if present. Returns
+/// once the peer is gone or `channel_write` is closed.
 async fn handle_connection(
-    mut stream: TcpStream,
-    channel_read: Sender<Vec<u8>>,
+    stream: TcpStream,
+    session: Option<Session>,
+    mut limits: Limits,
+    channel_read: Sender<(Option<Peer>, Vec<u8>)>,
     channel_write: Receiver<Vec<u8>>,
 ) {
-    loop {
-        select! {
-            read_result = async_read_stream(&mut stream).fuse() => {
-                if read_result.is_ok() {
-                    let msg = read_result.unwrap();
-                    channel_read.send(msg).await.unwrap();
+    let peer = session.as_ref().map(|session| session.peer);
+    // The guard hands over the setup of all rounds at once.
+    if peer == Some(Peer::Guard) {
+        limits.max_len = usize::MAX;
+    }
+    let (mut opener, mut sealer) = match session {
+        Some(session) => (Some(session.opener), Some(session.sealer)),
+        None => (None, None),
+    };
+    // Reads and writes run side by side on two handles of the stream, so that
+    // a write never cuts a frame short that is half read.
+    let mut reader = stream.clone();
+    let mut writer = stream;
+    let read = async {
+        loop {
+            let read_result = async_read_stream(&mut reader, &limits)
+                .await
+                .and_then(|msg| match opener.as_mut() {
+                    Some(opener) => opener.open(&msg),
+                    None => Ok(msg),
+                });
+            match read_result {
+                Ok(msg) => channel_read.send((peer, msg)).await.unwrap(),
+                // The peer is gone or misbehaved. It may connect again later.
+                Err(NetError::Eof) => {
+                    info!("{:?} disconnected.", peer);
+                    return;
                 }
-            }
-            write_result = channel_write.recv().fuse() => {
-                if write_result.is_ok() {
-                    let message = write_result.unwrap();
-                    if async_write_stream(&mut stream, &message).await.is_err() {
-                        error!("Write error on socket.");
-                    }
+                Err(e) => {
+                    warn!("Closing the connection of {:?}: {}.", peer, e);
+                    return;
                 }
             }
         }
+    };
+    let write = async {
+        // The relay disconnected the peer once the channel is closed.
+        while let Ok(message) = channel_write.recv().await {
+            let message = match sealer.as_mut() {
+                Some(sealer) => sealer.seal(&message),
+                None => message,
+            };
+            if let Err(e) = async_write_stream(&mut writer, &message, &limits).await {
+                error!("Write error on socket: {}.", e);
+            }
+        }
+    };
+    select! {
+        () = read.fuse() => {},
+        () = write.fuse() => {},
     }
 }
 
-/// A simple listener for connections.
+/// Connection of a node, registered with the sender.
+struct Connection {
+    /// The node on the other side, if the connection is authenticated.
+    peer: Option<Peer>,
+    /// Channel of the messages to write to the connection.
+    channel: Sender<Vec<u8>>,
+    /// Whether the node resumes its session after a reconnect.
+    resume: bool,
+}
+
+/// Answers the `Hello` of a node on `stream`. Returns `None` if the node runs
+/// another protocol version or config, and otherwise whether it resumes the
+/// session it holds the token of in `sessions`. A node that does not resume
+/// gets a fresh token.
+async fn welcome(
+    c: &Config,
+    stream: &mut TcpStream,
+    session: &mut Session,
+    sessions: &RefCell<HashMap<Peer, u64>>,
+) -> Option<bool> {
+    let hello = match async_read_stream(stream, &Limits::handshake(c))
+        .await
+        .and_then(|frame| session.opener.open(&frame))
+        .map(|buf| bincode::deserialize::<Hello>(&buf))
+    {
+        Ok(Ok(hello)) => hello,
+        Ok(Err(_)) => {
+            warn!("Malformed Hello from {:?}.", session.peer);
+            return None;
+        }
+        Err(e) => {
+            warn!("No Hello from {:?}: {}.", session.peer, e);
+            return None;
+        }
+    };
+    // A refused node gets our capabilities to log the mismatch, but no
+    // session.
+    let ours = Capabilities::from_config(c);
+    let accepted = ours.check(&hello.capabilities);
+    let known = sessions.borrow().get(&session.peer).copied();
+    let resume = accepted.is_ok() && known.is_some() && hello.session == known;
+    let token = match known {
+        Some(token) if resume => token,
+        _ if accepted.is_err() => 0,
+        _ => {
+            let mut token = [0u8; 8];
+            openssl::rand::rand_bytes(&mut token).unwrap();
+            u64::from_le_bytes(token)
+        }
+    };
+    let welcome = Welcome {
+        capabilities: ours,
+        session: token,
+    };
+    let frame = session.sealer.seal(&bincode::serialize(&welcome).unwrap());
+    if let Err(e) = accepted {
+        error!("Refusing {:?}: {}.", session.peer, e);
+        let _ = async_write_stream(stream, &frame, &Limits::from_config(c)).await;
+        return None;
+    }
+    if let Err(e) = async_write_stream(stream, &frame, &Limits::from_config(c)).await {
+        warn!("Cannot welcome {:?}: {}.", session.peer, e);
+        return None;
+    }
+    sessions.borrow_mut().insert(session.peer, token);
+    Some(resume)
+}
+
+/// A simple listener for connections. Every connection runs the handshake
+/// with the current `identity` and exchanges `Hello` and `Welcome` first if
+/// `identity` is present.
 async fn listener(
     c: &Config,
-    reactor_input_channel_send: Sender<Vec<u8>>,
-    boardcast_channels_send: Sender<Sender<Vec<u8>>>,
+    listener: TcpListener,
+    identity: Option<&RefCell<Identity>>,
+    reactor_input_channel_send: Sender<(Option<Peer>, Vec<u8>)>,
+    boardcast_channels_send: Sender<Connection>,
 ) {
-    let listener = TcpListener::bind(c.server_addr).await.unwrap();
+    let sessions = &RefCell::new(HashMap::<Peer, u64>::new());
     listener
         .incoming()
         .for_each_concurrent(None, |stream| {
             let boardcast_channels_send = boardcast_channels_send.clone();
             let reactor_input_channel_send = reactor_input_channel_send.clone();
             async move {
-                let stream = stream.unwrap();
+                let mut stream = match stream {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        warn!("Failed to accept a connection: {}.", e);
+                        return;
+                    }
+                };
+                let (session, resume) = match identity {
+                    Some(identity) => {
+                        // The guard may revoke keys while the handshake runs.
+                        let identity = identity.borrow().clone();
+                        match accept(&mut stream, &identity, &Limits::handshake(c)).await {
+                            Ok(mut session) => {
+                                let resume =
+                                    match welcome(c, &mut stream, &mut session, sessions).await {
+                                        Some(resume) => resume,
+                                        None => return,
+                                    };
+                                if resume {
+                                    info!("{:?} resumed its session.", session.peer);
+                                } else {
+                                    info!("{:?} connected.", session.peer);
+                                }
+                                (Some(session), resume)
+                            }
+                            Err(e) => {
+                                warn!("Handshake failed with {:?}: {}.", stream.peer_addr(), e);
+                                return;
+                            }
+                        }
+                    }
+                    None => (None, false),
+                };
                 let (channel_send, channel_recv) = unbounded::<Vec<u8>>();
-                boardcast_channels_send.send(channel_send).await.unwrap();
-                handle_connection(stream, reactor_input_channel_send, channel_recv).await;
+                let peer = session.as_ref().map(|session| session.peer);
+                boardcast_channels_send
+                    .send(Connection {
+                        peer,
+                        channel: channel_send,
+                        resume,
+                    })
+                    .await
+                    .unwrap();
+                handle_connection(
+                    stream,
+                    session,
+                    Limits::from_config(c),
+                    reactor_input_channel_send,
+                    channel_recv,
+                )
+                .await;
             }
         })
         .await;
 }
 
-/// Simple message sender.
+/// Simple message sender. Closes the connections of the peers received on
+/// `disconnect_channel_recv`, and the earlier connection of a node that
+/// connects again. A node that resumes its session gets the latest
+/// `ServerBaseMessage`, `ServerBaseRetryMessage` and `ServerBulkMessage`
+/// again, in case it missed them.
 async fn sender(
-    boardcast_channels_recv: Receiver<Sender<Vec<u8>>>,
+    boardcast_channels_recv: Receiver<Connection>,
     reactor_output_channel_recv: Receiver<Vec<u8>>,
+    disconnect_channel_recv: Receiver<Peer>,
 ) {
-    let mut channels = Vec::<Sender<Vec<u8>>>::new();
+    let mut connections = Vec::<Connection>::new();
+    // The base round message goes first, so that a stale retry of the same
+    // round is ignored.
+    let mut latest: [Option<Vec<u8>>; 3] = Default::default();
     loop {
         select! {
-            new_channel = boardcast_channels_recv.recv().fuse() => {
-                let new_channel = new_channel.unwrap();
-                channels.push(new_channel);
+            new_connection = boardcast_channels_recv.recv().fuse() => {
+                let new_connection = new_connection.unwrap();
+                // Drop the closed connections and the one the node left behind.
+                connections.retain(|connection| {
+                    let stale =
+                        new_connection.peer.is_some() && connection.peer == new_connection.peer;
+                    if stale {
+                        connection.channel.close();
+                    }
+                    !stale && !connection.channel.is_closed()
+                });
+                if new_connection.resume {
+                    for message in latest.iter().flatten() {
+                        let _ = new_connection.channel.send(message.clone()).await;
+                    }
+                }
+                connections.push(new_connection);
+            }
+            peer = disconnect_channel_recv.recv().fuse() => {
+                let peer = peer.unwrap();
+                for connection in connections.iter().filter(|c| c.peer == Some(peer)) {
+                    connection.channel.close();
+                }
             }
             new_message = reactor_output_channel_recv.recv().fuse() => {
                 let new_message = new_message.unwrap();
-                for chan in channels.iter() {
-                    chan.send(new_message.clone()).await.unwrap();
+                match bincode::deserialize::<Message>(&new_message) {
+                    Ok(Message::ServerBaseMessage(_)) => latest[0] = Some(new_message.clone()),
+                    Ok(Message::ServerBaseRetryMessage(_)) => latest[1] = Some(new_message.clone()),
+                    Ok(Message::ServerBulkMessage(_)) => latest[2] = Some(new_message.clone()),
+                    _ => {}
+                }
+                // Drop the channels of closed connections.
+                connections.retain(|connection| !connection.channel.is_closed());
+                for connection in connections.iter() {
+                    if connection.channel.send(new_message.clone()).await.is_err() {
+                        error!("Send error on channel.");
+                    }
                 }
             }
         }
@@ -158,187 +649,858 @@ async fn sender(
 }
 
 /// Initializes the network and readies the reactor to process the messages.
-pub async fn main(c: Config, base_prf: SetupRelay, bulk_prf: SetupRelay) {
-    let (boardcast_channels_send, boardcast_channels_recv) = unbounded::<Sender<Vec<u8>>>();
-    let (reactor_input_channel_send, reactor_input_channel_recv) = unbounded::<Vec<u8>>();
+/// `base_prf` and `bulk_prf` hold the setup of the base and bulk rounds.
+/// `identity` authenticates the relay to the nodes and the nodes to the relay,
+/// with the client keys replaced on every reconfiguration by the guard.
+/// Fails if the address cannot be bound or the output cannot be opened.
+pub async fn main(
+    c: Config,
+    base_prf: RelaySetup,
+    bulk_prf: RelaySetup,
+    identity: Identity,
+) -> Result<(), Error> {
+    let (boardcast_channels_send, boardcast_channels_recv) = unbounded::<Connection>();
+    let (reactor_input_channel_send, reactor_input_channel_recv) =
+        unbounded::<(Option<Peer>, Vec<u8>)>();
     let (reactor_output_channel_send, reactor_output_channel_recv) = unbounded::<Vec<u8>>();
+    let (disconnect_channel_send, disconnect_channel_recv) = unbounded::<Peer>();
+    let sink = crate::sink::open(&c.output)?;
+    let metrics = Metrics::default();
+    let identity = RefCell::new(identity);
+    let tcp_listener = TcpListener::bind(c.server_addr).await?;
     select!(
-        () = listener(&c, reactor_input_channel_send, boardcast_channels_send).fuse() => {},
-        () = sender(boardcast_channels_recv, reactor_output_channel_recv).fuse() => {},
+        () = listener(
+            &c,
+            tcp_listener,
+            Some(&identity),
+            reactor_input_channel_send,
+            boardcast_channels_send
+        ).fuse() => {},
+        () = sender(
+            boardcast_channels_recv,
+            reactor_output_channel_recv,
+            disconnect_channel_recv
+        ).fuse() => {},
         () = reactor(
             &c,
             base_prf,
             bulk_prf,
+            &identity,
+            sink,
+            &metrics,
             reactor_input_channel_recv,
-            reactor_output_channel_send
+            reactor_output_channel_send,
+            disconnect_channel_send
         ).fuse() => {
             debug!("Main finished.");
         }
     );
+    info!("{:?}", metrics);
+    Ok(())
 }
 
 /// Prepares the base and bulk round reactors and route the message accordingly.
+/// The payloads of the bulk round go to `sink` if there is one. Peers that
+/// break the protocol, or whose key the guard revokes in `identity`, are sent
+/// to `disconnect_channel`. Messages from clients that are not members in
+/// their round are dropped. The reactors count what happens in `metrics`.
+#[allow(clippy::too_many_arguments)]
 pub async fn reactor(
     c: &Config,
-    base_prf: SetupRelay,
-    bulk_prf: SetupRelay,
-    reactor_input_channel: Receiver<Vec<u8>>,
+    base_prf: RelaySetup,
+    bulk_prf: RelaySetup,
+    identity: &RefCell<Identity>,
+    sink: Option<Box<dyn Sink>>,
+    metrics: &Metrics,
+    reactor_input_channel: Receiver<(Option<Peer>, Vec<u8>)>,
     reactor_output_channel: Sender<Vec<u8>>,
+    disconnect_channel: Sender<Peer>,
 ) {
     let (base_input_channel_send, base_input_channel_recv) = unbounded::<ClientBaseMessage>();
     let (bulk_input_channel_send, bulk_input_channel_recv) = unbounded::<ClientBulkMessage>();
-    let msg_dist = || async move {
-        loop {
-            let message = &reactor_input_channel.recv().await.unwrap();
-            info!("Got message of size {}.", message.len());
-            let message: Message = bincode::deserialize(message).unwrap();
-            match message {
-                Message::ClientBaseMessage(msg) => {
-                    base_input_channel_send.send(msg).await.unwrap();
-                }
-                Message::ClientBulkMessage(msg) => {
-                    bulk_input_channel_send.send(msg).await.unwrap();
+    let (blame_input_channel_send, blame_input_channel_recv) = unbounded::<BlameInput>();
+    let (base_blame_channel_send, base_blame_channel_recv) = unbounded::<ClientBaseBlameMessage>();
+    let (base_recovery_channel_send, base_recovery_channel_recv) =
+        unbounded::<ClientRecoveryMessage>();
+    let (bulk_recovery_channel_send, bulk_recovery_channel_recv) =
+        unbounded::<ClientRecoveryMessage>();
+    let (participant_channel_send, participant_channel_recv) = unbounded::<Vec<usize>>();
+    let (finished_channel_send, finished_channel_recv) = unbounded::<usize>();
+    let reservations = &RefCell::new(HashMap::<usize, Vec<Integer>>::new());
+    let blame = &RefCell::new(BlameState::default());
+    let epochs = &RefCell::new(vec![Epoch {
+        from_round: 1,
+        base: Rc::new(base_prf),
+        bulk: Rc::new(bulk_prf),
+    }]);
+    let msg_dist = || {
+        let blame_input_channel_send = blame_input_channel_send.clone();
+        let disconnect_channel = &disconnect_channel;
+        async move {
+            loop {
+                let (peer, message) = &reactor_input_channel.recv().await.unwrap();
+                info!("Got message of size {}.", message.len());
+                let message: Message = match bincode::deserialize(message) {
+                    Ok(message) => message,
+                    Err(_) => {
+                        disconnect(disconnect_channel, *peer, ProtocolError::Malformed(*peer))
+                            .await;
+                        continue;
+                    }
+                };
+                // Drop everything from clients excluded by the blame protocol,
+                // or not members in the round of the message.
+                let sender = match &message {
+                    Message::ClientBaseMessage(msg) => Some((msg.nid, msg.round)),
+                    Message::ClientBulkMessage(msg) => Some((msg.nid, msg.round)),
+                    // An accusation does not name its sender, so that it does
+                    // not tie the accuser to its slot group for the clients.
+                    Message::ClientAccusationMessage(msg) => match peer {
+                        Some(Peer::Client(nid)) => Some((*nid, msg.round)),
+                        _ => {
+                            disconnect(disconnect_channel, *peer, ProtocolError::Malformed(*peer))
+                                .await;
+                            continue;
+                        }
+                    },
+                    Message::ClientBlameMessage(msg) => Some((msg.nid, msg.round)),
+                    Message::ClientBaseBlameMessage(msg) => Some((msg.nid, msg.round)),
+                    Message::ClientRecoveryMessage(msg) => Some((msg.nid, msg.round)),
+                    _ => None,
+                };
+                if let Some((nid, round)) = sender {
+                    if let Err(e) = check_sender(*peer, nid) {
+                        disconnect(disconnect_channel, *peer, e).await;
+                        continue;
+                    }
+                    if !get_epoch(epochs, round).base.members.contains(&nid) {
+                        warn!(
+                            "Dropping message: {}.",
+                            ProtocolError::NotMember { nid, round }
+                        );
+                        continue;
+                    }
+                    if blame.borrow().excluded.contains(&nid) {
+                        warn!("Dropping message from excluded client {}.", nid);
+                        continue;
+                    }
                 }
-                _ => {
-                    error!("Unknown message {:?}.", message);
+                match message {
+                    Message::ClientBaseMessage(msg) => {
+                        base_input_channel_send.send(msg).await.unwrap();
+                    }
+                    Message::ClientBulkMessage(msg) => {
+                        bulk_input_channel_send.send(msg).await.unwrap();
+                    }
+                    Message::ClientAccusationMessage(msg) => {
+                        blame_input_channel_send
+                            .send(BlameInput::Accusation(msg))
+                            .await
+                            .unwrap();
+                    }
+                    Message::ClientBlameMessage(msg) => {
+                        blame_input_channel_send
+                            .send(BlameInput::Opening(msg))
+                            .await
+                            .unwrap();
+                    }
+                    Message::ClientBaseBlameMessage(msg) => {
+                        base_blame_channel_send.send(msg).await.unwrap();
+                    }
+                    Message::ClientRecoveryMessage(msg) => match msg.phase {
+                        Phase::Base => base_recovery_channel_send.send(msg).await.unwrap(),
+                        Phase::Bulk => bulk_recovery_channel_send.send(msg).await.unwrap(),
+                    },
+                    Message::GuardReconfigureMessage(_) if *peer != Some(Peer::Guard) => {
+                        disconnect(
+                            disconnect_channel,
+                            *peer,
+                            ProtocolError::Unauthorized(*peer),
+                        )
+                        .await;
+                    }
+                    Message::GuardReconfigureMessage(msg) => {
+                        info!(
+                            "Switching to members {:?} from round {}.",
+                            msg.base.members, msg.from_round
+                        );
+                        {
+                            let mut epochs = epochs.borrow_mut();
+                            epochs.retain(|epoch| epoch.from_round < msg.from_round);
+                            epochs.push(Epoch {
+                                from_round: msg.from_round,
+                                base: Rc::new(msg.base),
+                                bulk: Rc::new(msg.bulk),
+                            });
+                        }
+                        // Cut off the clients whose key changed, so that a
+                        // removed client cannot connect again.
+                        let old =
+                            std::mem::replace(&mut identity.borrow_mut().clients, msg.clients);
+                        let revoked: Vec<usize> = (0..old.len())
+                            .filter(|nid| identity.borrow().clients.get(*nid) != Some(&old[*nid]))
+                            .collect();
+                        for nid in revoked {
+                            info!("Revoking the key of client {}.", nid);
+                            let _ = disconnect_channel.send(Peer::Client(nid)).await;
+                        }
+                    }
+                    // Only the relay sends the other messages.
+                    _ => {
+                        disconnect(disconnect_channel, *peer, ProtocolError::Malformed(*peer))
+                            .await;
+                    }
                 }
             }
         }
     };
     select!(
         () = msg_dist().fuse() => {},
+        () = reactor_blame(
+            c,
+            epochs,
+            blame,
+            blame_input_channel_recv,
+            reactor_output_channel.clone()
+        ).fuse() => {},
         ((), ()) = join(reactor_base_round(
             c,
-            base_prf,
+            epochs,
+            blame,
+            reservations,
+            metrics,
             base_input_channel_recv,
-            reactor_output_channel.clone()
+            base_recovery_channel_recv,
+            base_blame_channel_recv,
+            participant_channel_send,
+            finished_channel_recv,
+            reactor_output_channel.clone(),
+            disconnect_channel.clone()
         ),
         reactor_bulk_round(
             c,
-            bulk_prf,
+            epochs,
+            blame,
+            reservations,
+            metrics,
             bulk_input_channel_recv,
-            reactor_output_channel.clone()
+            bulk_recovery_channel_recv,
+            participant_channel_recv,
+            finished_channel_send,
+            blame_input_channel_send.clone(),
+            sink,
+            reactor_output_channel.clone(),
+            disconnect_channel.clone()
         )).fuse() => {
             debug!("Reactor finished.");
         }
     );
 }
 
-/// Verifies the PRF if simulating blame protocol.
-fn verify(
-    params: &ProtocolParams,
-    msg: &[Integer],
-    msg_b: &[Integer],
-    e: &[Vec<u8>],
-    qw: &[Vec<u8>],
+/// Checks whether every expected client that is not excluded has contributed
+/// to `aggregate`.
+fn is_complete(expected: &[usize], aggregate: &Aggregate, blame: &RefCell<BlameState>) -> bool {
+    let excluded = &blame.borrow().excluded;
+    expected
+        .iter()
+        .filter(|nid| !excluded.contains(nid))
+        .all(|nid| aggregate.contains(*nid))
+}
+
+/// Sends `output` to the outside world through `uplink` and logs the round trip.
+async fn egress_round_trip(uplink: &mut dyn Uplink, output: &[u8]) {
+    match uplink.round_trip(output).await {
+        Ok(elapsed) => info!("Egress round trip took {:?}.", elapsed),
+        Err(e) => error!("Egress round trip failed: {}.", e),
+    }
+}
+
+/// Returns the deadline of a phase starting now, if there is one.
+fn get_deadline(c: &Config) -> Option<Instant> {
+    c.round_timeout
+        .map(|timeout| Instant::now() + Duration::from_millis(timeout))
+}
+
+/// Receives the next message, or returns `None` once `deadline` has passed.
+async fn recv_until<T>(channel: &Receiver<T>, deadline: Option<Instant>) -> Option<T> {
+    match deadline {
+        Some(deadline) => timeout(
+            deadline.saturating_duration_since(Instant::now()),
+            channel.recv(),
+        )
+        .await
+        .ok()
+        .map(|msg| msg.unwrap()),
+        None => Some(channel.recv().await.unwrap()),
+    }
+}
+
+/// Checks the shares of the seed of the self mask a client sent along with
+/// its message, which must be there exactly with `Config::self_masks`.
+fn has_self_shares(
+    c: &Config,
+    self_shares: &Option<SelfShares>,
+    nid: usize,
+    members: &[usize],
 ) -> bool {
-    msg.par_iter()
-        .zip(msg_b.par_iter())
-        .zip(e.par_iter())
-        .zip(qw.par_iter())
-        .all(|(((a, b), c), d)| {
-            add(
-                params,
-                &add(
-                    params,
-                    &mul(
-                        params,
-                        &get_g(params),
-                        &Integer::from(a * &params.ring_v.order),
-                    ),
-                    &mul(
-                        params,
-                        &get_h(params),
-                        &Integer::from(b * &params.ring_v.order),
-                    ),
-                ),
-                &from_bytes(params, c),
-            )
-            .eq(
-                params.group.as_ref().unwrap(),
-                &from_bytes(params, d),
-                &mut new_big_num_context(),
+    match self_shares {
+        Some(self_shares) => c.self_masks() && is_well_formed(self_shares, nid, members),
+        None => !c.self_masks(),
+    }
+}
+
+/// Removes the self masks with `seeds` from the messages of their senders,
+/// so that the blame protocol sees the messages masked by the keys only.
+fn strip_self_masks<'a>(
+    params: &ProtocolParams,
+    seeds: &HashMap<usize, Integer>,
+    messages: impl Iterator<Item = (&'a usize, &'a mut PackedVec)>,
+) {
+    for (nid, message) in messages {
+        if let Some(seed) = seeds.get(nid) {
+            let values: Vec<Integer> = message
+                .unpack()
+                .into_iter()
+                .zip(self_mask(params, seed, message.len()))
+                .map(|(x, m)| (x - m + &params.q) % &params.q)
+                .collect();
+            *message = PackedVec::pack(&values, width(&params.q));
+        }
+    }
+}
+
+/// Returns the first `len` elements of the sum of the masks of the `present`
+/// clients for `attempt` of `round` in `phase`, and the seeds of their self
+/// masks. `masks` holds the masks of the relay.
+///
+/// With `Config::self_masks`, asks the present clients to reveal their
+/// shares of the seeds dealt in `shares`, and to recover the masks of the
+/// missing clients if there are any. Waits until every present client has
+/// answered or the deadline has passed. Returns `None` if a present client
+/// did not recover the masks of the missing ones, or if fewer than
+/// `recovery_threshold` valid shares of a seed came in.
+#[allow(clippy::too_many_arguments)]
+async fn get_mask(
+    c: &Config,
+    relay: &RelaySetup,
+    masks: &Masks,
+    phase: Phase,
+    round: usize,
+    attempt: usize,
+    len: usize,
+    present: &[usize],
+    shares: &HashMap<usize, SelfShares>,
+    recovery_channel: &Receiver<ClientRecoveryMessage>,
+    reactor_output_channel: &Sender<Vec<u8>>,
+) -> Option<(Vec<Integer>, HashMap<usize, Integer>)> {
+    let params = match phase {
+        Phase::Base => &c.base_params,
+        Phase::Bulk => &c.bulk_params,
+    };
+    // Without self masks, nobody is ever missing.
+    if !c.self_masks() {
+        let mut mask = masks.get(params, round, attempt);
+        mask.truncate(len);
+        return Some((mask, HashMap::new()));
+    }
+    let missing: Vec<usize> = relay
+        .members
+        .iter()
+        .copied()
+        .filter(|nid| !present.contains(nid))
+        .collect();
+    if !missing.is_empty() {
+        warn!(
+            "Recovering the masks of {:?} in the {:?} round {}.",
+            missing, phase, round
+        );
+    }
+    let message = bincode::serialize(&Message::ServerRecoveryRequestMessage(
+        ServerRecoveryRequestMessage {
+            round,
+            attempt,
+            phase,
+            missing: missing.clone(),
+            shares: present
+                .iter()
+                .filter_map(|nid| shares.get(nid).map(|s| (*nid, s.encrypted.clone())))
+                .collect(),
+        },
+    ))
+    .unwrap();
+    info!(
+        "Sending ServerRecoveryRequestMessage, size = {}...",
+        message.len()
+    );
+    reactor_output_channel.send(message).await.unwrap();
+    info!("Sent ServerRecoveryRequestMessage.");
+    let recovery_len = if missing.is_empty() {
+        0
+    } else {
+        params.vector_len
+    };
+    let deadline = Some(get_deadline(c).unwrap_or_else(|| Instant::now() + RECOVERY_TIMEOUT));
+    let mut answers = HashMap::<usize, ClientRecoveryMessage>::new();
+    while answers.len() < present.len() {
+        let msg = match recv_until(recovery_channel, deadline).await {
+            Some(msg) => msg,
+            None => {
+                warn!("Recovery in the {:?} round {} timed out.", phase, round);
+                break;
+            }
+        };
+        info!(
+            "Received ClientRecoveryMessage from {} on round {}, attempt {}.",
+            msg.nid, msg.round, msg.attempt
+        );
+        if msg.round == round
+            && msg.phase == phase
+            && msg.attempt == attempt
+            && present.contains(&msg.nid)
+            && msg.recovery.len() == recovery_len
+        {
+            if let Err(e) = insert_once(&mut answers, msg.nid, round, msg) {
+                error!("Rejecting ClientRecoveryMessage: {}.", e);
+            }
+        }
+    }
+    // The vectors a present client shares with the missing ones are only
+    // known to the two of them.
+    if !missing.is_empty() && answers.len() < present.len() {
+        warn!(
+            "Cannot recover the masks of {:?} in the {:?} round {}.",
+            missing, phase, round
+        );
+        return None;
+    }
+    let threshold = recovery_threshold(relay.members.len());
+    let mut seeds = HashMap::new();
+    for dealer in present {
+        let revealed: BTreeMap<usize, &Integer> = answers
+            .iter()
+            .filter_map(|(nid, msg)| msg.shares.get(dealer).map(|share| (*nid, share)))
+            .collect();
+        let seed = shares.get(dealer).and_then(|shares| {
+            reconstruct_seed(
+                *dealer,
+                &shares.digests,
+                &revealed,
+                threshold,
+                round,
+                phase,
+                attempt,
             )
-            .unwrap()
-        })
+        });
+        match seed {
+            Some(seed) => seeds.insert(*dealer, seed),
+            None => {
+                warn!(
+                    "Cannot reconstruct the self mask of {} in the {:?} round {}.",
+                    dealer, phase, round
+                );
+                return None;
+            }
+        };
+    }
+    let mut mask = if missing.is_empty() {
+        masks.get(params, round, attempt)
+    } else {
+        let recoveries: Vec<Vec<Integer>> = answers.into_values().map(|msg| msg.recovery).collect();
+        relay_mask(params, relay, masks, round, attempt, present, &recoveries)
+    };
+    mask.truncate(len);
+    for seed in seeds.values() {
+        for (x, m) in mask.iter_mut().zip(self_mask(params, seed, len)) {
+            *x = (&*x + m) % &params.q;
+        }
+    }
+    Some((mask, seeds))
+}
+
+/// Blame protocol handler. Finds the clients that do not open an accused
+/// slot group before the deadline guilty.
+pub async fn reactor_blame(
+    c: &Config,
+    epochs: &RefCell<Vec<Epoch>>,
+    blame: &RefCell<BlameState>,
+    blame_input_channel: Receiver<BlameInput>,
+    reactor_output_channel: Sender<Vec<u8>>,
+) {
+    loop {
+        let deadline = blame.borrow().deadline();
+        let input = recv_until(&blame_input_channel, deadline.map(|(_, deadline)| deadline)).await;
+        let outputs = match (input, deadline) {
+            (Some(BlameInput::Record(round, perm, messages)), _) => {
+                blame.borrow_mut().record(round, perm, messages);
+                Vec::new()
+            }
+            (Some(BlameInput::Accusation(msg)), _) => blame.borrow_mut().accuse(c, msg),
+            (Some(BlameInput::Opening(msg)), _) => {
+                let bulk_prf = get_epoch(epochs, msg.round).bulk;
+                blame.borrow_mut().open(c, &bulk_prf, msg)
+            }
+            (None, Some((round, _))) => {
+                let bulk_prf = get_epoch(epochs, round).bulk;
+                blame.borrow_mut().expire(c, &bulk_prf)
+            }
+            (None, None) => Vec::new(),
+        };
+        for output in outputs {
+            let message = bincode::serialize(&output).unwrap();
+            info!("Sending blame message, size = {}...", message.len());
+            reactor_output_channel.send(message).await.unwrap();
+        }
+    }
+}
+
+/// Asks the `present` clients to open `attempt` of the base round `round`,
+/// whose unmasked `sums` failed to solve, and finds the ones whose message in
+/// `messages` is malformed. Waits for the openings on `base_blame_channel` until
+/// every present client has answered or the deadline has passed.
+#[allow(clippy::too_many_arguments)]
+async fn blame_base_round(
+    c: &Config,
+    base_prf: &RelaySetup,
+    round: usize,
+    attempt: usize,
+    sums: Vec<Integer>,
+    present: &[usize],
+    messages: &HashMap<usize, ClientBaseMessage>,
+    base_blame_channel: &Receiver<ClientBaseBlameMessage>,
+    reactor_output_channel: &Sender<Vec<u8>>,
+) -> Vec<usize> {
+    warn!("Blaming attempt {} of base round {}.", attempt, round);
+    let message = bincode::serialize(&Message::ServerBaseBlameRequestMessage(
+        ServerBaseBlameRequestMessage {
+            round,
+            attempt,
+            sums,
+        },
+    ))
+    .unwrap();
+    reactor_output_channel.send(message).await.unwrap();
+    let deadline = Some(blame_deadline(c));
+    let mut openings = HashMap::<usize, ClientBaseBlameMessage>::new();
+    while openings.len() < present.len() {
+        let msg = match recv_until(base_blame_channel, deadline).await {
+            Some(msg) => msg,
+            None => {
+                warn!("Blame on base round {} timed out.", round);
+                break;
+            }
+        };
+        if msg.round == round && msg.attempt == attempt && present.contains(&msg.nid) {
+            if let Err(e) = insert_once(&mut openings, msg.nid, round, msg) {
+                error!("Rejecting ClientBaseBlameMessage: {}.", e);
+            }
+        }
+    }
+    let guilty = find_base_guilty(c, base_prf, round, attempt, present, messages, &openings);
+    warn!(
+        "Blame on attempt {} of base round {} finds {:?} guilty.",
+        attempt, round, guilty
+    );
+    let message = bincode::serialize(&Message::ServerBlameResultMessage(
+        ServerBlameResultMessage {
+            round,
+            slot: None,
+            guilty: guilty.clone(),
+        },
+    ))
+    .unwrap();
+    reactor_output_channel.send(message).await.unwrap();
+    guilty
 }
 
-/// Base round handler.
+/// Base round handler. Reports the clients that take part in each round to
+/// the bulk round handler through `participant_channel` and the IDs of the
+/// slot groups they reserved through `reservations`, and runs at most
+/// `pipeline_depth` rounds ahead of the bulk rounds reported finished through
+/// `finished_channel`. Clients that send malformed messages are sent to
+/// `disconnect_channel`. With `do_blame`, an attempt that fails to solve is
+/// opened through `base_blame_channel` and the guilty clients are excluded.
+/// After `MAX_ATTEMPTS` failed attempts, the round goes on without slot
+/// groups. Retries and given up rounds are counted in `metrics`.
+#[allow(clippy::too_many_arguments)]
 pub async fn reactor_base_round(
     c: &Config,
-    base_prf: SetupRelay,
+    epochs: &RefCell<Vec<Epoch>>,
+    blame: &RefCell<BlameState>,
+    reservations: &RefCell<HashMap<usize, Vec<Integer>>>,
+    metrics: &Metrics,
     base_input_channel: Receiver<ClientBaseMessage>,
+    recovery_channel: Receiver<ClientRecoveryMessage>,
+    base_blame_channel: Receiver<ClientBaseBlameMessage>,
+    participant_channel: Sender<Vec<usize>>,
+    finished_channel: Receiver<usize>,
     reactor_output_channel: Sender<Vec<u8>>,
+    disconnect_channel: Sender<Peer>,
 ) {
-    let mut base_protocol_buffer = HashMap::<usize, HashMap<usize, ClientBaseMessage>>::new();
+    // The equation only needs the power sums and the number of reservations.
+    let new_aggregate =
+        || Aggregate::new(&c.base_params.q, reservation_capacity(c) + 1, c.client_size);
+    let mut base_protocol_buffer = HashMap::<(usize, usize), Aggregate>::new();
+    // The full messages are only kept if a failed attempt may be blamed.
+    let mut base_records = HashMap::<(usize, usize), HashMap<usize, ClientBaseMessage>>::new();
+    let mut base_shares = HashMap::<(usize, usize), HashMap<usize, SelfShares>>::new();
+    let masks = Masks::relay(&c.base_params, c.round, !c.do_unzip);
     let mut round: usize = 0;
+    let mut finished: usize = 0;
     loop {
         round += 1;
         if round > c.round {
-            info!("Base round finished.");
+            info!(
+                "Base round finished with {} retries in total.",
+                metrics.base_retries.get()
+            );
             return;
         }
-        info!("Base round {}.", round);
-        if base_protocol_buffer.get(&round).is_none() {
-            base_protocol_buffer.insert(round, HashMap::new());
+        // Messages of the next rounds stay buffered in the meantime.
+        while finished + c.pipeline_depth < round {
+            finished = finished_channel.recv().await.unwrap();
         }
+        info!("Base round {}.", round);
+        let base_prf = get_epoch(epochs, round).base;
+        let expected = &base_prf.members;
+        let mut attempt: usize = 0;
+        let mut deadline = get_deadline(c);
+        base_protocol_buffer
+            .entry((round, attempt))
+            .or_insert_with(new_aggregate);
         loop {
-            let msg = base_input_channel.recv().await.unwrap();
-            info!(
-                "Received ClientBaseMessage from {} on round {}.",
-                msg.nid, msg.round
-            );
-            if base_protocol_buffer.get(&msg.round).is_none() {
-                base_protocol_buffer.insert(msg.round, HashMap::new());
-            }
-            // Verify the PRF if doing blame protocol simulation.
-            if c.do_blame
-                && !verify(
-                    &c.base_params,
-                    msg.blame.as_ref().unwrap(),
-                    msg.blame_blinding.as_ref().unwrap(),
-                    msg.e.as_ref().unwrap(),
-                    &base_prf.qw.as_ref().unwrap()[msg.nid],
-                )
-            {
-                warn!("Blame protocol verification failure for {}.", msg.nid);
-            }
-            base_protocol_buffer
-                .get_mut(&msg.round)
-                .unwrap()
-                .insert(msg.nid, msg);
-            if base_protocol_buffer.get(&round).unwrap().len() == c.client_size {
-                info!("All base messages received. Computing...");
-                let mut scaled = base_prf.values.share.scaled.clone();
-                // Compute PRF on-demand if needed.
-                if c.do_unzip {
-                    scaled = crate::prf::compute(&c.base_params, &base_prf.values);
+            match recv_until(&base_input_channel, deadline).await {
+                Some(msg) => {
+                    info!(
+                        "Received ClientBaseMessage from {} on round {}, attempt {}.",
+                        msg.nid, msg.round, msg.attempt
+                    );
+                    // Messages of an abandoned attempt or a finished round are of no use.
+                    if msg.round < round || (msg.round == round && msg.attempt < attempt) {
+                        continue;
+                    }
+                    // A client has at most `pipeline_depth` rounds in flight,
+                    // the oldest of which is at most this one, and only runs
+                    // the attempts the relay asked for.
+                    let next_attempt = if msg.round == round { attempt } else { 0 };
+                    if msg.round >= round + c.pipeline_depth
+                        || msg.round > c.round
+                        || msg.attempt > next_attempt
+                    {
+                        let peer = Some(Peer::Client(msg.nid));
+                        let e = ProtocolError::OutOfWindow {
+                            nid: msg.nid,
+                            round: msg.round,
+                            attempt: msg.attempt,
+                        };
+                        disconnect(&disconnect_channel, peer, e).await;
+                        continue;
+                    }
+                    let members = &get_epoch(epochs, msg.round).base.members;
+                    if !msg
+                        .slot_messages
+                        .is_shaped(c.base_params.vector_len, width(&c.base_params.q))
+                        || !has_self_shares(c, &msg.self_shares, msg.nid, members)
+                    {
+                        let peer = Some(Peer::Client(msg.nid));
+                        disconnect(&disconnect_channel, peer, ProtocolError::Malformed(peer)).await;
+                        continue;
+                    }
+                    let aggregate = base_protocol_buffer
+                        .entry((msg.round, msg.attempt))
+                        .or_insert_with(new_aggregate);
+                    if !aggregate.add(msg.nid, &msg.slot_messages) {
+                        error!(
+                            "Rejecting ClientBaseMessage: {}.",
+                            ProtocolError::Duplicate {
+                                nid: msg.nid,
+                                round: msg.round
+                            }
+                        );
+                        continue;
+                    }
+                    if let Some(self_shares) = msg.self_shares.clone() {
+                        base_shares
+                            .entry((msg.round, msg.attempt))
+                            .or_default()
+                            .insert(msg.nid, self_shares);
+                    }
+                    if c.do_blame {
+                        base_records
+                            .entry((msg.round, msg.attempt))
+                            .or_default()
+                            .insert(msg.nid, msg);
+                    }
+                    if !is_complete(
+                        expected,
+                        base_protocol_buffer.get(&(round, attempt)).unwrap(),
+                        blame,
+                    ) {
+                        continue;
+                    }
+                    info!("All base messages received. Computing...");
+                }
+                None => {
+                    if base_protocol_buffer[&(round, attempt)].is_empty() {
+                        warn!("No base messages on round {} before the deadline.", round);
+                        deadline = get_deadline(c);
+                        continue;
+                    }
+                    warn!("Base round {} timed out. Computing...", round);
                 }
-                // Solve the equation to find out the permutation.
-                let perm = solve_equation(c, &scaled, base_protocol_buffer.get(&round).unwrap());
-                let message = bincode::serialize(&Message::ServerBaseMessage(ServerBaseMessage {
-                    round,
-                    perm,
-                }))
-                .unwrap();
-                info!("Sending ServerBaseMessage, size = {}...", message.len());
-                reactor_output_channel.send(message).await.unwrap();
-                info!("Sent ServerBaseMessage.");
-                base_protocol_buffer.remove(&round);
-                break;
             }
+            // A client excluded after its vector was added still takes part in
+            // this attempt, since its vector cannot be taken out of the sum.
+            let aggregate = base_protocol_buffer.remove(&(round, attempt)).unwrap();
+            let present = aggregate.contributors();
+            let shares = base_shares.remove(&(round, attempt)).unwrap_or_default();
+            let mut records = base_records.remove(&(round, attempt)).unwrap_or_default();
+            let unmasked = get_mask(
+                c,
+                &base_prf,
+                &masks,
+                Phase::Base,
+                round,
+                attempt,
+                reservation_capacity(c) + 1,
+                &present,
+                &shares,
+                &recovery_channel,
+                &reactor_output_channel,
+            )
+            .await;
+            // Solve the equation to find out the permutation.
+            // Re-run the base round with fresh IDs and masks on a collision,
+            // or if the sums do not solve because of a bad message or mask.
+            // With `do_blame`, the clients open an attempt that does not solve
+            // first, and the ones that sent a malformed message are excluded.
+            let perm = match unmasked {
+                None => {
+                    warn!("Failed to unmask base round {}.", round);
+                    None
+                }
+                Some((scaled, seeds)) => {
+                    strip_self_masks(
+                        &c.base_params,
+                        &seeds,
+                        records
+                            .iter_mut()
+                            .map(|(nid, msg)| (nid, &mut msg.slot_messages)),
+                    );
+                    let sums = unmask_sums(c, &scaled, &aggregate);
+                    match solve_sums(c, &sums) {
+                        Ok(perm) if !has_collision(&perm) => Some(perm),
+                        Ok(_) => {
+                            warn!("Collision in base round {}.", round);
+                            None
+                        }
+                        Err(e) => {
+                            warn!("Failed to solve base round {}: {:?}.", round, e);
+                            if c.do_blame {
+                                let guilty = blame_base_round(
+                                    c,
+                                    &base_prf,
+                                    round,
+                                    attempt,
+                                    sums,
+                                    &present,
+                                    &records,
+                                    &base_blame_channel,
+                                    &reactor_output_channel,
+                                )
+                                .await;
+                                blame.borrow_mut().excluded.extend(guilty);
+                            }
+                            None
+                        }
+                    }
+                }
+            };
+            let perm = match perm {
+                Some(perm) => perm,
+                // Give up on the round rather than retry forever. No client
+                // finds its IDs, so the bulk round has no slot groups.
+                None if attempt + 1 >= MAX_ATTEMPTS => {
+                    error!(
+                        "Giving up base round {} after {} attempts.",
+                        round, MAX_ATTEMPTS
+                    );
+                    metrics.aborted_rounds.set(metrics.aborted_rounds.get() + 1);
+                    Vec::new()
+                }
+                None => {
+                    attempt += 1;
+                    metrics.base_retries.set(metrics.base_retries.get() + 1);
+                    warn!("Retrying base round {} with attempt {}.", round, attempt);
+                    deadline = get_deadline(c);
+                    base_protocol_buffer
+                        .entry((round, attempt))
+                        .or_insert_with(new_aggregate);
+                    let message = bincode::serialize(&Message::ServerBaseRetryMessage(
+                        ServerBaseRetryMessage { round, attempt },
+                    ))
+                    .unwrap();
+                    info!(
+                        "Sending ServerBaseRetryMessage, size = {}...",
+                        message.len()
+                    );
+                    reactor_output_channel.send(message).await.unwrap();
+                    info!("Sent ServerBaseRetryMessage.");
+                    continue;
+                }
+            };
+            info!("Base round {} needed {} retries.", round, attempt);
+            info!("{:?}", metrics);
+            reservations.borrow_mut().insert(round, perm.clone());
+            participant_channel.send(present).await.unwrap();
+            let message = bincode::serialize(&Message::ServerBaseMessage(ServerBaseMessage {
+                round,
+                perm,
+            }))
+            .unwrap();
+            info!("Sending ServerBaseMessage, size = {}...", message.len());
+            reactor_output_channel.send(message).await.unwrap();
+            info!("Sent ServerBaseMessage.");
+            base_protocol_buffer.retain(|(r, _), _| *r > round);
+            base_records.retain(|(r, _), _| *r > round);
+            base_shares.retain(|(r, _), _| *r > round);
+            break;
         }
     }
 }
 
-/// Bulk round handler.
+/// Bulk round handler. Waits for the clients reported by the base round
+/// handler through `participant_channel`, with as many slot groups as they
+/// reserved IDs in `reservations`, and reports each finished round
+/// through `finished_channel`. Messages of later rounds are held back until
+/// the participants of their round are reported. Publishes the payloads to `sink` if there is
+/// one. Clients that send malformed or premature messages are sent to
+/// `disconnect_channel`. Rounds given up are counted in `metrics`.
+#[allow(clippy::too_many_arguments)]
 pub async fn reactor_bulk_round(
     c: &Config,
-    bulk_prf: SetupRelay,
+    epochs: &RefCell<Vec<Epoch>>,
+    blame: &RefCell<BlameState>,
+    reservations: &RefCell<HashMap<usize, Vec<Integer>>>,
+    metrics: &Metrics,
     bulk_input_channel: Receiver<ClientBulkMessage>,
+    recovery_channel: Receiver<ClientRecoveryMessage>,
+    participant_channel: Receiver<Vec<usize>>,
+    finished_channel: Sender<usize>,
+    blame_input_channel: Sender<BlameInput>,
+    mut sink: Option<Box<dyn Sink>>,
     reactor_output_channel: Sender<Vec<u8>>,
+    disconnect_channel: Sender<Peer>,
 ) {
-    let mut bulk_protocol_buffer = HashMap::<usize, HashMap<usize, ClientBulkMessage>>::new();
+    let new_aggregate =
+        |groups: usize| Aggregate::new(&c.bulk_params.q, c.slot_per_round * groups, c.client_size);
+    let mut bulk_protocol_buffer = HashMap::<usize, Aggregate>::new();
+    let masks = Masks::relay(&c.bulk_params, c.round, !c.do_unzip);
+    let mut uplink = crate::egress::open(&c.egress, Rng::from_config(c, c.client_size + 1));
+    // The full messages are only kept if a client may accuse a slot.
+    let mut bulk_records = HashMap::<usize, HashMap<usize, ClientBulkMessage>>::new();
+    let mut bulk_shares = HashMap::<usize, HashMap<usize, SelfShares>>::new();
+    // Messages of later rounds wait until the participants of their round are known.
+    let mut early = HashMap::<usize, HashMap<usize, ClientBulkMessage>>::new();
     let mut round: usize = 0;
     loop {
         round += 1;
@@ -347,82 +1509,244 @@ pub async fn reactor_bulk_round(
             async_std::task::sleep(std::time::Duration::from_secs(5)).await;
             return;
         }
+        let expected = participant_channel.recv().await.unwrap();
         info!("Bulk round {}.", round);
-        if bulk_protocol_buffer.get(&round).is_none() {
-            bulk_protocol_buffer.insert(round, HashMap::new());
-        }
+        let bulk_prf = get_epoch(epochs, round).bulk;
+        let mut deadline = get_deadline(c);
+        let groups = reservations.borrow()[&round].len();
+        info!("Bulk round {} has {} slot groups.", round, groups);
+        bulk_protocol_buffer
+            .entry(round)
+            .or_insert_with(|| new_aggregate(groups));
+        let mut pending: VecDeque<ClientBulkMessage> = early
+            .remove(&round)
+            .unwrap_or_default()
+            .into_values()
+            .collect();
         loop {
-            let msg = bulk_input_channel.recv().await.unwrap();
-            info!(
-                "Received ClientBulkMessage from {} on round {}.",
-                msg.nid, msg.round
-            );
-            if bulk_protocol_buffer.get(&msg.round).is_none() {
-                bulk_protocol_buffer.insert(msg.round, HashMap::new());
-            }
-            bulk_protocol_buffer
-                .get_mut(&msg.round)
-                .unwrap()
-                .insert(msg.nid, msg);
-            if bulk_protocol_buffer.get(&round).unwrap().len() == c.client_size {
-                info!("All bulk messages received. Computing...");
-                let mut scaled = bulk_prf.values.share.scaled.clone();
-                if c.do_unzip {
-                    scaled = crate::prf::compute(&c.bulk_params, &bulk_prf.values);
-                }
-                // Remove the PRF and find the message.
-                compute_message(c, &scaled, bulk_protocol_buffer.get(&round).unwrap());
-                if c.do_ping {
+            let received = match pending.pop_front() {
+                Some(msg) => Some(msg),
+                None => recv_until(&bulk_input_channel, deadline).await,
+            };
+            match received {
+                Some(msg) => {
                     info!(
-                        "{}",
-                        std::str::from_utf8(
-                            &std::process::Command::new("ping")
-                                .arg("google.com")
-                                .arg("-c")
-                                .arg("1")
-                                .output()
-                                .unwrap()
-                                .stdout
-                        )
-                        .unwrap()
+                        "Received ClientBulkMessage from {} on round {}.",
+                        msg.nid, msg.round
+                    );
+                    // Only the clients that made it through the base round have a slot.
+                    if msg.round < round || (msg.round == round && !expected.contains(&msg.nid)) {
+                        continue;
+                    }
+                    // The base round of the message tells its length.
+                    let peer = Some(Peer::Client(msg.nid));
+                    let groups = reservations.borrow().get(&msg.round).map(Vec::len);
+                    let groups = match groups {
+                        Some(groups) => groups,
+                        None => {
+                            let e = ProtocolError::Premature {
+                                nid: msg.nid,
+                                round: msg.round,
+                            };
+                            disconnect(&disconnect_channel, peer, e).await;
+                            continue;
+                        }
+                    };
+                    if msg.round > round {
+                        let (nid, msg_round) = (msg.nid, msg.round);
+                        let buffered = early.entry(msg_round).or_default();
+                        if let Err(e) = insert_once(buffered, nid, msg_round, msg) {
+                            error!("Rejecting ClientBulkMessage: {}.", e);
+                        }
+                        continue;
+                    }
+                    let members = &get_epoch(epochs, msg.round).bulk.members;
+                    if !msg
+                        .slot_messages
+                        .is_shaped(c.slot_per_round * groups, width(&c.bulk_params.q))
+                        || !has_self_shares(c, &msg.self_shares, msg.nid, members)
+                    {
+                        disconnect(&disconnect_channel, peer, ProtocolError::Malformed(peer)).await;
+                        continue;
+                    }
+                    let aggregate = bulk_protocol_buffer
+                        .entry(msg.round)
+                        .or_insert_with(|| new_aggregate(groups));
+                    if !aggregate.add(msg.nid, &msg.slot_messages) {
+                        error!(
+                            "Rejecting ClientBulkMessage: {}.",
+                            ProtocolError::Duplicate {
+                                nid: msg.nid,
+                                round: msg.round
+                            }
+                        );
+                        continue;
+                    }
+                    if let Some(self_shares) = msg.self_shares.clone() {
+                        bulk_shares
+                            .entry(msg.round)
+                            .or_default()
+                            .insert(msg.nid, self_shares);
+                    }
+                    if c.do_blame {
+                        bulk_records
+                            .entry(msg.round)
+                            .or_default()
+                            .insert(msg.nid, msg);
+                    }
+                    if !is_complete(&expected, &bulk_protocol_buffer[&round], blame) {
+                        continue;
+                    }
+                    info!("All bulk messages received. Computing...");
+                }
+                None => {
+                    if bulk_protocol_buffer[&round].is_empty() {
+                        warn!("No bulk messages on round {} before the deadline.", round);
+                        deadline = get_deadline(c);
+                        continue;
+                    }
+                    warn!("Bulk round {} timed out. Computing...", round);
+                }
+            }
+            // A client excluded after its vector was added still takes part in
+            // this round, since its vector cannot be taken out of the sum.
+            let aggregate = bulk_protocol_buffer.remove(&round).unwrap();
+            let present = aggregate.contributors();
+            let shares = bulk_shares.remove(&round).unwrap_or_default();
+            let mut records = bulk_records.remove(&round).unwrap_or_default();
+            let unmasked = get_mask(
+                c,
+                &bulk_prf,
+                &masks,
+                Phase::Bulk,
+                round,
+                0,
+                c.slot_per_round * groups,
+                &present,
+                &shares,
+                &recovery_channel,
+                &reactor_output_channel,
+            )
+            .await;
+            // Give up on the round if it cannot be unmasked. The clients find
+            // no digests and skip their accusations.
+            let payloads = match unmasked {
+                Some((scaled, seeds)) => {
+                    strip_self_masks(
+                        &c.bulk_params,
+                        &seeds,
+                        records
+                            .iter_mut()
+                            .map(|(nid, msg)| (nid, &mut msg.slot_messages)),
                     );
+                    // Remove the PRF and find the message.
+                    compute_message(c, &scaled, &aggregate)
+                }
+                None => {
+                    error!("Giving up bulk round {}: cannot unmask it.", round);
+                    metrics.aborted_rounds.set(metrics.aborted_rounds.get() + 1);
+                    Vec::new()
+                }
+            };
+            for (i, payload) in payloads.iter().enumerate() {
+                match payload {
+                    Some(payload) => {
+                        info!(
+                            "Slot {} on round {}: {}",
+                            i,
+                            round,
+                            String::from_utf8_lossy(payload)
+                        );
+                        if let Some(sink) = sink.as_mut().filter(|_| !payload.is_empty()) {
+                            if let Err(e) = sink.publish(round, i, payload) {
+                                error!("Failed to publish slot {} on round {}: {}.", i, round, e);
+                            }
+                        }
+                    }
+                    None => warn!("Slot {} on round {} is malformed.", i, round),
                 }
-                let message = bincode::serialize(&Message::ServerBulkMessage).unwrap();
-                info!("Sending ServerBulkMessage, size = {}...", message.len());
-                reactor_output_channel.send(message).await.unwrap();
-                info!("Sent ServerBulkMessage.");
-                bulk_protocol_buffer.remove(&round);
-                break;
             }
+            if c.do_ping {
+                let output: Vec<u8> = payloads.iter().flatten().flatten().copied().collect();
+                egress_round_trip(uplink.as_mut(), &output).await;
+            }
+            // Keep the messages around in case a client accuses a slot.
+            if c.do_blame && !payloads.is_empty() {
+                let perm = reservations.borrow()[&round].clone();
+                blame_input_channel
+                    .send(BlameInput::Record(round, perm, records))
+                    .await
+                    .unwrap();
+            }
+            let message = bincode::serialize(&Message::ServerBulkMessage(ServerBulkMessage {
+                round,
+                digests: payloads
+                    .iter()
+                    .map(|payload| payload.as_deref().map(payload_digest))
+                    .collect(),
+            }))
+            .unwrap();
+            info!("Sending ServerBulkMessage, size = {}...", message.len());
+            reactor_output_channel.send(message).await.unwrap();
+            info!("Sent ServerBulkMessage.");
+            // The base round handler is gone once it has run every round.
+            let _ = finished_channel.send(round).await;
+            bulk_protocol_buffer.retain(|r, _| *r > round);
+            bulk_records.retain(|r, _| *r > round);
+            bulk_shares.retain(|r, _| *r > round);
+            early.retain(|r, _| *r > round);
+            reservations.borrow_mut().retain(|r, _| *r > round);
+            break;
         }
     }
 }
 
 // Prifi timing code below:
 /// PriFi main code.
-pub async fn main_prifi(c: Config) {
-    let (boardcast_channels_send, boardcast_channels_recv) = unbounded::<Sender<Vec<u8>>>();
-    let (reactor_input_channel_send, reactor_input_channel_recv) = unbounded::<Vec<u8>>();
+pub async fn main_prifi(c: Config) -> Result<(), Error> {
+    let (boardcast_channels_send, boardcast_channels_recv) = unbounded::<Connection>();
+    let (reactor_input_channel_send, reactor_input_channel_recv) =
+        unbounded::<(Option<Peer>, Vec<u8>)>();
     let (reactor_output_channel_send, reactor_output_channel_recv) = unbounded::<Vec<u8>>();
+    // Nobody is disconnected, but the channel stays open.
+    let (_disconnect_channel_send, disconnect_channel_recv) = unbounded::<Peer>();
+    let tcp_listener = TcpListener::bind(c.server_addr).await?;
     select!(
-        () = listener(&c, reactor_input_channel_send, boardcast_channels_send).fuse() => {},
-        () = sender(boardcast_channels_recv, reactor_output_channel_recv).fuse() => {},
+        () = listener(
+            &c,
+            tcp_listener,
+            None,
+            reactor_input_channel_send,
+            boardcast_channels_send
+        ).fuse() => {},
+        () = sender(
+            boardcast_channels_recv,
+            reactor_output_channel_recv,
+            disconnect_channel_recv
+        ).fuse() => {},
         () = reactor_prifi(&c, reactor_input_channel_recv, reactor_output_channel_send).fuse() => {}
     );
+    Ok(())
 }
 
 /// PriFi reactor.
 pub async fn reactor_prifi(
     c: &Config,
-    reactor_input_channel: Receiver<Vec<u8>>,
+    reactor_input_channel: Receiver<(Option<Peer>, Vec<u8>)>,
     reactor_output_channel: Sender<Vec<u8>>,
 ) {
     let (base_input_channel_send, base_input_channel_recv) = unbounded::<ClientPrifiMessage>();
     let msg_dist = || async move {
         loop {
-            let message = &reactor_input_channel.recv().await.unwrap();
+            let (_, message) = &reactor_input_channel.recv().await.unwrap();
             info!("Got message of size {}.", message.len());
-            let message: Message = bincode::deserialize(message).unwrap();
+            let message: Message = match bincode::deserialize(message) {
+                Ok(message) => message,
+                Err(e) => {
+                    warn!("Dropping undecodable message: {}.", e);
+                    continue;
+                }
+            };
             match message {
                 Message::ClientPrifiMessage(msg) => {
                     base_input_channel_send.send(msg).await.unwrap();
@@ -447,6 +1771,8 @@ pub async fn reactor_prifi_round(
 ) {
     let mut base_protocol_buffer = HashMap::<usize, HashMap<usize, ClientPrifiMessage>>::new();
     let mut round: usize = 0;
+    let mut rng = Rng::from_config(c, c.client_size);
+    let mut uplink = crate::egress::open(&c.egress, Rng::from_config(c, c.client_size + 1));
     loop {
         round += 1;
         if round > c.round {
@@ -455,69 +1781,352 @@ pub async fn reactor_prifi_round(
             return;
         }
         info!("Base round {}.", round);
-        if base_protocol_buffer.get(&round).is_none() {
-            base_protocol_buffer.insert(round, HashMap::new());
-        }
+        base_protocol_buffer.entry(round).or_default();
         loop {
             let msg = base_input_channel.recv().await.unwrap();
             info!(
                 "Received ClientBaseMessage from {} on round {}.",
                 msg.nid, msg.round
             );
-            if base_protocol_buffer.get(&msg.round).is_none() {
-                base_protocol_buffer.insert(msg.round, HashMap::new());
+            if msg.nid >= c.client_size
+                || msg.round < round
+                || msg.round > c.round
+                || msg.slot_messages.len() != c.client_size
+            {
+                warn!(
+                    "Dropping malformed ClientPrifiMessage from {} on round {}.",
+                    msg.nid, msg.round
+                );
+                continue;
             }
             base_protocol_buffer
-                .get_mut(&msg.round)
-                .unwrap()
+                .entry(msg.round)
+                .or_default()
                 .insert(msg.nid, msg);
-            if base_protocol_buffer.get(&round).unwrap().len() == c.client_size {
+            if base_protocol_buffer[&round].len() == c.client_size {
+                let mut messages: Vec<ClientPrifiMessage> = base_protocol_buffer
+                    .remove(&round)
+                    .unwrap_or_default()
+                    .into_values()
+                    .collect();
+                messages.sort_by_key(|msg| msg.nid);
                 info!("All prifi messages received. Computing...");
                 let nbits: usize = c.slot_per_round * 8;
                 let nguards: usize = 10;
-                let mut rand = rug::rand::RandState::new();
-                let prgs: Vec<Integer> = std::iter::repeat_with(|| {
-                    Integer::from(Integer::random_bits(nbits as u32, &mut rand))
-                })
-                .take(nguards)
-                .collect();
-                for i in 0..c.client_size {
-                    let msg = base_protocol_buffer.get(&round).unwrap().get(&i).unwrap();
+                let prgs: Vec<Integer> = std::iter::repeat_with(|| rng.bits(nbits as u32))
+                    .take(nguards)
+                    .collect();
+                for (i, msg) in messages.iter().enumerate() {
                     let mut xored_val = msg.slot_messages[0].clone();
                     let mut xored_prg = Integer::from(0);
-                    for j in 2..c.client_size {
-                        xored_val ^= &base_protocol_buffer
-                            .get(&round)
-                            .unwrap()
-                            .get(&j)
-                            .unwrap()
-                            .slot_messages[i];
+                    for other in messages.iter().skip(2) {
+                        xored_val ^= &other.slot_messages[i];
                     }
                     for prg in prgs[2..nguards].iter() {
                         xored_prg ^= prg;
                     }
                     xored_val ^= xored_prg;
                 }
-                info!(
-                    "{}",
-                    std::str::from_utf8(
-                        &std::process::Command::new("ping")
-                            .arg("google.com")
-                            .arg("-c")
-                            .arg("1")
-                            .output()
-                            .unwrap()
-                            .stdout
-                    )
-                    .unwrap()
-                );
+                if c.do_ping {
+                    egress_round_trip(uplink.as_mut(), &vec![0; c.slot_per_round]).await;
+                }
                 let message = bincode::serialize(&Message::Ok).unwrap();
                 info!("Sending Server Ok Message, size = {}...", message.len());
                 reactor_output_channel.send(message).await.unwrap();
                 info!("Sent Server Ok Message.");
-                base_protocol_buffer.remove(&round);
                 break;
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::aggregate::Aggregate;
+    use crate::client::{
+        encode_payload, generate_client_base_message, generate_client_bulk_message,
+        payload_capacity, reservation_capacity,
+    };
+    use crate::config::{default_base_params, Config};
+    use crate::guard::{derive_key, generate_keys};
+    use crate::message::{Message, ServerBaseMessage, ServerBulkMessage};
+    use crate::net::{async_read_stream, Limits, FRAME_VERSION};
+    use crate::packed::{width, PackedVec};
+    use crate::prf::Masks;
+    use crate::rng::Rng;
+    use crate::secure::Peer;
+    use crate::server::{
+        check_sender, compute_message, decode_payload, handle_connection, has_collision,
+        insert_once, round_scaled, sender, solve_sums, unmask_sums, Connection, EquationError,
+        ProtocolError,
+    };
+    use async_std::channel::unbounded;
+    use async_std::io::WriteExt;
+    use async_std::net::{TcpListener, TcpStream};
+    use futures::{future::join, select, FutureExt};
+    use rug::ops::Pow;
+    use rug::Integer;
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    fn get_config(slot_per_round: usize) -> Config {
+        Config::new("127.0.0.1:8001".parse().unwrap(), 5, 1, slot_per_round)
+    }
+
+    #[test]
+    fn payload_test() {
+        for slot_per_round in [1, 3, 5] {
+            let c = get_config(slot_per_round);
+            let capacity = payload_capacity(&c);
+            for len in [0, 1, capacity / 2, capacity] {
+                let payload: Vec<u8> = (0..len).map(|i| (255 - i % 256) as u8).collect();
+                let slots = encode_payload(&c, &payload).unwrap();
+                assert_eq!(slots.len(), slot_per_round);
+                assert_eq!(decode_payload(&c, &slots), Some(payload));
+            }
+            assert!(encode_payload(&c, &vec![0; capacity + 1]).is_none());
+        }
+    }
+
+    #[test]
+    fn bulk_test() {
+        let mut c = get_config(2);
+        c.client_size = 4;
+        c.bulk_params.vector_len = 16;
+        let params = &c.bulk_params;
+        let mut rng = Rng::from_seed(0, 0);
+        let (offsets, seeds) = generate_keys(c.client_size, params, &mut rng);
+        let masks: Vec<_> = (0..c.client_size)
+            .map(|i| {
+                let key = derive_key(params, i, &offsets[i], &seeds[i]);
+                Masks::new(params, &key, 1, false)
+            })
+            .collect();
+        // Client 0 fills slot group 2, client 1 sends an empty payload in slot
+        // group 0 and nobody writes slot group 1.
+        let payload = b"hello".to_vec();
+        let groups = 3;
+        let chunks = [
+            vec![(2, encode_payload(&c, &payload).unwrap())],
+            vec![(0, encode_payload(&c, &[]).unwrap())],
+            vec![],
+            vec![],
+        ];
+        let len = c.slot_per_round * groups;
+        let mut aggregate = Aggregate::new(&params.q, len, c.client_size);
+        for (nid, chunks) in chunks.into_iter().enumerate() {
+            let prf = masks[nid].get(params, 1, 0);
+            let message = generate_client_bulk_message(&c, &prf, groups, chunks);
+            assert!(aggregate.add(nid, &PackedVec::pack(&message, width(&params.q))));
+        }
+        // The zero elements only decode if the negative errors are lifted.
+        let prf = Masks::relay(params, 1, false).get(params, 1, 0);
+        assert_eq!(
+            compute_message(&c, &prf, &aggregate),
+            vec![Some(Vec::new()), Some(Vec::new()), Some(payload)]
+        );
+    }
+
+    #[test]
+    fn collision_test() {
+        let c = get_config(1);
+        let perm: Vec<Integer> = (1..c.client_size as u32 + 1).map(Integer::from).collect();
+        assert!(!has_collision(&perm));
+        assert!(!has_collision(&perm[0..4]));
+        assert!(!has_collision(&[]));
+        let mut repeated = perm.clone();
+        repeated[4] = Integer::from(1);
+        assert!(has_collision(&repeated));
+    }
+
+    #[test]
+    fn reservation_test() {
+        let mut c = get_config(1);
+        c.max_reservation = 2;
+        let capacity = reservation_capacity(&c);
+        let prf = vec![Integer::new(); c.base_params.vector_len];
+        let ids: Vec<Vec<Integer>> = vec![
+            vec![],
+            vec![Integer::from(7)],
+            vec![Integer::from(3), Integer::from(11)],
+        ];
+        let mut aggregate = Aggregate::new(&c.base_params.q, capacity + 1, c.client_size);
+        for (nid, ids) in ids.iter().enumerate() {
+            let message = generate_client_base_message(&c, &prf, ids);
+            assert_eq!(message.len(), c.base_params.vector_len);
+            assert!(aggregate.add(nid, &PackedVec::pack(&message, width(&c.base_params.q))));
+        }
+        // The relay learns the power sums of all IDs and their number only.
+        let sums: Vec<Integer> = aggregate
+            .sum()
+            .iter()
+            .map(|x| x.clone() / c.base_params.error_scale)
+            .collect();
+        for k in 1..capacity as u32 + 1 {
+            let expected =
+                Integer::from(7).pow(k) + Integer::from(3).pow(k) + Integer::from(11).pow(k);
+            assert_eq!(sums[k as usize - 1], expected % &c.base_params.p);
+        }
+        assert_eq!(sums[capacity], 3);
+        // A client checks that the sums of an attempt fail to solve before it
+        // opens the attempt.
+        let scale = Integer::from(c.base_params.error_scale);
+        let mut unmasked = unmask_sums(&c, &vec![Integer::new(); capacity + 1], &aggregate);
+        unmasked[capacity] = Integer::from(capacity + 1) * &scale;
+        assert_eq!(
+            solve_sums(&c, &unmasked),
+            Err(EquationError::Reservation(Integer::from(capacity + 1)))
+        );
+        unmasked[capacity] += Integer::from(&scale / 2);
+        assert_eq!(
+            solve_sums(&c, &unmasked),
+            Err(EquationError::Ambiguous(capacity))
+        );
+        unmasked[capacity] = Integer::new();
+        assert_eq!(solve_sums(&c, &unmasked), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn sender_test() {
+        assert!(check_sender(Some(Peer::Client(2)), 2).is_ok());
+        assert_eq!(
+            check_sender(Some(Peer::Client(1)), 2),
+            Err(ProtocolError::Impersonation {
+                peer: Some(Peer::Client(1)),
+                claimed: 2
+            })
+        );
+        assert!(check_sender(Some(Peer::Guard), 2).is_err());
+        assert!(check_sender(None, 2).is_err());
+        let mut messages = HashMap::new();
+        assert!(insert_once(&mut messages, 2, 1, "first").is_ok());
+        assert_eq!(
+            insert_once(&mut messages, 2, 1, "second"),
+            Err(ProtocolError::Duplicate { nid: 2, round: 1 })
+        );
+        assert_eq!(messages[&2], "first");
+    }
+
+    #[test]
+    fn rounding_test() {
+        let params = default_base_params();
+        assert_eq!(
+            round_scaled(&params, &Integer::from(5003), 9),
+            Some(Integer::from(5))
+        );
+        assert_eq!(
+            round_scaled(&params, &Integer::from(4991), 9),
+            Some(Integer::from(5))
+        );
+        assert_eq!(
+            round_scaled(&params, &Integer::from(9), 9),
+            Some(Integer::from(0))
+        );
+        // A negative error wraps around `q`.
+        let below_zero = Integer::from(&params.q - 7);
+        assert_eq!(
+            round_scaled(&params, &below_zero, 9),
+            Some(Integer::from(0))
+        );
+        assert_eq!(round_scaled(&params, &(below_zero - 3), 9), None);
+        // An error beyond the bound could have come from the next value.
+        assert_eq!(round_scaled(&params, &Integer::from(5010), 9), None);
+        assert_eq!(round_scaled(&params, &Integer::from(5499), 9), None);
+    }
+
+    #[test]
+    fn resume_test() {
+        let connect = |peer, resume| {
+            let (channel, recv) = unbounded::<Vec<u8>>();
+            let connection = Connection {
+                peer: Some(Peer::Client(peer)),
+                channel,
+                resume,
+            };
+            (connection, recv)
+        };
+        let base = bincode::serialize(&Message::ServerBaseMessage(ServerBaseMessage {
+            round: 1,
+            perm: vec![Integer::from(7)],
+        }))
+        .unwrap();
+        let bulk = bincode::serialize(&Message::ServerBulkMessage(ServerBulkMessage {
+            round: 1,
+            digests: vec![None],
+        }))
+        .unwrap();
+        let ok = bincode::serialize(&Message::Ok).unwrap();
+        let (connections_send, connections_recv) = unbounded::<Connection>();
+        let (output_send, output_recv) = unbounded::<Vec<u8>>();
+        let (disconnect_send, disconnect_recv) = unbounded::<Peer>();
+        let test = async {
+            let (first, first_recv) = connect(1, false);
+            connections_send.send(first).await.unwrap();
+            async_std::task::yield_now().await;
+            output_send.send(base.clone()).await.unwrap();
+            output_send.send(bulk.clone()).await.unwrap();
+            assert_eq!(first_recv.recv().await.unwrap(), base);
+            assert_eq!(first_recv.recv().await.unwrap(), bulk);
+            // The client resumes on a new connection, which replaces the first one.
+            let (second, second_recv) = connect(1, true);
+            connections_send.send(second).await.unwrap();
+            assert_eq!(second_recv.recv().await.unwrap(), base);
+            assert_eq!(second_recv.recv().await.unwrap(), bulk);
+            assert!(first_recv.recv().await.is_err());
+            // A new session starts without the earlier messages.
+            let (third, third_recv) = connect(2, false);
+            connections_send.send(third).await.unwrap();
+            async_std::task::yield_now().await;
+            output_send.send(ok.clone()).await.unwrap();
+            assert_eq!(third_recv.recv().await.unwrap(), ok);
+            assert_eq!(second_recv.recv().await.unwrap(), ok);
+            disconnect_send.send(Peer::Client(1)).await.unwrap();
+            assert!(second_recv.recv().await.is_err());
+        };
+        async_std::task::block_on(async {
+            select!(
+                () = sender(connections_recv, output_recv, disconnect_recv).fuse() => {},
+                () = test.fuse() => {}
+            )
+        });
+    }
+
+    #[test]
+    fn connection_test() {
+        let limits = Limits {
+            max_len: 1 << 20,
+            timeout: Some(Duration::from_secs(5)),
+        };
+        let frame = vec![7u8; 1 << 16];
+        let mut raw = vec![FRAME_VERSION];
+        raw.extend((frame.len() as u64).to_le_bytes());
+        raw.extend(&frame);
+        async_std::task::block_on(async {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let mut client = TcpStream::connect(listener.local_addr().unwrap())
+                .await
+                .unwrap();
+            let (stream, _) = listener.accept().await.unwrap();
+            let (read_send, read_recv) = unbounded::<(Option<Peer>, Vec<u8>)>();
+            let (write_send, write_recv) = unbounded::<Vec<u8>>();
+            let test = async {
+                // A write to the peer while its frame is half read leaves the
+                // frame intact.
+                let (first, second) = raw.split_at(raw.len() / 2);
+                client.write_all(first).await.unwrap();
+                async_std::task::sleep(Duration::from_millis(50)).await;
+                write_send.send(b"ping".to_vec()).await.unwrap();
+                let ping = async_read_stream(&mut client, &limits).await.unwrap();
+                assert_eq!(ping, b"ping");
+                client.write_all(second).await.unwrap();
+                assert_eq!(read_recv.recv().await.unwrap(), (None, frame.clone()));
+                write_send.close();
+            };
+            join(
+                handle_connection(stream, None, limits, read_send, write_recv),
+                test,
+            )
+            .await;
+        });
+    }
+}
@@ -0,0 +1,458 @@
+use crate::config::{Config, ProtocolParams};
+use crate::ecc::{get_g, get_order, mul, to_bytes, try_from_bytes};
+use crate::error::Error;
+use crate::guard::{commit, derive_key, gen_blinding, ClientSetup, RelaySetup, Seeds, SEED_LEN};
+use crate::message::{
+    ClientSetupCommitMessage, ClientSetupKeyMessage, Message, Phase, ServerSetupKeysMessage,
+};
+use crate::net::{async_read_stream, async_write_stream, Limits};
+use crate::rng::Rng;
+use crate::secure::{accept, sign, verify, Identity, Peer, SecureStream, Session};
+use crate::server::{check_sender, ProtocolError};
+use async_std::future::timeout;
+use async_std::net::{TcpListener, TcpStream};
+use rug::Integer;
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+// Dealerless setup: the clients agree on their pairwise seeds with ECDH over
+// the group of the base round, so that no node ever sees the key of another.
+// Every client signs its ECDH key with its long-term identity, whose public
+// key every node holds beforehand, so that the relay cannot swap the keys.
+
+/// Domain separation string for the derivation of the pairwise seeds.
+const SEED_DOMAIN: &[u8] = b"OrgAn pairwise seed";
+
+/// Domain separation string for the signatures of the ECDH keys.
+const KEY_DOMAIN: &[u8] = b"OrgAn setup key";
+
+/// Time a connecting node has to complete the handshake of the setup.
+const SETUP_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns the public offsets of `n` clients, which sum up to 1. The offsets
+/// need not be secret since the keys are hidden by the pairwise vectors.
+pub fn public_offsets(n: usize) -> Vec<Integer> {
+    (0..n).map(|i| Integer::from((i == 0) as u8)).collect()
+}
+
+/// Generates an ECDH key pair over the group of `params`. Returns the private
+/// scalar and the serialized public point.
+pub fn generate_key_pair(params: &ProtocolParams, rng: &mut Rng) -> (Integer, Vec<u8>) {
+    let secret = rng.below(&(get_order(params) - 1u8)) + 1u8;
+    let public = to_bytes(params, &mul(params, &get_g(params), &secret));
+    (secret, public)
+}
+
+/// Derives the seeds `nid` shares with each other client from its `secret`
+/// and their `public_keys`. `phase` separates the seeds of the base and the
+/// bulk round. Fails if a key is not a point of the group.
+pub fn agree_seeds(
+    params: &ProtocolParams,
+    nid: usize,
+    secret: &Integer,
+    public_keys: &[Vec<u8>],
+    phase: Phase,
+) -> Result<Seeds, ProtocolError> {
+    public_keys
+        .iter()
+        .enumerate()
+        .map(|(peer, public)| {
+            if peer == nid {
+                return Ok(None);
+            }
+            let point = try_from_bytes(params, public)
+                .ok_or(ProtocolError::Malformed(Some(Peer::Client(peer))))?;
+            let shared = to_bytes(params, &mul(params, &point, secret));
+            let mut hasher = Sha256::new();
+            hasher.update(SEED_DOMAIN);
+            hasher.update(match phase {
+                Phase::Base => b"base",
+                Phase::Bulk => b"bulk",
+            });
+            hasher.update((nid.min(peer) as u64).to_le_bytes());
+            hasher.update((nid.max(peer) as u64).to_le_bytes());
+            hasher.update(shared);
+            Ok(Some(hasher.finalize()[..SEED_LEN].to_vec()))
+        })
+        .collect()
+}
+
+/// Generates the setup of `nid` from its `seeds`. Returns the setup and the
+/// commitments the relay needs in the blame protocol.
+pub fn gen_client_setup(
+    c: &Config,
+    params: &ProtocolParams,
+    nid: usize,
+    seeds: Seeds,
+    rng: &mut Rng,
+) -> (ClientSetup, Option<Vec<Vec<u8>>>) {
+    let share = derive_key(params, nid, &public_offsets(c.client_size)[nid], &seeds);
+    let blinding = gen_blinding(params, rng);
+    let commitments = if c.do_blame {
+        Some(commit(params, &share, &blinding))
+    } else {
+        None
+    };
+    (
+        ClientSetup {
+            seeds,
+            share,
+            blinding,
+        },
+        commitments,
+    )
+}
+
+/// Generates the setup of the relay from the `commitments` of each client.
+pub fn gen_relay_setup(c: &Config, commitments: Vec<Option<Vec<Vec<u8>>>>) -> RelaySetup {
+    RelaySetup {
+        members: (0..c.client_size).collect(),
+        offsets: public_offsets(c.client_size),
+        commitments: if c.do_blame {
+            Some(
+                commitments
+                    .into_iter()
+                    .map(Option::unwrap_or_default)
+                    .collect(),
+            )
+        } else {
+            None
+        },
+    }
+}
+
+/// Returns what client `nid` signs to vouch for its ECDH `public_key`.
+fn key_transcript(nid: usize, public_key: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::new();
+    hasher.update(KEY_DOMAIN);
+    hasher.update((nid as u64).to_le_bytes());
+    hasher.update(public_key);
+    hasher.finalize().to_vec()
+}
+
+/// Checks that the ECDH `public_key` of client `nid` is signed with the key of
+/// its long-term `identity`.
+pub fn verify_key(
+    nid: usize,
+    public_key: &[u8],
+    identity: &[u8],
+    signature: &[u8],
+) -> Result<(), ProtocolError> {
+    verify(
+        identity,
+        &key_transcript(nid, public_key),
+        b"setup",
+        signature,
+    )
+    .map_err(|_| ProtocolError::Unsigned(nid))
+}
+
+/// Decodes a message of the setup from `peer`.
+fn decode(buf: &[u8], peer: Option<Peer>) -> Result<Message, Error> {
+    bincode::deserialize(buf).map_err(|_| Error::Protocol(ProtocolError::Malformed(peer)))
+}
+
+/// Runs the setup of client `nid` through the relay. `identity` holds the
+/// long-term key of the client and the public keys of the relay and of every
+/// client, exchanged beforehand. Returns the setup of the base and the bulk
+/// round. Fails if the relay cannot be reached or forwards a key that its
+/// client did not sign or that does not decode.
+pub fn main_client(
+    c: &Config,
+    nid: usize,
+    identity: &Identity,
+) -> Result<(ClientSetup, ClientSetup), Error> {
+    debug!("Connecting to {:?}...", c.server_addr);
+    let mut socket = SecureStream::connect(
+        c.server_addr,
+        Peer::Client(nid),
+        identity,
+        Limits::from_config(c),
+    )?;
+    let mut rng = Rng::from_config(c, nid);
+    let (secret, public_key) = generate_key_pair(&c.base_params, &mut rng);
+    let signature = sign(&identity.key, &key_transcript(nid, &public_key), b"setup")?;
+    let message = Message::ClientSetupKeyMessage(ClientSetupKeyMessage {
+        nid,
+        public_key,
+        signature,
+    });
+    socket.write(&bincode::serialize(&message).unwrap())?;
+    let msg = match decode(&socket.read()?, None)? {
+        Message::ServerSetupKeysMessage(msg)
+            if msg.public_keys.len() == c.client_size && msg.signatures.len() == c.client_size =>
+        {
+            msg
+        }
+        _ => return Err(Error::Protocol(ProtocolError::Malformed(None))),
+    };
+    info!(
+        "Received the public keys of {} clients.",
+        msg.public_keys.len()
+    );
+    // The relay could swap in keys of its own, so check every key against
+    // the identity of its client before agreeing on a seed.
+    for (peer, (public_key, signature)) in msg.public_keys.iter().zip(&msg.signatures).enumerate() {
+        let identity = identity.clients.get(peer).map_or(&[][..], Vec::as_slice);
+        verify_key(peer, public_key, identity, signature)?;
+    }
+    let base_seeds = agree_seeds(&c.base_params, nid, &secret, &msg.public_keys, Phase::Base)?;
+    let bulk_seeds = agree_seeds(&c.base_params, nid, &secret, &msg.public_keys, Phase::Bulk)?;
+    let mut setup = [
+        gen_client_setup(c, &c.base_params, nid, base_seeds, &mut rng),
+        gen_client_setup(c, &c.bulk_params, nid, bulk_seeds, &mut rng),
+    ];
+    let message = Message::ClientSetupCommitMessage(ClientSetupCommitMessage {
+        nid,
+        base_commitments: setup[0].1.take(),
+        bulk_commitments: setup[1].1.take(),
+    });
+    socket.write(&bincode::serialize(&message).unwrap())?;
+    let [(base, _), (bulk, _)] = setup;
+    Ok((base, bulk))
+}
+
+/// Connection of a client during the setup.
+struct SetupConnection {
+    /// Underlying stream.
+    stream: TcpStream,
+    /// Channel to the client.
+    session: Session,
+}
+
+impl SetupConnection {
+    /// Reads one message from the client.
+    async fn read(&mut self, limits: &Limits) -> Result<Vec<u8>, Error> {
+        let frame = async_read_stream(&mut self.stream, limits).await?;
+        Ok(self.session.opener.open(&frame)?)
+    }
+
+    /// Writes one message to the client.
+    async fn write(&mut self, data: &[u8], limits: &Limits) -> Result<(), Error> {
+        let frame = self.session.sealer.seal(data);
+        Ok(async_write_stream(&mut self.stream, &frame, limits).await?)
+    }
+}
+
+/// Runs the setup of the relay, forwarding the signed public keys among the
+/// clients and collecting their commitments. `identity` holds the long-term
+/// key of the relay and the public keys of the clients, exchanged beforehand.
+/// Only clients that authenticate with their identity and send a signed key
+/// of the group in time take part; a client that connects again replaces its
+/// earlier connection. Returns the setup of
+/// the base and the bulk round. Fails if the address cannot be bound or a
+/// client breaks off the setup.
+pub async fn main_relay(
+    c: &Config,
+    identity: &Identity,
+) -> Result<(RelaySetup, RelaySetup), Error> {
+    let listener = TcpListener::bind(c.server_addr).await?;
+    // The commitments hold a point per element of the key.
+    let limits = Limits {
+        max_len: usize::MAX,
+        ..Limits::from_config(c)
+    };
+    let mut connections: Vec<Option<SetupConnection>> = (0..c.client_size).map(|_| None).collect();
+    let mut public_keys = vec![Vec::new(); c.client_size];
+    let mut signatures = vec![Vec::new(); c.client_size];
+    while connections.iter().any(Option::is_none) {
+        let (mut stream, addr) = listener.accept().await?;
+        let session = match timeout(
+            SETUP_HANDSHAKE_TIMEOUT,
+            accept(&mut stream, identity, &Limits::handshake(c)),
+        )
+        .await
+        {
+            Ok(Ok(session)) => session,
+            Ok(Err(e)) => {
+                warn!("Handshake failed with {:?}: {}.", addr, e);
+                continue;
+            }
+            Err(_) => {
+                warn!("Handshake timed out with {:?}.", addr);
+                continue;
+            }
+        };
+        let peer = Some(session.peer);
+        let mut connection = SetupConnection { stream, session };
+        // The limits only bound a frame once its header has arrived, so a
+        // silent client would hold up the others.
+        let msg = match timeout(SETUP_HANDSHAKE_TIMEOUT, connection.read(&limits)).await {
+            Ok(Ok(buf)) => decode(&buf, peer),
+            Ok(Err(e)) => Err(e),
+            Err(_) => {
+                warn!("Setup key timed out with {:?}.", peer);
+                continue;
+            }
+        };
+        let msg = match msg {
+            Ok(Message::ClientSetupKeyMessage(msg)) => msg,
+            Ok(_) | Err(_) => {
+                warn!("Ignoring an unexpected setup message from {:?}.", peer);
+                continue;
+            }
+        };
+        if let Err(e) = check_sender(peer, msg.nid) {
+            warn!("Ignoring the setup key: {}.", e);
+            continue;
+        }
+        if msg.nid >= c.client_size {
+            warn!(
+                "Ignoring the setup key of client {} beyond client_size.",
+                msg.nid
+            );
+            continue;
+        }
+        if let Err(e) = verify_key(
+            msg.nid,
+            &msg.public_key,
+            &identity.clients[msg.nid],
+            &msg.signature,
+        ) {
+            warn!("Ignoring the setup key: {}.", e);
+            continue;
+        }
+        // A key that does not decode would fail the setup of every other client.
+        if try_from_bytes(&c.base_params, &msg.public_key).is_none() {
+            warn!(
+                "Ignoring the setup key: {}.",
+                ProtocolError::Malformed(peer)
+            );
+            continue;
+        }
+        if connections[msg.nid].is_some() {
+            info!("Client {} connected again.", msg.nid);
+        }
+        info!("Received the public key of client {}.", msg.nid);
+        public_keys[msg.nid] = msg.public_key;
+        signatures[msg.nid] = msg.signature;
+        connections[msg.nid] = Some(connection);
+    }
+    let message = bincode::serialize(&Message::ServerSetupKeysMessage(ServerSetupKeysMessage {
+        public_keys,
+        signatures,
+    }))
+    .unwrap();
+    let mut base_commitments = vec![None; c.client_size];
+    let mut bulk_commitments = vec![None; c.client_size];
+    for connection in connections.iter_mut().flatten() {
+        connection.write(&message, &limits).await?;
+    }
+    for (nid, connection) in connections.iter_mut().flatten().enumerate() {
+        let peer = Some(Peer::Client(nid));
+        match decode(&connection.read(&limits).await?, peer)? {
+            Message::ClientSetupCommitMessage(msg) if msg.nid == nid => {
+                info!("Received the commitments of client {}.", nid);
+                base_commitments[nid] = msg.base_commitments;
+                bulk_commitments[nid] = msg.bulk_commitments;
+            }
+            _ => return Err(Error::Protocol(ProtocolError::Malformed(peer))),
+        }
+    }
+    let base = gen_relay_setup(c, base_commitments);
+    let bulk = gen_relay_setup(c, bulk_commitments);
+    Ok((base, bulk))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::default_base_params;
+    use crate::config::Config;
+    use crate::error::Error;
+    use crate::guard::derive_key;
+    use crate::message::Phase;
+    use crate::rng::Rng;
+    use crate::secure::{issue_identities, sign, Peer};
+    use crate::server::ProtocolError;
+    use crate::setup::{
+        agree_seeds, generate_key_pair, key_transcript, main_client, main_relay, public_offsets,
+        verify_key,
+    };
+    use rug::Integer;
+    use std::time::Duration;
+
+    #[test]
+    fn agree_test() {
+        let mut params = default_base_params();
+        params.vector_len = 16;
+        let n = 3;
+        let mut rng = Rng::from_seed(0, 0);
+        let (secrets, public_keys): (Vec<_>, Vec<_>) =
+            (0..n).map(|_| generate_key_pair(&params, &mut rng)).unzip();
+        let seeds: Vec<_> = (0..n)
+            .map(|i| agree_seeds(&params, i, &secrets[i], &public_keys, Phase::Base).unwrap())
+            .collect();
+        // A key that is no point of the group fails the setup rather than panics.
+        let mut bad_keys = public_keys.clone();
+        bad_keys[1] = vec![0xff; 3];
+        assert_eq!(
+            agree_seeds(&params, 0, &secrets[0], &bad_keys, Phase::Base),
+            Err(ProtocolError::Malformed(Some(Peer::Client(1))))
+        );
+        for (i, seeds_i) in seeds.iter().enumerate() {
+            assert!(seeds_i[i].is_none());
+            for (j, seeds_j) in seeds.iter().enumerate() {
+                assert_eq!(seeds_i[j], seeds_j[i]);
+            }
+        }
+        let offsets = public_offsets(n);
+        let keys: Vec<_> = (0..n)
+            .map(|i| derive_key(&params, i, &offsets[i], &seeds[i]))
+            .collect();
+        for k in 0..params.vector_len {
+            let sum = keys.iter().fold(Integer::from(0), |acc, key| acc + &key[k]);
+            assert_eq!(sum % &params.ring_v.order, 1);
+        }
+    }
+
+    #[test]
+    fn setup_test() {
+        let mut c = Config::new("127.0.0.1:8017".parse().unwrap(), 3, 1, 1);
+        c.base_params.vector_len = 16;
+        c.bulk_params.vector_len = 16;
+        let (mut clients, relay, _) = issue_identities(c.client_size);
+        for client in clients.iter_mut() {
+            client.clients = relay.clients.clone();
+        }
+        // A key is only good for the client that signed it.
+        let mut rng = Rng::from_seed(0, 0);
+        let (_, public_key) = generate_key_pair(&c.base_params, &mut rng);
+        let signature = sign(&clients[0].key, &key_transcript(0, &public_key), b"setup").unwrap();
+        assert!(verify_key(0, &public_key, &relay.clients[0], &signature).is_ok());
+        assert!(verify_key(1, &public_key, &relay.clients[1], &signature).is_err());
+        let (base, setups) = std::thread::scope(|scope| {
+            let handles: Vec<_> = clients
+                .iter()
+                .enumerate()
+                .map(|(nid, identity)| {
+                    let c = &c;
+                    scope.spawn(move || loop {
+                        // Wait for the relay to listen.
+                        match main_client(c, nid, identity) {
+                            Err(Error::Net(_)) => std::thread::sleep(Duration::from_millis(50)),
+                            result => return result.unwrap(),
+                        }
+                    })
+                })
+                .collect();
+            let (base, _) = async_std::task::block_on(main_relay(&c, &relay)).unwrap();
+            let setups: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+            (base, setups)
+        });
+        assert_eq!(base.members, vec![0, 1, 2]);
+        for (i, (base_i, bulk_i)) in setups.iter().enumerate() {
+            for (j, (base_j, bulk_j)) in setups.iter().enumerate() {
+                assert_eq!(base_i.seeds[j], base_j.seeds[i]);
+                assert_eq!(bulk_i.seeds[j], bulk_j.seeds[i]);
+                assert!(i == j || base_i.seeds[j] != bulk_i.seeds[j]);
+            }
+        }
+        let params = &c.base_params;
+        for k in 0..params.vector_len {
+            let sum = setups
+                .iter()
+                .fold(Integer::from(0), |acc, (base, _)| acc + &base.share[k]);
+            assert_eq!(sum % &params.ring_v.order, 1);
+        }
+    }
+}
@@ -0,0 +1,143 @@
+use crate::config::Output;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::net::{SocketAddr, TcpStream};
+
+// Output of the relay. Every well-formed, non-empty payload of a bulk round
+// goes to a sink, which hands it on to the outside world. The file and the
+// TCP sink write one record per payload: the round, the slot group and the
+// length of the payload as little-endian `u64`, then the payload itself.
+
+/// Destination of the payloads the relay decodes.
+pub trait Sink {
+    /// Publishes `payload` of slot group `slot` on `round`.
+    fn publish(&mut self, round: usize, slot: usize, payload: &[u8]) -> std::io::Result<()>;
+}
+
+/// Returns the record of `payload` of slot group `slot` on `round`.
+pub fn encode_record(round: usize, slot: usize, payload: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(24 + payload.len());
+    record.extend_from_slice(&(round as u64).to_le_bytes());
+    record.extend_from_slice(&(slot as u64).to_le_bytes());
+    record.extend_from_slice(&(payload.len() as u64).to_le_bytes());
+    record.extend_from_slice(payload);
+    record
+}
+
+/// Sink that prints each payload as a line of text.
+pub struct StdoutSink;
+
+impl Sink for StdoutSink {
+    fn publish(&mut self, round: usize, slot: usize, payload: &[u8]) -> std::io::Result<()> {
+        let mut stdout = std::io::stdout().lock();
+        writeln!(
+            stdout,
+            "{} {}: {}",
+            round,
+            slot,
+            String::from_utf8_lossy(payload)
+        )?;
+        stdout.flush()
+    }
+}
+
+/// Sink that appends the records to a file.
+pub struct FileSink {
+    /// File to append to.
+    file: File,
+}
+
+impl FileSink {
+    /// Opens `path` for appending, creating it if needed.
+    pub fn open(path: &str) -> std::io::Result<Self> {
+        Ok(FileSink {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+        })
+    }
+}
+
+impl Sink for FileSink {
+    fn publish(&mut self, round: usize, slot: usize, payload: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(&encode_record(round, slot, payload))?;
+        self.file.flush()
+    }
+}
+
+/// Sink that forwards the records over TCP. Connects again on the next
+/// payload after the connection fails.
+pub struct TcpSink {
+    /// Address to forward to.
+    addr: SocketAddr,
+    /// Connection, if there is one.
+    stream: Option<TcpStream>,
+}
+
+impl TcpSink {
+    /// Returns a sink that forwards to `addr`.
+    pub fn new(addr: SocketAddr) -> Self {
+        TcpSink { addr, stream: None }
+    }
+}
+
+impl Sink for TcpSink {
+    fn publish(&mut self, round: usize, slot: usize, payload: &[u8]) -> std::io::Result<()> {
+        let stream = match self.stream.as_mut() {
+            Some(stream) => stream,
+            None => self.stream.insert(TcpStream::connect(self.addr)?),
+        };
+        let result = stream
+            .write_all(&encode_record(round, slot, payload))
+            .and_then(|()| stream.flush());
+        if result.is_err() {
+            self.stream = None;
+        }
+        result
+    }
+}
+
+/// Returns the sink of `output`, or `None` if the payloads are only logged.
+pub fn open(output: &Output) -> std::io::Result<Option<Box<dyn Sink>>> {
+    Ok(match output {
+        Output::Log => None,
+        Output::Stdout => Some(Box::new(StdoutSink)),
+        Output::File(path) => Some(Box::new(FileSink::open(path)?)),
+        Output::Tcp(addr) => Some(Box::new(TcpSink::new(*addr))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sink::{encode_record, FileSink, Sink, TcpSink};
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    #[test]
+    fn sink_test() {
+        let mut expected = encode_record(3, 1, b"hello");
+        expected.extend(encode_record(3, 2, b""));
+        assert_eq!(&expected[0..8], &3u64.to_le_bytes());
+        assert_eq!(&expected[16..24], &5u64.to_le_bytes());
+        assert_eq!(expected.len(), 24 + 5 + 24);
+
+        let path = std::env::temp_dir().join(format!("organ_sink_{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut sink = FileSink::open(path.to_str().unwrap()).unwrap();
+        sink.publish(3, 1, b"hello").unwrap();
+        sink.publish(3, 2, b"").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), expected);
+        std::fs::remove_file(&path).unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = TcpSink::new(listener.local_addr().unwrap());
+        sink.publish(3, 1, b"hello").unwrap();
+        sink.publish(3, 2, b"").unwrap();
+        let mut received = vec![0; expected.len()];
+        listener
+            .accept()
+            .unwrap()
+            .0
+            .read_exact(&mut received)
+            .unwrap();
+        assert_eq!(received, expected);
+    }
+}